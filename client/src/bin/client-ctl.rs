@@ -0,0 +1,128 @@
+//! Talks to the client's control socket, see `control` in the client config.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+
+const DEFAULT_SOCKET: &str = "/run/vpn_client.sock";
+
+const USAGE: &str = "usage: client-ctl [--socket <path>] <command>
+
+commands:
+  status                 connected server, RTT and uptime
+  upstreams              state, RTT and loss of each server and path
+  flows                  TCP flows in the tunnel
+  reconnect              open a new connection to the server
+  disconnect             close the connection, the tunnel stays up
+  switch-profile <name>  connect with one of the configured profiles";
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut socket = DEFAULT_SOCKET.to_string();
+    if args.peek().is_some_and(|arg| arg == "--socket") {
+        args.next();
+        socket = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    }
+    let command = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let request = match command.as_str() {
+        "status" => json!({ "cmd": "status" }),
+        "upstreams" => json!({ "cmd": "upstreams" }),
+        "flows" => json!({ "cmd": "flows" }),
+        "reconnect" => json!({ "cmd": "reconnect" }),
+        "disconnect" => json!({ "cmd": "disconnect" }),
+        "switch-profile" => {
+            let name = args.next().ok_or_else(|| anyhow!(USAGE))?;
+            json!({ "cmd": "switch_profile", "name": name })
+        }
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    let mut stream =
+        UnixStream::connect(&socket).with_context(|| format!("connecting to {}", socket))?;
+    let mut line = request.to_string();
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line).context("parsing client response")?;
+
+    if response["ok"] != true {
+        return Err(anyhow!(
+            "{}",
+            response["error"].as_str().unwrap_or("request failed")
+        ));
+    }
+    let status = &response["status"];
+    if status.is_object() {
+        println!(
+            "profile:   {}",
+            status["profile"].as_str().unwrap_or("default")
+        );
+        println!(
+            "server:    {} ({})",
+            status["server"].as_str().unwrap_or_default(),
+            status["server_name"].as_str().unwrap_or_default()
+        );
+        println!(
+            "state:     {}",
+            status["state"].as_str().unwrap_or_default()
+        );
+        if status["state"] == "connected" {
+            println!(
+                "rtt:       {:.1} ms",
+                status["rtt_ms"].as_f64().unwrap_or(0.0)
+            );
+            println!("uptime:    {} s", status["uptime_secs"]);
+        }
+        println!("reconnects: {}", status["reconnects"]);
+        if let Some(error) = status["last_error"].as_str() {
+            println!("last error: {}", error);
+        }
+    } else if let Some(upstreams) = response["upstreams"].as_array() {
+        println!(
+            "{:<22} {:<24} {:<10} {:<13} {:>10} {:>7} {:>10}",
+            "SERVER", "NAME", "PATH", "STATE", "RTT", "LOSS", "RECONNECTS"
+        );
+        for upstream in upstreams {
+            let (rtt, loss) = match (
+                upstream["rtt_ms"].as_f64(),
+                upstream["loss_percent"].as_f64(),
+            ) {
+                (Some(rtt), Some(loss)) => (format!("{:.1} ms", rtt), format!("{:.1}%", loss)),
+                _ => ("-".to_string(), "-".to_string()),
+            };
+            println!(
+                "{:<22} {:<24} {:<10} {:<13} {:>10} {:>7} {:>10}",
+                upstream["server"].as_str().unwrap_or_default(),
+                upstream["server_name"].as_str().unwrap_or_default(),
+                upstream["interface"].as_str().unwrap_or("-"),
+                upstream["state"].as_str().unwrap_or_default(),
+                rtt,
+                loss,
+                upstream["reconnects"]
+            );
+        }
+    } else if let Some(flows) = response["flows"].as_array() {
+        println!(
+            "{:<22} {:<22} {:<13} {:>12} {:>12}",
+            "LOCAL", "REMOTE", "STATE", "UP", "DOWN"
+        );
+        for flow in flows {
+            println!(
+                "{:<22} {:<22} {:<13} {:>12} {:>12}",
+                flow["local"].as_str().unwrap_or_default(),
+                flow["remote"].as_str().unwrap_or_default(),
+                flow["state"].as_str().unwrap_or_default(),
+                flow["bytes_up"],
+                flow["bytes_down"]
+            );
+        }
+    } else {
+        println!("ok");
+    }
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap, net::SocketAddr, path::Path, path::PathBuf, sync::Arc, time::Duration,
+};
+
+use anyhow::{Context, Result};
+use encryption::transport::TransportConfig;
+use serde::Deserialize;
+
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "/etc/vpn_client.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub server: SocketAddr,
+    /// Name the server certificate is issued for.
+    pub server_name: String,
+    pub aead_key: PathBuf,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Client certificate chain and key for mTLS. When the server accepts the
+    /// certificate the username/password step is skipped.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub trust: Trust,
+    /// Local address for the Prometheus `/metrics` endpoint, off when unset.
+    pub metrics: Option<SocketAddr>,
+    /// Unix socket for `client-ctl`, off when unset.
+    pub control: Option<PathBuf>,
+    /// Routes through a TUN device. Turn it off where none can be created
+    /// and use `proxy` instead.
+    pub tun: bool,
+    /// TCP implementation behind the TUN.
+    pub engine: Engine,
+    /// Local address for a SOCKS5 and HTTP CONNECT proxy into the tunnel,
+    /// off when unset.
+    pub proxy: Option<SocketAddr>,
+    pub transparent: TransparentConfig,
+    /// Other client configs `client-ctl switch-profile` can move to, by name.
+    /// Only their server, credential and trust settings are used.
+    pub profiles: HashMap<String, PathBuf>,
+    pub reconnect: ReconnectConfig,
+    pub upstreams: UpstreamsConfig,
+    pub multipath: MultipathConfig,
+    /// QUIC settings of the connections to the servers.
+    pub transport: TransportConfig,
+}
+
+/// Listener for TCP connections that firewall rules divert to the client.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TransparentConfig {
+    /// Address the `REDIRECT` or `TPROXY` rules point at, off when unset.
+    pub listen: Option<SocketAddr>,
+    /// Expect `TPROXY` rules rather than `REDIRECT`.
+    pub tproxy: bool,
+}
+
+/// Local uplinks to use at the same time, each with its own connection to
+/// every server.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MultipathConfig {
+    /// Interface names, e.g. `["wwan0", "sat0"]`. Empty means one connection
+    /// over whatever the routing table picks.
+    pub interfaces: Vec<String>,
+}
+
+/// Servers besides `server`, and how flows are spread over all of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UpstreamsConfig {
+    pub strategy: Strategy,
+    /// How often each server is probed with a QUIC PING.
+    #[serde(with = "humantime_serde")]
+    pub health_interval: Duration,
+    /// A server that does not answer for this long counts as down and its
+    /// connection is replaced.
+    #[serde(with = "humantime_serde")]
+    pub health_timeout: Duration,
+    pub servers: Vec<UpstreamServer>,
+}
+
+/// What terminates the kernel's TCP connections on the TUN.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Engine {
+    /// The client's own minimal TCP.
+    #[default]
+    Builtin,
+    /// smoltcp's TCP/IP stack, with retransmission and flow control.
+    Smoltcp,
+}
+
+/// Which server a new flow goes to. Servers that are down are skipped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Strategy {
+    /// The first server in config order.
+    #[default]
+    Failover,
+    LowestRtt,
+    /// The next server for each flow.
+    RoundRobin,
+    /// The server the destination address went to before.
+    Sticky,
+}
+
+/// Another server, sharing credentials and trust settings with `server`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UpstreamServer {
+    pub server: SocketAddr,
+    pub server_name: String,
+}
+
+impl Default for UpstreamsConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::default(),
+            health_interval: Duration::from_secs(5),
+            health_timeout: Duration::from_secs(15),
+            servers: vec![],
+        }
+    }
+}
+
+/// Backoff between attempts while the connection to the server is down.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ReconnectConfig {
+    #[serde(with = "humantime_serde")]
+    pub min_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How the server certificate is verified.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub(crate) enum Trust {
+    /// Chain to a CA from this PEM bundle, checked against `server_name`.
+    Ca { ca: PathBuf },
+    /// Accept only these server keys, as `sha256/<base64 of SPKI digest>`.
+    Pin { pins: Vec<String> },
+    /// Remember the key seen on first connect and refuse any other afterwards.
+    Tofu { known_hosts: PathBuf },
+    /// Accept anything. Only for local experiments.
+    Insecure,
+}
+
+impl Default for Trust {
+    fn default() -> Self {
+        Self::Tofu {
+            known_hosts: "/etc/vpn_known_hosts".into(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: "172.28.0.3:1080".parse().unwrap(),
+            server_name: "vpn".to_string(),
+            aead_key: "/etc/xchacha20.key".into(),
+            username: None,
+            password: None,
+            cert: None,
+            key: None,
+            trust: Trust::default(),
+            metrics: None,
+            control: None,
+            tun: true,
+            engine: Engine::default(),
+            proxy: None,
+            transparent: TransparentConfig::default(),
+            profiles: HashMap::new(),
+            reconnect: ReconnectConfig::default(),
+            upstreams: UpstreamsConfig::default(),
+            multipath: MultipathConfig::default(),
+            transport: TransportConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads config from `path`, falling back to defaults if the file is missing.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(raw) => toml::from_str(&raw).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("no config at {}, using defaults", path.display());
+                Ok(Self::default())
+            }
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// A config per server: this one, then one for each of `upstreams.servers`.
+    pub(crate) fn per_server(self: &Arc<Self>) -> Vec<Arc<Self>> {
+        let mut configs = vec![self.clone()];
+        for upstream in &self.upstreams.servers {
+            let mut config = Self::clone(self);
+            config.server = upstream.server;
+            config.server_name = upstream.server_name.clone();
+            configs.push(Arc::new(config));
+        }
+        configs
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    sync::{mpsc::UnboundedSender, oneshot},
+};
+
+use crate::config::Config;
+use crate::tcp::{self, Session, Status};
+use crate::tunnel::{FlowInfo, FlowQuery};
+
+/// One command, sent as a single JSON line, e.g. `{"cmd":"switch_profile","name":"work"}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    Status,
+    /// Status of every server in the pool.
+    Upstreams,
+    Flows,
+    Reconnect,
+    Disconnect,
+    SwitchProfile {
+        name: String,
+    },
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstreams: Option<Vec<Status>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flows: Option<Vec<FlowInfo>>,
+}
+
+/// Local control socket: newline-delimited JSON over a Unix socket.
+pub(crate) struct Control {
+    /// The configured server first, then the other servers of the pool, with
+    /// a session per path of each.
+    pub sessions: Vec<Arc<Session>>,
+    /// Flows of the TUN, `None` without one.
+    pub flows: Option<UnboundedSender<FlowQuery>>,
+    pub profiles: HashMap<String, PathBuf>,
+}
+
+impl Control {
+    /// Binds `path`, replacing a socket left over from a previous run, and
+    /// makes it accessible to the owner only.
+    pub(crate) fn serve(self, path: &Path) -> Result<()> {
+        let listener = encryption::local::bind_private(path)?;
+        log::info!("control socket on {}", path.display());
+
+        let control = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("control: {}", e);
+                        continue;
+                    }
+                };
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = control.session(stream).await {
+                        log::warn!("control: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    async fn session(&self, stream: UnixStream) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    log::info!("control: {:?}", request);
+                    self.handle(request).await.unwrap_or_else(|e| Response {
+                        error: Some(format!("{:#}", e)),
+                        ..Response::default()
+                    })
+                }
+                Err(e) => Response {
+                    error: Some(format!("bad request: {}", e)),
+                    ..Response::default()
+                },
+            };
+            let mut out = serde_json::to_vec(&response).expect("serializing control response");
+            out.push(b'\n');
+            writer.write_all(&out).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, request: Request) -> Result<Response> {
+        let mut response = Response {
+            ok: true,
+            ..Response::default()
+        };
+        match request {
+            Request::Status => response.status = Some(self.sessions[0].status()),
+            Request::Upstreams => {
+                response.upstreams = Some(self.sessions.iter().map(|s| s.status()).collect())
+            }
+            Request::Flows => {
+                let (reply, flows) = oneshot::channel();
+                self.flows
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("flows are listed for the TUN only, which is off"))?
+                    .send(reply)
+                    .map_err(|_| anyhow!("tunnel is not running"))?;
+                let mut flows = flows.await?;
+                flows.sort_by_key(|flow| (flow.remote, flow.local));
+                response.flows = Some(flows);
+            }
+            Request::Reconnect => {
+                // a dead server must not keep the others down
+                let mut errors = vec![];
+                for session in &self.sessions {
+                    if let Err(e) = session.connect().await {
+                        errors.push(format!("{:#}", e));
+                    }
+                }
+                if !errors.is_empty() {
+                    return Err(anyhow!(errors.join("; ")));
+                }
+            }
+            Request::Disconnect => {
+                for session in &self.sessions {
+                    session.disconnect();
+                }
+            }
+            Request::SwitchProfile { name } => {
+                let path = self
+                    .profiles
+                    .get(&name)
+                    .ok_or_else(|| anyhow!("no profile named {}", name))?;
+                // Config::load falls back to defaults, which is no profile at all
+                std::fs::metadata(path).with_context(|| format!("reading {}", path.display()))?;
+                if self.sessions[0].interface().is_some() {
+                    return Err(anyhow!("profiles cannot be switched with multipath"));
+                }
+                let config = Config::load(path)?;
+                let aead_key = tcp::load_key(&config.aead_key)?;
+                // only the configured server follows the profile, the rest of the pool stays
+                self.sessions[0]
+                    .switch(Arc::new(config), aead_key, Some(name))
+                    .await?;
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        let parse = |line| serde_json::from_str::<Request>(line);
+        assert!(matches!(parse(r#"{"cmd":"flows"}"#), Ok(Request::Flows)));
+        assert!(matches!(
+            parse(r#"{"cmd":"switch_profile","name":"work"}"#),
+            Ok(Request::SwitchProfile { name }) if name == "work"
+        ));
+        assert!(parse(r#"{"cmd":"switch_profile"}"#).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{Context, Result, anyhow};
+use encryption::pki::write_private;
+
+use crate::config::Config;
+use crate::tcp;
+
+/// SOCKS5 private method the server uses for certificate enrollment.
+const ENROLLMENT_METHOD: u8 = 0x80;
+
+/// Redeems a one-time enrollment token: generates a key pair, has the server
+/// sign it and stores the certificate and key at the configured `cert`/`key`.
+pub(crate) async fn enroll(config: &Config, token: &str) -> Result<()> {
+    let (Some(cert_path), Some(key_path)) = (&config.cert, &config.key) else {
+        return Err(anyhow!("set cert and key in the config to enroll"));
+    };
+    let name = config.username.as_deref().unwrap_or("client");
+    let (csr, key_pem) = encryption::pki::client_csr(name)?;
+
+    let connection = tcp::connect(
+        &tcp::bind(None)?,
+        config,
+        &tcp::client_config(config, false)?,
+    )?
+    .await?;
+    let (mut sender, mut reader) = connection.open_bi().await?;
+
+    // |version, nmethods, method|
+    sender.write_all(&[0x05, 1, ENROLLMENT_METHOD]).await?;
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf).await?;
+    if buf != [0x05, ENROLLMENT_METHOD] {
+        return Err(anyhow!("server does not accept enrollment"));
+    }
+
+    // |version, token_len, token, csr_len (u16), csr|
+    let mut request = Vec::with_capacity(4 + token.len() + csr.len());
+    request.push(0x01);
+    request.push(token.len() as u8);
+    request.extend_from_slice(token.as_bytes());
+    request.extend_from_slice(&(csr.len() as u16).to_be_bytes());
+    request.extend_from_slice(&csr);
+    sender.write_all(&request).await?;
+
+    // |version, status, cert_len (u16), cert chain (PEM)|
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header).await?;
+    if header[0] != 0x01 {
+        return Err(anyhow!("invalid enrollment version in reply"));
+    }
+    if header[1] != 0x00 {
+        return Err(anyhow!("enrollment token rejected"));
+    }
+    let mut cert = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+    reader.read_exact(&mut cert).await?;
+    connection.close(0u32.into(), b"enrolled");
+
+    write_private(key_path, key_pem.as_bytes())?;
+    std::fs::write(cert_path, &cert).with_context(|| format!("writing {}", cert_path.display()))?;
+    log::info!("enrolled, certificate written to {}", cert_path.display());
+    Ok(())
+}
@@ -1,34 +1,151 @@
-use anyhow::Result;
-use std::{fs::File, io::Read, path::Path};
+use anyhow::{Result, anyhow};
+use encryption::Key;
+use rustls::crypto::{CryptoProvider, ring};
+use std::{path::Path, pin::Pin, sync::Arc};
 use tokio::{self};
 
+use crate::config::{Config, Engine};
+use crate::control::Control;
+use crate::metrics::Metrics;
+use crate::tcp::{Bond, Session};
+
+mod config;
+mod control;
+mod enroll;
+mod metrics;
+mod mtu;
+mod netlink;
+mod proxy;
 mod tcp;
 mod tun;
 mod tunnel;
 
+/// The loop of the TUN engine picked at runtime.
+type Run = Pin<Box<dyn Future<Output = ()>>>;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+    CryptoProvider::install_default(ring::default_provider())
+        .expect("failed to install default crypto provider");
 
-    let path = Path::new("/etc/xchacha20.key");
-    let mut file = File::open(path)?;
-    let mut aead_key = vec![];
-    file.read_to_end(&mut aead_key)?;
-    let aead_key = aead_key.as_slice().into();
+    let mut args = std::env::args().skip(1);
+    let config_path = match args.next() {
+        Some(cmd) if cmd == "enroll" => {
+            let token = args
+                .next()
+                .ok_or_else(|| anyhow!("usage: client enroll <token> [config]"))?;
+            let config_path = args
+                .next()
+                .unwrap_or_else(|| config::DEFAULT_CONFIG_PATH.to_string());
+            return enroll::enroll(&Config::load(Path::new(&config_path))?, &token).await;
+        }
+        Some(path) => path,
+        None => config::DEFAULT_CONFIG_PATH.to_string(),
+    };
+    let config = Arc::new(Config::load(Path::new(&config_path))?);
+    let aead_key = tcp::load_key(&config.aead_key)?;
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = config.metrics {
+        metrics
+            .clone()
+            .serve(encryption::metrics::bind(addr).await?)?;
+    }
 
-    let tun = tun::Tun::new();
-    let vpn = tcp::TcpUpstream::new("172.28.0.3:1080".parse().unwrap(), aead_key).await;
-    let mut tunnel = tunnel::Tunnel::new(tun, vpn);
+    let servers: Vec<Vec<_>> = config
+        .per_server()
+        .into_iter()
+        .map(|config| paths(config, aead_key, &metrics))
+        .collect();
+    let sessions: Vec<_> = servers.iter().flatten().cloned().collect();
+    for session in &sessions {
+        session.clone().supervise(config.reconnect);
+    }
+    if let Err(e) = netlink::watch(sessions.clone()) {
+        log::warn!("not following network changes: {:#}", e);
+    }
+
+    if !config.tun && config.proxy.is_none() && config.transparent.listen.is_none() {
+        return Err(anyhow!(
+            "tun is off and neither proxy nor transparent.listen is set"
+        ));
+    }
+    if let Some(addr) = config.proxy {
+        let vpn = tcp::Pool::new(servers.clone(), config.upstreams.strategy, metrics.clone());
+        proxy::serve(addr, vpn).await?;
+    }
+    if let Some(addr) = config.transparent.listen {
+        let vpn = tcp::Pool::new(servers.clone(), config.upstreams.strategy, metrics.clone());
+        proxy::serve_transparent(addr, config.transparent.tproxy, vpn).await?;
+    }
+    let tunnel: Option<(_, Run)> = config.tun.then(|| {
+        let mtu = mtu::watch(sessions.clone(), config.transport.initial_mtu);
+        let tun = tun::Tun::new(*mtu.borrow());
+        let vpn = tcp::Pool::new(servers, config.upstreams.strategy, metrics.clone());
+        match config.engine {
+            Engine::Builtin => {
+                let mut tunnel = tunnel::Tunnel::new(tun, vpn, mtu, metrics);
+                let flows = tunnel.flow_queries();
+                (
+                    flows,
+                    Box::pin(async move { tunnel.loop_read().await }) as Pin<Box<_>>,
+                )
+            }
+            Engine::Smoltcp => {
+                let mut stack = tunnel::Stack::new(tun, vpn, mtu, metrics);
+                let flows = stack.flow_queries();
+                (
+                    flows,
+                    Box::pin(async move { stack.loop_read().await }) as Pin<Box<_>>,
+                )
+            }
+        }
+    });
+    let (flows, tunnel) = tunnel.unzip();
+    if let Some(socket) = &config.control {
+        Control {
+            sessions,
+            flows,
+            profiles: config.profiles.clone(),
+        }
+        .serve(socket)?;
+    }
 
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             println!("Received SIGINT/SIGTERM, shutting down...");
         },
-        _ = tunnel.loop_read() => {},
+        _ = async {
+            match tunnel {
+                Some(tunnel) => tunnel.await,
+                None => std::future::pending().await,
+            }
+        } => {},
+    }
+    if let Some(socket) = &config.control {
+        let _ = std::fs::remove_file(socket);
     }
     Ok(())
 }
 
+/// The sessions to the server of `config`, one per multipath interface.
+fn paths(config: Arc<Config>, aead_key: Key, metrics: &Arc<Metrics>) -> Vec<Arc<Session>> {
+    if config.multipath.interfaces.is_empty() {
+        return vec![Arc::new(Session::new(config, aead_key, metrics.clone()))];
+    }
+    let bond = Arc::new(Bond::new());
+    config
+        .multipath
+        .interfaces
+        .iter()
+        .map(|interface| {
+            let session = Session::new(config.clone(), aead_key, metrics.clone());
+            Arc::new(session.on_path(interface.clone(), bond.clone()))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     // use super::*;
@@ -0,0 +1,279 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use encryption::metrics::label_value;
+use quinn::Connection;
+use serde::Serialize;
+use tokio::net::TcpListener;
+
+/// Upper bounds of the stream open latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Lifecycle of a flow in the tunnel's flow table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FlowState {
+    /// SYN answered, waiting for the kernel's ACK.
+    SynReceived,
+    Established,
+}
+
+/// Why a packet read from the TUN device was not handled.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Dropped {
+    /// Not an IPv4 or TCP packet we can parse.
+    Unparseable,
+    /// Parsed fine, but not TCP.
+    Protocol,
+    /// TCP segment for a flow we don't know that isn't a SYN.
+    UnknownFlow,
+    /// Larger than the tunnel MTU, queued before it shrank.
+    TooBig,
+}
+
+/// Client counters, rendered in the Prometheus text format on `/metrics`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    syn_received: AtomicI64,
+    established: AtomicI64,
+    packets_read: AtomicU64,
+    bytes_read: AtomicU64,
+    packets_written: AtomicU64,
+    bytes_written: AtomicU64,
+    unparseable: AtomicU64,
+    dropped_protocol: AtomicU64,
+    dropped_unknown_flow: AtomicU64,
+    dropped_too_big: AtomicU64,
+    open_failures: AtomicU64,
+    open_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    open_count: AtomicU64,
+    open_sum_micros: AtomicU64,
+    /// The latest connection of each server and path, by their labels.
+    connections: Mutex<BTreeMap<String, Connection>>,
+}
+
+impl Metrics {
+    fn flows(&self, state: FlowState) -> &AtomicI64 {
+        match state {
+            FlowState::SynReceived => &self.syn_received,
+            FlowState::Established => &self.established,
+        }
+    }
+
+    pub(crate) fn flow_opened(&self, state: FlowState) {
+        self.flows(state).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn flow_closed(&self, state: FlowState) {
+        self.flows(state).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn flow_moved(&self, from: FlowState, to: FlowState) {
+        self.flow_closed(from);
+        self.flow_opened(to);
+    }
+
+    pub(crate) fn packet_read(&self, len: usize) {
+        self.packets_read.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn packet_written(&self, len: usize) {
+        self.packets_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self, reason: Dropped) {
+        let counter = match reason {
+            Dropped::Unparseable => &self.unparseable,
+            Dropped::Protocol => &self.dropped_protocol,
+            Dropped::UnknownFlow => &self.dropped_unknown_flow,
+            Dropped::TooBig => &self.dropped_too_big,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time from asking for an upstream stream to the server's CONNECT reply.
+    pub(crate) fn stream_opened(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bucket, bound) in self.open_buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.open_count.fetch_add(1, Ordering::Relaxed);
+        self.open_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stream_failed(&self) {
+        self.open_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The QUIC connection path stats of `server` are read from, over
+    /// `interface` with multipath, replacing those of `previous` when a
+    /// profile switch moved the session to another server.
+    pub(crate) fn set_connection(
+        &self,
+        server: SocketAddr,
+        interface: Option<&str>,
+        previous: SocketAddr,
+        connection: Connection,
+    ) {
+        let labels = |server: SocketAddr| match interface {
+            Some(interface) => format!(
+                "server=\"{}\",interface=\"{}\"",
+                server,
+                label_value(interface)
+            ),
+            None => format!("server=\"{}\"", server),
+        };
+        let mut connections = self.connections.lock().unwrap();
+        if previous != server {
+            connections.remove(&labels(previous));
+        }
+        connections.insert(labels(server), connection);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let _ = writeln!(out, "# TYPE vpn_client_flows gauge");
+        for (state, gauge) in [
+            ("syn_received", &self.syn_received),
+            ("established", &self.established),
+        ] {
+            let _ = writeln!(
+                out,
+                "vpn_client_flows{{state=\"{}\"}} {}",
+                state,
+                gauge.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE vpn_client_tun_packets_total counter");
+        let _ = writeln!(
+            out,
+            "vpn_client_tun_packets_total{{direction=\"read\"}} {}",
+            load(&self.packets_read)
+        );
+        let _ = writeln!(
+            out,
+            "vpn_client_tun_packets_total{{direction=\"written\"}} {}",
+            load(&self.packets_written)
+        );
+        let _ = writeln!(out, "# TYPE vpn_client_tun_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "vpn_client_tun_bytes_total{{direction=\"read\"}} {}",
+            load(&self.bytes_read)
+        );
+        let _ = writeln!(
+            out,
+            "vpn_client_tun_bytes_total{{direction=\"written\"}} {}",
+            load(&self.bytes_written)
+        );
+
+        let _ = writeln!(out, "# TYPE vpn_client_dropped_packets_total counter");
+        for (reason, counter) in [
+            ("unparseable", &self.unparseable),
+            ("protocol", &self.dropped_protocol),
+            ("unknown_flow", &self.dropped_unknown_flow),
+            ("too_big", &self.dropped_too_big),
+        ] {
+            let _ = writeln!(
+                out,
+                "vpn_client_dropped_packets_total{{reason=\"{}\"}} {}",
+                reason,
+                load(counter)
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE vpn_client_stream_open_failures_total counter");
+        let _ = writeln!(
+            out,
+            "vpn_client_stream_open_failures_total {}",
+            load(&self.open_failures)
+        );
+        let name = "vpn_client_stream_open_duration_seconds";
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, bound) in self.open_buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, load(bucket));
+        }
+        let count = load(&self.open_count);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = load(&self.open_sum_micros) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        let paths: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(labels, connection)| (labels.clone(), connection.stats().path))
+            .collect();
+        if !paths.is_empty() {
+            let _ = writeln!(out, "# TYPE vpn_client_rtt_seconds gauge");
+            for (labels, path) in &paths {
+                let rtt = path.rtt.as_secs_f64();
+                let _ = writeln!(out, "vpn_client_rtt_seconds{{{}}} {}", labels, rtt);
+            }
+            let _ = writeln!(out, "# TYPE vpn_client_cwnd_bytes gauge");
+            for (labels, path) in &paths {
+                let _ = writeln!(out, "vpn_client_cwnd_bytes{{{}}} {}", labels, path.cwnd);
+            }
+            let _ = writeln!(out, "# TYPE vpn_client_lost_packets_total counter");
+            for (labels, path) in &paths {
+                let lost = path.lost_packets;
+                let _ = writeln!(out, "vpn_client_lost_packets_total{{{}}} {}", labels, lost);
+            }
+            let _ = writeln!(out, "# TYPE vpn_client_sent_packets_total counter");
+            for (labels, path) in &paths {
+                let sent = path.sent_packets;
+                let _ = writeln!(out, "vpn_client_sent_packets_total{{{}}} {}", labels, sent);
+            }
+        }
+        out
+    }
+
+    /// Serves `GET /metrics` on `listener`, which should be on a loopback
+    /// address.
+    pub(crate) fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        encryption::metrics::serve(listener, move || self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.flow_opened(FlowState::SynReceived);
+        metrics.flow_opened(FlowState::SynReceived);
+        metrics.flow_moved(FlowState::SynReceived, FlowState::Established);
+        metrics.packet_read(60);
+        metrics.dropped(Dropped::Protocol);
+        metrics.stream_opened(Duration::from_millis(40));
+
+        let out = metrics.render();
+        assert!(out.contains("vpn_client_flows{state=\"syn_received\"} 1\n"));
+        assert!(out.contains("vpn_client_flows{state=\"established\"} 1\n"));
+        assert!(out.contains("vpn_client_tun_bytes_total{direction=\"read\"} 60\n"));
+        assert!(out.contains("vpn_client_dropped_packets_total{reason=\"protocol\"} 1\n"));
+        assert!(out.contains("vpn_client_stream_open_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(out.contains("vpn_client_stream_open_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+    }
+}
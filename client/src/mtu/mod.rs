@@ -0,0 +1,77 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use crate::tcp::Session;
+
+/// QUIC packets carry at least this much besides stream data: short header
+/// with the longest connection id and packet number, AEAD tag, and a STREAM
+/// frame header with the longest id, offset and length.
+const QUIC_OVERHEAD: u16 = 1 + 20 + 4 + 16 + 1 + 8 + 8 + 2;
+
+/// IPv4 and TCP headers without options, which are not tunnelled.
+const HEADERS: u16 = 20 + 20;
+
+/// How often the path MTUs are looked at. quinn raises them within a few
+/// round trips of connecting and lowers them as soon as probes get lost.
+const POLL: Duration = Duration::from_secs(1);
+
+/// The TUN MTU for QUIC packets of `path_mtu` bytes: a full TCP segment
+/// fits into one QUIC packet, so losing a packet never costs two segments.
+pub(crate) fn tunnel_mtu(path_mtu: u16) -> u16 {
+    path_mtu.saturating_sub(QUIC_OVERHEAD) + HEADERS
+}
+
+/// MSS of the TUN MTU `mtu`.
+pub(crate) fn mss(mtu: u16) -> u16 {
+    mtu - HEADERS
+}
+
+/// Follows the smallest path MTU of the sessions' connections, since a new
+/// flow may go to any of them, and yields the TUN MTU for it. Starts from
+/// `initial_mtu`, what quinn assumes before probing.
+pub(crate) fn watch(sessions: Vec<Arc<Session>>, initial_mtu: u16) -> watch::Receiver<u16> {
+    let (tx, rx) = watch::channel(tunnel_mtu(initial_mtu));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL);
+        loop {
+            interval.tick().await;
+            // keeps the last value while nothing is connected
+            let Some(path_mtu) = sessions.iter().filter_map(|s| s.path_mtu()).min() else {
+                continue;
+            };
+            let mtu = tunnel_mtu(path_mtu);
+            tx.send_if_modified(|current| {
+                if *current == mtu {
+                    return false;
+                }
+                log::info!(
+                    "QUIC path MTU is {}, tunnel MTU {} -> {}",
+                    path_mtu,
+                    current,
+                    mtu
+                );
+                *current = mtu;
+                true
+            });
+            if tx.is_closed() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_mtu() {
+        // the QUIC minimum
+        assert_eq!(tunnel_mtu(1200), 1180);
+        assert_eq!(mss(tunnel_mtu(1200)), 1140);
+        // Ethernet, 1500 less IPv4 and UDP headers
+        assert_eq!(tunnel_mtu(1472), 1452);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    ffi::CString,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::io::{Interest, unix::AsyncFd};
+
+use crate::tcp::Session;
+use crate::tun;
+
+/// How long to wait for the rest of a burst of changes, e.g. an address
+/// that comes up right after its link, before rebinding once.
+const SETTLE: Duration = Duration::from_millis(500);
+
+const NLMSG_HDRLEN: usize = 16;
+
+/// Rebinds the sessions' endpoints whenever an interface or address other
+/// than the tunnel's comes or goes.
+pub(crate) fn watch(sessions: Vec<Arc<Session>>) -> Result<()> {
+    let monitor = Monitor::open().context("opening netlink socket")?;
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = monitor.next_change().await {
+                log::warn!("netlink: {}, no longer watching for network changes", e);
+                return;
+            }
+            while let Ok(Ok(())) = tokio::time::timeout(SETTLE, monitor.next_change()).await {}
+            for session in &sessions {
+                if let Err(e) = session.rebind() {
+                    log::warn!("rebinding after a network change: {:#}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// An rtnetlink socket subscribed to link and address notifications.
+struct Monitor {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Monitor {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Waits for a notification about an interface other than the tunnel.
+    async fn next_change(&self) -> io::Result<()> {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let read = self
+                .fd
+                .async_io(Interest::READABLE, |fd| {
+                    let n = unsafe {
+                        libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0)
+                    };
+                    if n < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(n as usize)
+                })
+                .await;
+            let n = match read {
+                Ok(n) => n,
+                // notifications were dropped, so something changed
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let tun = tunnel_index();
+            if interfaces(&buf[..n]).any(|index| index != tun) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Index of the tunnel interface, 0 while it does not exist.
+fn tunnel_index() -> u32 {
+    let name = CString::new(tun::NAME).unwrap();
+    unsafe { libc::if_nametoindex(name.as_ptr()) }
+}
+
+/// Interface indexes of the link and address messages in `buf`.
+fn interfaces(mut buf: &[u8]) -> impl Iterator<Item = u32> {
+    std::iter::from_fn(move || {
+        loop {
+            if buf.len() < NLMSG_HDRLEN {
+                return None;
+            }
+            let len = u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize;
+            let kind = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+            if len < NLMSG_HDRLEN || len > buf.len() {
+                return None;
+            }
+            let payload = &buf[NLMSG_HDRLEN..len];
+            buf = &buf[((len + 3) & !3).min(buf.len())..];
+            // ifinfomsg and ifaddrmsg both have the index at offset 4
+            let is_change = matches!(
+                kind,
+                libc::RTM_NEWLINK | libc::RTM_DELLINK | libc::RTM_NEWADDR | libc::RTM_DELADDR
+            );
+            if is_change && payload.len() >= 8 {
+                return Some(u32::from_ne_bytes(payload[4..8].try_into().unwrap()));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, index: u32, payload_len: usize) -> Vec<u8> {
+        let len = NLMSG_HDRLEN + payload_len;
+        let mut msg = vec![0u8; (len + 3) & !3];
+        msg[..4].copy_from_slice(&(len as u32).to_ne_bytes());
+        msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+        msg[NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8].copy_from_slice(&index.to_ne_bytes());
+        msg
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let mut buf = message(libc::RTM_NEWADDR, 3, 9);
+        buf.extend(message(libc::RTM_NEWROUTE, 7, 12));
+        buf.extend(message(libc::RTM_DELLINK, 5, 16));
+        assert_eq!(interfaces(&buf).collect::<Vec<_>>(), [3, 5]);
+        assert_eq!(interfaces(&buf[..20]).count(), 0);
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::tunnel::{ConnectFailed, FLOW_QUEUE, FlowKey, Response, VPNUpstream};
+
+mod transparent;
+
+pub(crate) use transparent::serve_transparent;
+
+/// Longest HTTP CONNECT request head accepted.
+const MAX_HEAD: usize = 8192;
+
+/// Time a client gets to send its request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a proxy client asked to go: a name, which the server resolves, or
+/// an address literal.
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    host: String,
+    port: u16,
+}
+
+impl Target {
+    /// The flow key for the target. Flows are keyed by an IPv4 address, so
+    /// names and IPv6 addresses get the unspecified one; the host itself goes
+    /// to the server.
+    fn key(&self, peer: SocketAddr) -> FlowKey {
+        let ip = self.host.parse().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        (ip, self.port, peer.port())
+    }
+}
+
+/// How a client asked for its target, which is how it gets the outcome.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Socks5,
+    HttpConnect,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.parse::<Ipv6Addr>() {
+            Ok(_) => write!(f, "[{}]:{}", self.host, self.port),
+            Err(_) => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Self {
+        Target {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+/// Serves SOCKS5 and HTTP CONNECT on `addr`, telling them apart by the first
+/// byte. Each accepted connection becomes a flow of `upstream`, the same as
+/// a TCP connection through the TUN, so apps can use the tunnel by setting a
+/// proxy where no TUN device can be created.
+pub(crate) async fn serve<UPSTREAM>(addr: SocketAddr, upstream: UPSTREAM) -> Result<()>
+where
+    UPSTREAM: VPNUpstream + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding proxy listener {}", addr))?;
+    log::info!("SOCKS5 and HTTP CONNECT proxy on {}", addr);
+    let upstream = Arc::new(Mutex::new(upstream));
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("proxy: {}", e);
+                    continue;
+                }
+            };
+            let upstream = upstream.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, peer, upstream).await {
+                    log::debug!("proxy connection from {}: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle<UPSTREAM: VPNUpstream>(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    upstream: Arc<Mutex<UPSTREAM>>,
+) -> Result<()> {
+    let (protocol, dest, early) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream))
+        .await
+        .map_err(|_| anyhow!("no request within {:?}", HANDSHAKE_TIMEOUT))??;
+    relay(stream, peer, dest, early, &upstream, Some(protocol)).await
+}
+
+/// Relays `stream` from `peer` as a flow of `upstream` to `dest`, starting
+/// with `early`, until either side closes. Once the server connected the
+/// flow, or failed to, a client that asked with `protocol` is told.
+async fn relay<UPSTREAM: VPNUpstream>(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    dest: Target,
+    early: Vec<u8>,
+    upstream: &Mutex<UPSTREAM>,
+    protocol: Option<Protocol>,
+) -> Result<()> {
+    log::debug!("proxying {} to {}", peer, dest);
+
+    let key = dest.key(peer);
+    let (up_tx, up_rx) = mpsc::channel(FLOW_QUEUE);
+    let (down_tx, mut down_rx) = mpsc::channel::<Response>(FLOW_QUEUE);
+    let (opened_tx, opened) = oneshot::channel();
+    let notify = upstream.lock().unwrap().new_connection(
+        key,
+        Some(dest.host),
+        up_rx,
+        down_tx,
+        Some(opened_tx),
+    )?;
+    let opened = opened
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("flow closed while opening")));
+    if let Some(protocol) = protocol {
+        let status = match &opened {
+            Ok(()) => 0x00,
+            // general failure
+            Err(e) => e.downcast_ref::<ConnectFailed>().map_or(0x01, |e| e.0),
+        };
+        reply(&mut stream, protocol, status).await?;
+    }
+    opened?;
+    if !early.is_empty() {
+        let _ = up_tx.send(early).await;
+    }
+
+    let (mut reader, mut writer) = stream.split();
+    // each way on its own, so a client that does not read does not keep
+    // its own data from going out, and either side can close its half
+    let up = async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = reader
+                .read(&mut buf)
+                .await
+                .context("reading from proxy client")?;
+            // dropping `up_tx` finishes the stream, like a FIN from the kernel
+            if n == 0 || up_tx.send(buf[..n].to_vec()).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+    let down = async {
+        while let Some(response) = down_rx.recv().await {
+            if response.reset {
+                return Err(anyhow!("flow reset"));
+            }
+            if response.payload.is_empty() {
+                return writer.shutdown().await.context("closing proxy client");
+            }
+            writer
+                .write_all(&response.payload)
+                .await
+                .context("writing to proxy client")?;
+        }
+        Ok(())
+    };
+    let relayed = tokio::try_join!(up, down).map(|_| ());
+    notify.notify_one();
+    relayed
+}
+
+/// Reads a SOCKS5 or HTTP CONNECT request from `stream`. Returns the
+/// destination and whatever the client sent past the request; the client
+/// gets its `reply` once the flow is opened.
+async fn handshake<S>(stream: &mut S) -> Result<(Protocol, Target, Vec<u8>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match stream.read_u8().await.context("reading request")? {
+        0x05 => Ok((Protocol::Socks5, socks5(stream).await?, vec![])),
+        first => {
+            let (dest, early) = http_connect(stream, first).await?;
+            Ok((Protocol::HttpConnect, dest, early))
+        }
+    }
+}
+
+/// Tells the client how its request went, by SOCKS5 reply `status`. HTTP
+/// has no statuses as fine-grained: a refusal by the rules is forbidden,
+/// anything else a bad gateway.
+async fn reply<S>(stream: &mut S, protocol: Protocol, status: u8) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let http: &[u8] = match status {
+        0x00 => b"HTTP/1.1 200 Connection established\r\n\r\n",
+        0x02 => b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+        _ => b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n",
+    };
+    match protocol {
+        Protocol::Socks5 => socks5_reply(stream, status).await,
+        Protocol::HttpConnect => Ok(stream.write_all(http).await?),
+    }
+}
+
+/// The rest of a SOCKS5 request without authentication, after the version.
+async fn socks5<S>(stream: &mut S) -> Result<Target>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // |version, n methods, methods|
+    let n = stream.read_u8().await?;
+    let mut methods = vec![0u8; n as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&0x00) {
+        stream.write_all(&[0x05, 0xff]).await?;
+        return Err(anyhow!("SOCKS5 client requires authentication"));
+    }
+    stream.write_all(&[0x05, 0x00]).await?;
+
+    // |version, command, reserved, dst addr: |type, addr|, dst port|
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 0x05 {
+        return Err(anyhow!("invalid SOCKS5 version {}", head[0]));
+    }
+    let host = match head[3] {
+        0x01 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        0x03 => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        0x04 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            socks5_reply(stream, 0x08).await?;
+            return Err(anyhow!("SOCKS5 address type {} not supported", head[3]));
+        }
+    };
+    let port = stream.read_u16().await?;
+    if head[1] != 0x01 {
+        socks5_reply(stream, 0x07).await?;
+        return Err(anyhow!("only CONNECT command supported"));
+    }
+    Ok(Target { host, port })
+}
+
+async fn socks5_reply<S: AsyncWrite + Unpin>(stream: &mut S, status: u8) -> Result<()> {
+    // |version, status, reserved, bound address: |type (ipv4), addr|, bound port|
+    stream
+        .write_all(&[0x05, status, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+/// The rest of an HTTP CONNECT request, after its `first` byte.
+async fn http_connect<S>(stream: &mut S, first: u8) -> Result<(Target, Vec<u8>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = vec![first];
+    let mut buf = [0u8; 1024];
+    let end = loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if head.len() > MAX_HEAD {
+            return Err(anyhow!("HTTP request head over {} bytes", MAX_HEAD));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("closed within the HTTP request head"));
+        }
+        head.extend_from_slice(&buf[..n]);
+    };
+    let early = head.split_off(end);
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    if request_line.next() != Some("CONNECT") {
+        stream
+            .write_all(
+                b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await?;
+        return Err(anyhow!("only CONNECT method supported"));
+    }
+    let target = request_line.next().unwrap_or_default();
+    let Some((host, port)) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .filter(|(host, _)| !host.is_empty())
+    else {
+        stream
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        return Err(anyhow!("invalid CONNECT target {:?}", target));
+    };
+    // IPv6 literals are bracketed
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let host = match host.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.to_string(),
+    };
+    Ok((Target { host, port }, early))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    /// Runs `handshake` against a client sending `request`, returning its
+    /// result and what the client read back.
+    async fn accept(request: &[u8]) -> (Result<(Protocol, Target, Vec<u8>)>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+        let accepted = handshake(&mut server).await;
+        drop(server);
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        (accepted, reply)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let dest = Target {
+            host: "10.1.2.3".into(),
+            port: 443,
+        };
+
+        // only the method is chosen until the flow is opened
+        let socks = [5, 1, 0, 5, 1, 0, 1, 10, 1, 2, 3, 1, 187];
+        let (accepted, reply) = accept(&socks).await;
+        assert_eq!(accepted.unwrap(), (Protocol::Socks5, dest.clone(), vec![]));
+        assert_eq!(reply, [5, 0]);
+
+        let bind = [5, 1, 0, 5, 2, 0, 1, 10, 1, 2, 3, 1, 187];
+        let (accepted, reply) = accept(&bind).await;
+        assert!(accepted.is_err());
+        assert_eq!(reply[3], 7);
+
+        let http = b"CONNECT 10.1.2.3:443 HTTP/1.1\r\nHost: 10.1.2.3:443\r\n\r\nhello";
+        let (accepted, reply) = accept(http).await;
+        let expected = (Protocol::HttpConnect, dest, b"hello".to_vec());
+        assert_eq!(accepted.unwrap(), expected);
+        assert!(reply.is_empty());
+
+        // names and IPv6 addresses are passed on for the server
+        let mut socks = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        socks.extend_from_slice(b"example.com");
+        socks.extend_from_slice(&[1, 187]);
+        let (accepted, _) = accept(&socks).await;
+        assert_eq!(accepted.unwrap().1.host, "example.com");
+        let (accepted, _) = accept(b"CONNECT [::1]:443 HTTP/1.1\r\n\r\n").await;
+        let (_, target, _) = accepted.unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.to_string(), "[::1]:443");
+
+        let (accepted, reply) = accept(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(accepted.is_err());
+        assert!(reply.starts_with(b"HTTP/1.1 405 "));
+    }
+
+    /// Hands each flow over to the test.
+    #[derive(Default)]
+    struct Upstream {
+        flows: Vec<Flow>,
+    }
+
+    struct Flow {
+        rx: mpsc::Receiver<Vec<u8>>,
+        tx: mpsc::Sender<Response>,
+        opened: oneshot::Sender<Result<()>>,
+    }
+
+    impl VPNUpstream for Upstream {
+        fn new_connection(
+            &mut self,
+            _: FlowKey,
+            _: Option<String>,
+            rx: mpsc::Receiver<Vec<u8>>,
+            tx: mpsc::Sender<Response>,
+            opened: Option<oneshot::Sender<Result<()>>>,
+        ) -> Result<Arc<Notify>> {
+            let opened = opened.unwrap();
+            self.flows.push(Flow { rx, tx, opened });
+            Ok(Arc::new(Notify::new()))
+        }
+    }
+
+    /// Starts relaying a connection to `example.com`, returning the client
+    /// end, the relay and its flow once opened.
+    async fn proxied(
+        protocol: Option<Protocol>,
+    ) -> (TcpStream, tokio::task::JoinHandle<Result<()>>, Flow) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let upstream = Arc::new(Mutex::new(Upstream::default()));
+        let dest = Target {
+            host: "example.com".into(),
+            port: 443,
+        };
+        let relayed = tokio::spawn({
+            let upstream = upstream.clone();
+            async move { relay(stream, peer, dest, vec![], &upstream, protocol).await }
+        });
+        loop {
+            if let Some(flow) = upstream.lock().unwrap().flows.pop() {
+                return (client, relayed, flow);
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_after_connect() {
+        let (mut client, relayed, flow) = proxied(Some(Protocol::Socks5)).await;
+        let mut answer = [0u8; 10];
+        let early = tokio::time::timeout(Duration::from_millis(100), client.read(&mut answer));
+        assert!(early.await.is_err(), "replied before the flow was opened");
+
+        // refused by the server's rules
+        flow.opened.send(Err(ConnectFailed(0x02).into())).unwrap();
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer[..2], [5, 2]);
+        assert!(relayed.await.unwrap().is_err());
+
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        reply(&mut server, Protocol::HttpConnect, 0x02)
+            .await
+            .unwrap();
+        reply(&mut server, Protocol::HttpConnect, 0x05)
+            .await
+            .unwrap();
+        drop(server);
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        assert!(replies.starts_with("HTTP/1.1 403 "));
+        assert!(replies.contains("HTTP/1.1 502 "));
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let (mut client, relayed, mut flow) = proxied(None).await;
+        flow.opened.send(Ok(())).unwrap();
+
+        // the client is done sending, the server is not
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(flow.rx.recv().await.unwrap(), b"ping");
+        assert!(flow.rx.recv().await.is_none());
+        let key = (Ipv4Addr::UNSPECIFIED, 443, 0);
+        let pong = Response {
+            payload: b"pong".to_vec(),
+            flow_key: key,
+            reset: false,
+        };
+        flow.tx.send(pong).await.unwrap();
+        flow.tx.send(Response::end(key)).await.unwrap();
+
+        let mut got = vec![];
+        client.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"pong");
+        relayed.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, anyhow};
+use tokio::net::{TcpSocket, TcpStream};
+
+use super::relay;
+use crate::tunnel::VPNUpstream;
+
+/// Accepts TCP connections that iptables or nftables rules divert to `addr`
+/// and relays each as a flow of `upstream` to where it was headed. The
+/// kernel terminates TCP here, so nothing goes through `Tunnel`.
+///
+/// With `tproxy` the listener takes connections for any address, as `TPROXY`
+/// rules need, which requires `CAP_NET_ADMIN`; otherwise it expects
+/// `REDIRECT` rules.
+pub(crate) async fn serve_transparent<UPSTREAM>(
+    addr: SocketAddr,
+    tproxy: bool,
+    upstream: UPSTREAM,
+) -> Result<()>
+where
+    UPSTREAM: VPNUpstream + Send + 'static,
+{
+    let SocketAddr::V4(listen) = addr else {
+        return Err(anyhow!("transparent listener must be IPv4, flows are"));
+    };
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    if tproxy {
+        let enabled: libc::c_int = 1;
+        let set = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_IP,
+                libc::IP_TRANSPARENT,
+                (&enabled as *const libc::c_int).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if set < 0 {
+            return Err(io::Error::last_os_error())
+                .context("setting IP_TRANSPARENT, which needs CAP_NET_ADMIN");
+        }
+    }
+    socket
+        .bind(addr)
+        .with_context(|| format!("binding transparent listener {}", addr))?;
+    let listener = socket.listen(1024)?;
+    log::info!(
+        "transparent proxy on {} for {} rules",
+        addr,
+        if tproxy { "TPROXY" } else { "REDIRECT" }
+    );
+
+    let upstream = Arc::new(Mutex::new(upstream));
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("transparent proxy: {}", e);
+                    continue;
+                }
+            };
+            let dest = match original_dst(&stream, listen, tproxy) {
+                Ok(dest) => dest,
+                Err(e) => {
+                    log::debug!("transparent connection from {}: {:#}", peer, e);
+                    continue;
+                }
+            };
+            let upstream = upstream.clone();
+            tokio::spawn(async move {
+                let dest = SocketAddr::V4(dest).into();
+                if let Err(e) = relay(stream, peer, dest, vec![], &upstream, None).await {
+                    log::debug!("transparent connection from {}: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Where `stream`, accepted on `listen`, was headed before the rules
+/// diverted it. `TPROXY` keeps the destination as the local address of the
+/// socket, `REDIRECT` rewrites it and conntrack keeps the original for
+/// `SO_ORIGINAL_DST`.
+fn original_dst(stream: &TcpStream, listen: SocketAddrV4, tproxy: bool) -> Result<SocketAddrV4> {
+    let SocketAddr::V4(local) = stream.local_addr()? else {
+        return Err(anyhow!("IPv6 is not tunnelled"));
+    };
+    if tproxy {
+        // connected to the listener itself, relaying would loop back here
+        let own = local == listen
+            || (listen.ip().is_unspecified()
+                && local.port() == listen.port()
+                && is_local(*local.ip()));
+        if own {
+            return Err(anyhow!("connected to the proxy itself"));
+        }
+        return Ok(local);
+    }
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let found = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            (&mut addr as *mut libc::sockaddr_in).cast(),
+            &mut len,
+        )
+    };
+    if found < 0 {
+        return Err(io::Error::last_os_error()).context("reading SO_ORIGINAL_DST");
+    }
+    let original = SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    );
+    // connected to the listener itself, relaying would loop back here
+    if original == local {
+        return Err(anyhow!("not redirected"));
+    }
+    Ok(original)
+}
+
+/// Whether `ip` is an address of this host, which only those can be bound
+/// to without `IP_TRANSPARENT`.
+fn is_local(ip: Ipv4Addr) -> bool {
+    std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_original_dst() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let SocketAddr::V4(addr) = addr else {
+            unreachable!()
+        };
+        let other = SocketAddrV4::new(*addr.ip(), 1);
+        assert_eq!(original_dst(&stream, other, true).unwrap(), addr);
+        // without a REDIRECT rule there is nothing to relay to
+        assert!(original_dst(&stream, other, false).is_err());
+
+        // TPROXY rules catching connections to the proxy itself
+        assert!(original_dst(&stream, addr, true).is_err());
+        let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, addr.port());
+        assert!(original_dst(&stream, any, true).is_err());
+    }
+}
@@ -1,141 +1,583 @@
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::path::Path;
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
 use encryption::Key;
-use quinn::Connection;
+use encryption::resume::{
+    CMD_CONNECT, CMD_RESUME, CMD_SESSION, MAX_STREAM_WINDOW, ReplayBuffer, SESSION_JOIN, SessionId,
+};
+use encryption::transport::transport_config;
 use quinn::crypto::rustls::QuicClientConfig;
-use rustls::crypto::CryptoProvider;
-use rustls::crypto::ring;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::mpsc::UnboundedSender;
+use quinn::{Connecting, Connection, Endpoint, EndpointConfig, RecvStream, SendStream};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Notify, oneshot, watch};
 
-use crate::tunnel::{FlowKey, Response};
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::tunnel::{ConnectFailed, FlowKey, Response};
 
 mod insecure_verifier;
+mod pool;
+mod session;
+mod trust;
 
+pub(crate) use pool::Pool;
+use session::Link;
+pub(crate) use session::{Bond, Session, Status};
+
+/// Flows to one server, over one path or the paths of a bond.
 pub(crate) struct TcpUpstream {
-    connection: Connection,
+    paths: Vec<Arc<Session>>,
+    metrics: Arc<Metrics>,
 }
 
 impl TcpUpstream {
-    pub(crate) async fn new(vpn_addr: SocketAddr, aead_key: &Key) -> Self {
-        CryptoProvider::install_default(ring::default_provider())
-            .expect("failed to install default crypto provider");
+    pub(crate) fn new(paths: Vec<Arc<Session>>, metrics: Arc<Metrics>) -> Self {
+        Self { paths, metrics }
+    }
 
-        let crypto = rustls::ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(insecure_verifier::SkipServerVerification::new())
-            .with_no_client_auth();
-        let config =
-            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()));
+    /// RTT of the fastest path that is up.
+    fn rtt(&self) -> Option<Duration> {
+        self.paths.iter().filter_map(|path| path.rtt()).min()
+    }
 
-        let mut cl = quinn::Endpoint::client((std::net::Ipv4Addr::UNSPECIFIED, 0).into()).unwrap();
-        cl.set_default_client_config(config);
-        let connection = cl.connect(vpn_addr, "vpn").unwrap().await.unwrap();
+    /// A path for a new flow, weighted by RTT and loss. The first one if
+    /// none is up.
+    fn pick(&self) -> &Arc<Session> {
+        let quality: Vec<_> = self
+            .paths
+            .iter()
+            .map(|path| path.rtt().zip(path.loss()))
+            .collect();
+        let index = pool::weighted(&quality, rand::random());
+        &self.paths[index.unwrap_or(0)]
+    }
+}
 
-        auth_with_password(&connection, aead_key).await.unwrap();
+pub(crate) fn load_key(path: &Path) -> Result<Key> {
+    let key = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let key = <[u8; 32]>::try_from(key.as_slice())
+        .map_err(|_| anyhow!("{} is not a 32 byte key", path.display()))?;
+    Ok(Key::from(key))
+}
+
+/// A client endpoint on an ephemeral port of all local addresses, sending
+/// only through `interface` if one is given.
+pub(crate) fn bind(interface: Option<&str>) -> Result<Endpoint> {
+    let runtime = quinn::default_runtime().ok_or_else(|| anyhow!("no async runtime"))?;
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        socket(interface)?,
+        runtime,
+    )?)
+}
 
-        Self { connection }
+/// The UDP socket of an endpoint, bound to `interface` with
+/// `SO_BINDTODEVICE` so the kernel routes it out there whatever the
+/// routing table says.
+fn socket(interface: Option<&str>) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    if let Some(interface) = interface {
+        let bound = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                interface.as_ptr().cast(),
+                interface.len() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("binding to interface {}", interface));
+        }
     }
+    Ok(socket)
+}
+
+/// QUIC and TLS settings for connecting to the server of `config`,
+/// presenting the configured client certificate if `client_cert` is set.
+///
+/// TLS sessions are only resumed with the settings they were set up with, so
+/// reuse the result for as long as `config` is used.
+pub(crate) fn client_config(config: &Config, client_cert: bool) -> Result<quinn::ClientConfig> {
+    let crypto = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(trust::server_verifier(&config.trust)?);
+    let mut crypto = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) if client_cert => {
+            let certs = CertificateDer::pem_file_iter(cert)
+                .with_context(|| format!("reading {}", cert.display()))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .with_context(|| format!("reading {}", key.display()))?;
+            crypto.with_client_auth_cert(certs, key)?
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            return Err(anyhow!("client cert and key must be configured together"));
+        }
+        _ => crypto.with_no_client_auth(),
+    };
+    crypto.enable_early_data = true;
+    let mut quic_config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    let mut transport_config = transport_config(&config.transport)?;
+    // the health check: PINGs keep the RTT fresh, and a server that stops
+    // answering them is noticed within the timeout
+    transport_config.keep_alive_interval(Some(config.upstreams.health_interval));
+    transport_config.max_idle_timeout(Some(config.upstreams.health_timeout.try_into()?));
+    quic_config.transport_config(Arc::new(transport_config));
+    Ok(quic_config)
+}
+
+/// Starts the QUIC handshake with the server.
+pub(crate) fn connect(
+    endpoint: &Endpoint,
+    config: &Config,
+    client_config: &quinn::ClientConfig,
+) -> Result<Connecting> {
+    Ok(endpoint.connect_with(client_config.clone(), config.server, &config.server_name)?)
+}
+
+/// How far a flow got in both directions, which is where it continues
+/// when it is resumed on another connection.
+struct FlowState {
+    /// Kernel data written to the stream.
+    sent: ReplayBuffer,
+    /// Bytes read from the stream.
+    received: u64,
+    /// The kernel closed its side, the stream is finished after any replay.
+    finished: bool,
 }
 
 impl crate::tunnel::VPNUpstream for TcpUpstream {
     fn new_connection(
         &mut self,
         key: FlowKey,
-        mut rx: UnboundedReceiver<Vec<u8>>,
-        tx: UnboundedSender<Response>,
-    ) -> Result<Arc<tokio::sync::Notify>> {
-        let notify = Arc::new(tokio::sync::Notify::new());
+        host: Option<String>,
+        mut rx: Receiver<Vec<u8>>,
+        tx: Sender<Response>,
+        opened: Option<oneshot::Sender<Result<()>>>,
+    ) -> Result<Arc<Notify>> {
+        let notify = Arc::new(Notify::new());
         let ntf = notify.clone();
-        let conn = self.connection.clone();
+        let session = self.pick().clone();
+        let mut links: Vec<_> = self.paths.iter().map(|path| path.link()).collect();
+        let metrics = self.metrics.clone();
         tokio::spawn(async move {
             log::debug!("trying to connect to {:?}", key);
-            let Ok((mut sender, mut receiver)) = conn.open_bi().await else {
-                return;
+            let started = Instant::now();
+            let current = session.link().borrow().clone();
+            let opening = match current {
+                Link::Up {
+                    connection, window, ..
+                } => {
+                    let id = window.map(|window| (session.next_flow_id(), window));
+                    open_stream(&connection, key, host.as_deref(), id.map(|(id, _)| id))
+                        .await
+                        .map(|streams| (connection, streams, id))
+                }
+                _ => Err(anyhow!("not connected")),
             };
-
-            // |version, command (connect tcp stream), reserved, dst addr: |type (ipv4), addr|, dst port|
-            let ip_octets = key.0.octets();
-            let port = key.1;
-            let mut req = Vec::with_capacity(10);
-            req.push(0x05); // version
-            req.push(0x01); // connect
-            req.push(0x00); // reserved
-            req.push(0x01); // IPv4
-            req.extend_from_slice(&ip_octets);
-            req.push((port >> 8) as u8);
-            req.push((port & 0xff) as u8);
-            if let Err(err) = sender.write_all(&req).await {
-                log::warn!("error opening new stream (to vpn): {}", err);
-                return;
-            }
-
-            // |version, status, reserved, bound address: |type (ipv4), addr|, bound port|
-            let mut buf = [0u8; 10];
-            if let Err(err) = receiver.read_exact(&mut buf).await {
-                log::warn!("error opening new stream (from vpn): {}", err);
-                return;
+            let (mut conn, mut streams, id) = match opening {
+                Ok(opening) => opening,
+                Err(err) => {
+                    log::warn!("opening stream to {:?}: {:#}", key, err);
+                    metrics.stream_failed();
+                    let _ = tx.send(Response::reset(key)).await;
+                    if let Some(opened) = opened {
+                        let _ = opened.send(Err(err));
+                    }
+                    return;
+                }
             };
-            if buf[0] != 0x05 {
-                log::warn!("invalid SOCKS5 version in connect reply");
-                return;
+            metrics.stream_opened(started.elapsed());
+            if let Some(opened) = opened {
+                let _ = opened.send(Ok(()));
             }
-            if buf[1] != 0x00 {
-                log::warn!("SOCKS5 connect failed, status {}", buf[1]);
-                return;
-            }
-
-            let mut buf = [0u8; 4096];
 
+            let mut state = FlowState {
+                sent: ReplayBuffer::new(id.map_or(0, |(_, window)| window)),
+                received: 0,
+                finished: false,
+            };
+            let id = id.map(|(id, _)| id);
             loop {
-                tokio::select! {
-                    _ = ntf.notified() => {
-                        log::info!("closing stream");
-                        return
-                    },
-                    Some(payload) = rx.recv() => {
-                        log::debug!("writing data to socket: {}", payload.len());
-                        sender.write_all(&payload).await.expect("writing payload");
-                    },
-                    n = receiver.read(&mut buf) => {
-                        match n {
-                            Ok(Some(n)) => {
-                                log::debug!("sending data to kernel: {}", n);
-                                if tx.send(Response{ // receiver
-                                    payload: buf[..n].to_vec(),
-                                    flow_key: key,
-                                }).is_err() {
-                                    return;
-                                }
-                            },
-                            Ok(None) => {
-                                log::debug!("received none bytes");
-                                break;
-                            },
-                            Err(err) => {
-                                log::warn!("reading from VPN socket: {}", err);
-                                // deal with errors, retry/reconnect;
-                                break;
-                            },
-                        };
-                    }
+                let Err(err) = relay(key, streams, &mut rx, &tx, &ntf, &mut state).await else {
+                    return;
+                };
+                // only flows that broke with the connection are kept by the server
+                let Some(id) = id.filter(|_| conn.close_reason().is_some()) else {
+                    log::warn!("stream to {:?}: {:#}", key, err);
+                    let _ = tx.send(Response::reset(key)).await;
+                    return;
                 };
+                log::debug!("stream to {:?} lost, waiting to resume: {:#}", key, err);
+                match resume(&mut links, &conn, id, &mut state, &ntf).await {
+                    Ok(Some((next, next_streams))) => {
+                        log::debug!("resumed stream to {:?}", key);
+                        (conn, streams) = (next, next_streams);
+                    }
+                    Ok(None) => return,
+                    Err(err) => {
+                        log::warn!("resuming stream to {:?}: {:#}", key, err);
+                        let _ = tx.send(Response::reset(key)).await;
+                        return;
+                    }
+                }
             }
         });
         Ok(notify)
     }
 }
 
-async fn auth_with_password(connection: &Connection, _aead_key: &Key) -> Result<()> {
-    let username = b"testuser";
-    let password = b"testpass";
+/// Opens a stream and sends the SOCKS5 CONNECT for `key`, or for `host`, on
+/// it, with the flow id if the session is resumable.
+async fn open_stream(
+    conn: &Connection,
+    key: FlowKey,
+    host: Option<&str>,
+    id: Option<u32>,
+) -> Result<(SendStream, RecvStream)> {
+    // |version, command (connect tcp stream), reserved, dst addr: |type, addr|, dst port|
+    let port = key.1;
+    let mut req = Vec::with_capacity(14);
+    req.push(0x05); // version
+    req.push(if id.is_some() { CMD_CONNECT } else { 0x01 }); // connect
+    req.push(0x00); // reserved
+    match host.map(|host| (host, host.parse::<IpAddr>())) {
+        None => {
+            req.push(0x01);
+            req.extend_from_slice(&key.0.octets());
+        }
+        Some((_, Ok(IpAddr::V4(ip)))) => {
+            req.push(0x01);
+            req.extend_from_slice(&ip.octets());
+        }
+        Some((_, Ok(IpAddr::V6(ip)))) => {
+            req.push(0x04);
+            req.extend_from_slice(&ip.octets());
+        }
+        // resolved by the server
+        Some((name, Err(_))) => {
+            let len =
+                u8::try_from(name.len()).map_err(|_| anyhow!("host name {} is too long", name))?;
+            req.push(0x03);
+            req.push(len);
+            req.extend_from_slice(name.as_bytes());
+        }
+    }
+    req.push((port >> 8) as u8);
+    req.push((port & 0xff) as u8);
+    if let Some(id) = id {
+        req.extend_from_slice(&id.to_be_bytes());
+    }
+    let (mut sender, mut receiver) = conn.open_bi().await?;
+    sender
+        .write_all(&req)
+        .await
+        .context("sending CONNECT request")?;
+
+    // |version, status, reserved, bound address: |type (ipv4), addr|, bound port|
+    let mut buf = [0u8; 10];
+    receiver
+        .read_exact(&mut buf)
+        .await
+        .context("reading CONNECT reply")?;
+    if buf[0] != 0x05 {
+        return Err(anyhow!("invalid SOCKS5 version in connect reply"));
+    }
+    if buf[1] != 0x00 {
+        return Err(ConnectFailed(buf[1]).into());
+    }
+    Ok((sender, receiver))
+}
+
+/// Moves data between the kernel side of a flow and its stream, each way
+/// until it ends: the kernel closing its side finishes the stream, and the
+/// server finishing it is passed on as `Response::end`. An error means the
+/// stream broke, e.g. because the connection was lost.
+async fn relay(
+    key: FlowKey,
+    (mut sender, mut receiver): (SendStream, RecvStream),
+    rx: &mut Receiver<Vec<u8>>,
+    tx: &Sender<Response>,
+    notify: &Notify,
+    state: &mut FlowState,
+) -> Result<()> {
+    let FlowState {
+        sent,
+        received,
+        finished,
+    } = state;
+    let up = async {
+        while !*finished {
+            match rx.recv().await {
+                Some(payload) => {
+                    log::debug!("writing data to socket: {}", payload.len());
+                    // kept before writing, the server may not get it
+                    sent.push(&payload);
+                    sender
+                        .write_all(&payload)
+                        .await
+                        .context("writing payload")?;
+                }
+                None => *finished = true,
+            }
+        }
+        sender.finish().context("finishing stream")
+    };
+    let down = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            let Some(n) = receiver
+                .read(&mut buf)
+                .await
+                .context("reading from VPN socket")?
+            else {
+                log::debug!("stream finished by the server");
+                let _ = tx.send(Response::end(key)).await;
+                return Ok(());
+            };
+            log::debug!("sending data to kernel: {}", n);
+            *received += n as u64;
+            let response = Response {
+                payload: buf[..n].to_vec(),
+                flow_key: key,
+                reset: false,
+            };
+            if tx.send(response).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = notify.notified() => {
+            log::info!("closing stream");
+            Ok(())
+        },
+        relayed = async { tokio::try_join!(up, down) } => relayed.map(|_| ()),
+    }
+}
+
+/// Waits for a connection other than `lost` on any of the paths in `links`
+/// and, if it resumed the session, continues flow `id` on it: the server
+/// replies with how much of our data reached the target and replays what we
+/// missed, then we send the rest of ours again. `None` if the kernel closed
+/// the flow meanwhile.
+///
+/// Another path may turn out to be lost as well, then the next one is tried.
+async fn resume(
+    links: &mut [watch::Receiver<Link>],
+    lost: &Connection,
+    id: u32,
+    state: &mut FlowState,
+    notify: &Notify,
+) -> Result<Option<(Connection, (SendStream, RecvStream))>> {
+    let mut lost = vec![lost.stable_id()];
+    loop {
+        let next = tokio::select! {
+            next = next_link(links, &lost) => next?,
+            _ = notify.notified() => return Ok(None),
+        };
+        let Link::Up {
+            connection,
+            resumed: true,
+            ..
+        } = next
+        else {
+            return Err(anyhow!("session was not resumed"));
+        };
+        match resume_on(&connection, id, state).await {
+            Err(_) if connection.close_reason().is_some() => lost.push(connection.stable_id()),
+            resumed => return resumed.map(|streams| Some((connection, streams))),
+        }
+    }
+}
+
+/// The first link that is up on a connection not in `lost`, or `Off` once
+/// every path is off.
+async fn next_link(links: &mut [watch::Receiver<Link>], lost: &[usize]) -> Result<Link> {
+    loop {
+        let mut off = true;
+        for link in links.iter_mut() {
+            let current = link.borrow_and_update().clone();
+            match &current {
+                Link::Up { connection, .. } if !lost.contains(&connection.stable_id()) => {
+                    return Ok(current);
+                }
+                Link::Off => {}
+                _ => off = false,
+            }
+        }
+        if off {
+            return Ok(Link::Off);
+        }
+        // whichever path changes first
+        let mut changes: Vec<_> = links
+            .iter_mut()
+            .map(|link| Box::pin(link.changed()))
+            .collect();
+        std::future::poll_fn(|cx| {
+            changes
+                .iter_mut()
+                .find_map(|change| match change.as_mut().poll(cx) {
+                    Poll::Ready(changed) => Some(Poll::Ready(changed)),
+                    Poll::Pending => None,
+                })
+                .unwrap_or(Poll::Pending)
+        })
+        .await?;
+    }
+}
+
+/// Sends RESUME for flow `id` on a new stream of `connection`.
+async fn resume_on(
+    connection: &Connection,
+    id: u32,
+    state: &mut FlowState,
+) -> Result<(SendStream, RecvStream)> {
+    let (mut sender, mut receiver) = connection.open_bi().await?;
+    // |version, command, reserved, reserved, flow id, received|
+    let mut req = vec![0x05, CMD_RESUME, 0x00, 0x00];
+    req.extend_from_slice(&id.to_be_bytes());
+    req.extend_from_slice(&state.received.to_be_bytes());
+    sender
+        .write_all(&req)
+        .await
+        .context("sending RESUME request")?;
+
+    // |version, status, reserved, received|
+    let mut buf = [0u8; 11];
+    receiver
+        .read_exact(&mut buf)
+        .await
+        .context("reading RESUME reply")?;
+    if buf[0] != 0x05 || buf[1] != 0x00 {
+        return Err(anyhow!("RESUME failed, status {}", buf[1]));
+    }
+    let delivered = u64::from_be_bytes(buf[3..].try_into().unwrap());
+    let missed = state
+        .sent
+        .since(delivered)
+        .ok_or_else(|| anyhow!("cannot replay from offset {}", delivered))?;
+    sender
+        .write_all(&missed)
+        .await
+        .context("replaying payload")?;
+    Ok((sender, receiver))
+}
+
+/// The server side of a session as the SESSION reply describes it.
+struct ServerSession {
+    id: SessionId,
+    resumed: bool,
+    /// The server's stream receive window.
+    window: u32,
+}
+
+/// Asks the server to resume session `id`, or for a new session, or to join
+/// session `id` from another path.
+async fn open_session(
+    connection: &Connection,
+    config: &Config,
+    id: Option<SessionId>,
+    join: bool,
+) -> Result<ServerSession> {
+    let (mut sender, mut reader) = connection.open_bi().await?;
+
+    // |version, command, flags, reserved, session id, stream window|
+    let flags = if join { SESSION_JOIN } else { 0x00 };
+    let mut req = vec![0x05, CMD_SESSION, flags, 0x00];
+    req.extend_from_slice(&id.unwrap_or_default());
+    req.extend_from_slice(&config.transport.stream_receive_window.to_be_bytes());
+    sender.write_all(&req).await?;
+    sender.finish()?;
+
+    // |version, status, resumed, session id, stream window|
+    let mut buf = [0u8; 23];
+    reader
+        .read_exact(&mut buf)
+        .await
+        .context("reading SESSION reply")?;
+    if buf[0] != 0x05 || buf[1] != 0x00 {
+        return Err(anyhow!("SESSION failed, status {}", buf[1]));
+    }
+    let session = ServerSession {
+        id: buf[3..19].try_into().unwrap(),
+        resumed: buf[2] == 1,
+        window: u32::from_be_bytes(buf[19..].try_into().unwrap()),
+    };
+    if join && Some(session.id) != id {
+        return Err(anyhow!("server does not support joining sessions"));
+    }
+    if session.window > MAX_STREAM_WINDOW {
+        return Err(anyhow!(
+            "server stream window {} is too large",
+            session.window
+        ));
+    }
+    Ok(session)
+}
+
+/// Completes a connection started by `connect`: authenticates and opens a
+/// session on it at the same time. With a resumed TLS session both go out as
+/// 0-RTT early data and are answered in the first round trip. If the server
+/// rejects the early data, they are sent again once the handshake is done.
+///
+/// An error from the session request only means it is not resumable.
+async fn establish(
+    connecting: Connecting,
+    config: &Config,
+    aead_key: &Key,
+    session_id: Option<SessionId>,
+    join: bool,
+) -> Result<(Connection, Result<ServerSession>)> {
+    let setup = |connection: Connection| async move {
+        let (auth, session) = tokio::join!(
+            authenticate(&connection, config, aead_key),
+            open_session(&connection, config, session_id, join)
+        );
+        auth.map(|()| (connection, session))
+    };
+    match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+            let early = setup(connection.clone()).await;
+            if accepted.await {
+                return early;
+            }
+            log::debug!("server rejected early data");
+            setup(connection).await
+        }
+        Err(connecting) => setup(connecting.await?).await,
+    }
+}
 
+async fn authenticate(connection: &Connection, config: &Config, _aead_key: &Key) -> Result<()> {
     let (mut sender, mut reader) = connection.open_bi().await?;
 
-    // |version, nmethods, method|
-    sender.write_all(&[0x05, 1, 2]).await?;
+    // a client certificate lets the server skip username/password
+    let mut methods = vec![];
+    if config.cert.is_some() {
+        methods.push(0x00);
+    }
+    if config.username.is_some() {
+        methods.push(0x02);
+    }
+
+    // |version, nmethods, methods|
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(&methods);
+    sender.write_all(&greeting).await?;
 
     // |version, method|
     let mut buf = [0u8; 2];
@@ -144,9 +586,24 @@ async fn auth_with_password(connection: &Connection, _aead_key: &Key) -> Result<
     if buf[0] != 0x05 {
         return Err(anyhow!("invalid SOCKS5 version in method reply"));
     }
-    if buf[1] != 2 {
-        return Err(anyhow!("server does not support username/password auth"));
+    match buf[1] {
+        0x00 => Ok(()),
+        0x02 => {
+            let username = config.username.as_deref().unwrap_or_default();
+            let password = config.password.as_deref().unwrap_or_default();
+            auth_with_password(&mut sender, &mut reader, username, password).await
+        }
+        _ => Err(anyhow!("server accepts none of the offered auth methods")),
     }
+}
+
+async fn auth_with_password(
+    sender: &mut quinn::SendStream,
+    reader: &mut quinn::RecvStream,
+    username: &str,
+    password: &str,
+) -> Result<()> {
+    let (username, password) = (username.as_bytes(), password.as_bytes());
 
     // |version, id_len, id, password_len, password|
     let mut auth_msg = Vec::with_capacity(3 + username.len() + password.len());
@@ -157,6 +614,7 @@ async fn auth_with_password(connection: &Connection, _aead_key: &Key) -> Result<
     auth_msg.extend_from_slice(password);
     sender.write_all(&auth_msg).await?;
 
+    let mut buf = [0u8; 2];
     reader.read_exact(&mut buf).await?;
     if buf[0] != 0x01 {
         return Err(anyhow!("invalid auth version in reply"));
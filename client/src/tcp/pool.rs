@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{Notify, oneshot};
+
+use super::{Session, TcpUpstream};
+use crate::config::Strategy;
+use crate::metrics::Metrics;
+use crate::tunnel::{FlowKey, Response, VPNUpstream};
+
+/// Destinations remembered by the sticky strategy before starting over.
+const STICKY_LIMIT: usize = 4096;
+
+/// Spreads new flows over several servers, each with its own session.
+///
+/// A server counts as healthy while one of its paths has a live connection; QUIC
+/// keep-alives close the connection of a server that stops answering, so new
+/// flows move on without waiting for one of them to fail. Flows stay with the
+/// server they were opened on.
+pub(crate) struct Pool {
+    members: Vec<TcpUpstream>,
+    picker: Picker,
+}
+
+impl Pool {
+    /// The paths of each server, in priority order. The first server is the
+    /// fallback when none of them is connected.
+    pub(crate) fn new(
+        servers: Vec<Vec<Arc<Session>>>,
+        strategy: Strategy,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            members: servers
+                .into_iter()
+                .map(|paths| TcpUpstream::new(paths, metrics.clone()))
+                .collect(),
+            picker: Picker::new(strategy),
+        }
+    }
+}
+
+impl VPNUpstream for Pool {
+    fn new_connection(
+        &mut self,
+        key: FlowKey,
+        host: Option<String>,
+        rx: Receiver<Vec<u8>>,
+        tx: Sender<Response>,
+        opened: Option<oneshot::Sender<Result<()>>>,
+    ) -> Result<Arc<Notify>> {
+        let rtts: Vec<_> = self.members.iter().map(|m| m.rtt()).collect();
+        let index = self.picker.pick(key.0, &rtts);
+        self.members[index].new_connection(key, host, rx, tx, opened)
+    }
+}
+
+struct Picker {
+    strategy: Strategy,
+    /// Where round robin continues.
+    next: usize,
+    sticky: HashMap<Ipv4Addr, usize>,
+}
+
+impl Picker {
+    fn new(strategy: Strategy) -> Self {
+        Self {
+            strategy,
+            next: 0,
+            sticky: HashMap::new(),
+        }
+    }
+
+    /// Index of the member for a flow to `dest`, given each member's RTT or
+    /// `None` for members that are down.
+    fn pick(&mut self, dest: Ipv4Addr, rtts: &[Option<Duration>]) -> usize {
+        let healthy = |i: usize| rtts[i].is_some();
+        let first = || (0..rtts.len()).find(|&i| healthy(i));
+        let picked = match self.strategy {
+            Strategy::Failover => first(),
+            Strategy::LowestRtt => (0..rtts.len())
+                .filter(|&i| healthy(i))
+                .min_by_key(|&i| rtts[i]),
+            Strategy::RoundRobin => {
+                let picked = (0..rtts.len())
+                    .map(|i| (self.next + i) % rtts.len())
+                    .find(|&i| healthy(i));
+                if let Some(i) = picked {
+                    self.next = i + 1;
+                }
+                picked
+            }
+            Strategy::Sticky => match self.sticky.get(&dest) {
+                Some(&i) if healthy(i) => Some(i),
+                _ => {
+                    // hash so destinations spread instead of all going to the first
+                    let start = u32::from(dest) as usize % rtts.len();
+                    let picked = (0..rtts.len())
+                        .map(|i| (start + i) % rtts.len())
+                        .find(|&i| healthy(i));
+                    if let Some(i) = picked {
+                        if self.sticky.len() >= STICKY_LIMIT {
+                            self.sticky.clear();
+                        }
+                        self.sticky.insert(dest, i);
+                    }
+                    picked
+                }
+            },
+        };
+        picked.unwrap_or(0)
+    }
+}
+
+/// How much worse a path looks per share of lost packets: 5% loss weighs
+/// like doubling the RTT.
+const LOSS_PENALTY: f64 = 20.0;
+
+/// Picks from the paths that are up, given as RTT and loss, with odds
+/// inversely proportional to their RTT inflated by their loss. `roll` is
+/// uniform in `[0, 1)`.
+pub(super) fn weighted(paths: &[Option<(Duration, f64)>], roll: f64) -> Option<usize> {
+    let weights: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            path.map_or(0.0, |(rtt, loss)| {
+                1.0 / (rtt.as_secs_f64().max(0.001) * (1.0 + LOSS_PENALTY * loss))
+            })
+        })
+        .collect();
+    let mut left = roll * weights.iter().sum::<f64>();
+    for (i, weight) in weights.iter().enumerate() {
+        if *weight > 0.0 && left < *weight {
+            return Some(i);
+        }
+        left -= weight;
+    }
+    // rounding, or nothing is up
+    weights.iter().rposition(|weight| *weight > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn test_pick() {
+        let dest = Ipv4Addr::new(10, 0, 0, 1);
+        let up = [ms(30), ms(10), ms(20)];
+        let first_down = [None, ms(10), ms(20)];
+
+        let mut failover = Picker::new(Strategy::Failover);
+        assert_eq!(failover.pick(dest, &up), 0);
+        assert_eq!(failover.pick(dest, &first_down), 1);
+        assert_eq!(failover.pick(dest, &[None, None, None]), 0);
+
+        let mut lowest = Picker::new(Strategy::LowestRtt);
+        assert_eq!(lowest.pick(dest, &up), 1);
+        assert_eq!(lowest.pick(dest, &[ms(30), None, ms(20)]), 2);
+
+        let mut round_robin = Picker::new(Strategy::RoundRobin);
+        let picks: Vec<_> = (0..4).map(|_| round_robin.pick(dest, &up)).collect();
+        assert_eq!(picks, [0, 1, 2, 0]);
+        assert_eq!(round_robin.pick(dest, &[ms(30), None, ms(20)]), 2);
+
+        let mut sticky = Picker::new(Strategy::Sticky);
+        let picked = sticky.pick(dest, &up);
+        assert_eq!(sticky.pick(dest, &up), picked);
+        let mut down = up;
+        down[picked] = None;
+        let moved = sticky.pick(dest, &down);
+        assert_ne!(moved, picked);
+        assert_eq!(sticky.pick(dest, &up), moved);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let lte = Some((Duration::from_millis(50), 0.0));
+        let satellite = Some((Duration::from_millis(600), 0.0));
+        let lossy = Some((Duration::from_millis(50), 0.55));
+        let share = |paths: &[Option<(Duration, f64)>], path| {
+            let picks = (0..1000).filter(|i| weighted(paths, *i as f64 / 1000.0) == Some(path));
+            picks.count() as f64 / 1000.0
+        };
+
+        assert!((share(&[lte, satellite], 0) - 12.0 / 13.0).abs() < 0.01);
+        assert!((share(&[lte, lossy], 1) - 1.0 / 13.0).abs() < 0.01);
+        assert_eq!(share(&[None, satellite], 1), 1.0);
+        assert_eq!(weighted(&[None, None], 0.5), None);
+    }
+}
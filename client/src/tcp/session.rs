@@ -0,0 +1,871 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use encryption::Key;
+use encryption::resume::SessionId;
+use quinn::{Connection, Endpoint, VarInt};
+use serde::Serialize;
+use tokio::sync::{Notify, watch};
+
+use crate::config::{Config, ReconnectConfig};
+use crate::metrics::Metrics;
+
+use super::{bind, client_config, connect, establish, socket};
+
+/// Application error code the connection is closed with on a local disconnect.
+const DISCONNECTED: VarInt = VarInt::from_u32(0);
+
+/// Application error codes the server closes with when the client should
+/// stay away: quota exceeded, kicked, and its session ended on another path.
+const CLOSED_FOR_GOOD: [VarInt; 3] = [
+    VarInt::from_u32(0x51),
+    VarInt::from_u32(0x52),
+    VarInt::from_u32(0x55),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LinkState {
+    Connected,
+    /// Not connected, the supervisor is trying to.
+    Reconnecting,
+    /// Disconnected on request, nothing is retried.
+    Disconnected,
+    /// The server closed the connection for good, e.g. kicked it. Nothing
+    /// is retried until `connect`.
+    Closed,
+}
+
+/// The connection as flows see it.
+#[derive(Clone)]
+pub(crate) enum Link {
+    Up {
+        connection: Connection,
+        /// The server's stream receive window if it keeps flows of this
+        /// connection when it is lost.
+        window: Option<u32>,
+        /// Whether the flows of the previous connection, or of the other paths
+        /// of its bond, can be resumed on it.
+        resumed: bool,
+    },
+    /// Lost, the supervisor is reconnecting.
+    Down,
+    /// Disconnected on request.
+    Off,
+}
+
+/// What `client-ctl status` prints.
+#[derive(Debug, Serialize)]
+pub(crate) struct Status {
+    /// `None` for the config the client was started with.
+    pub profile: Option<String>,
+    pub server: String,
+    pub server_name: String,
+    /// The uplink of a multipath connection.
+    pub interface: Option<String>,
+    pub state: LinkState,
+    pub rtt_ms: Option<f64>,
+    pub loss_percent: Option<f64>,
+    pub uptime_secs: Option<u64>,
+    /// Connections re-established after a loss.
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+}
+
+struct Current {
+    config: Arc<Config>,
+    aead_key: Key,
+    profile: Option<String>,
+    connection: Option<(Connection, Instant)>,
+    /// Whether the supervisor should keep a connection up.
+    wanted: bool,
+    /// Set when `wanted` was cleared by the server rather than a disconnect.
+    closed_by_server: bool,
+    /// Counts connect, switch and disconnect requests. A connection attempt
+    /// that finishes after a later request is dropped instead of undoing it.
+    generation: u64,
+    reconnects: u64,
+    last_error: Option<String>,
+    /// Server session of the current config, resumed on reconnects.
+    session_id: Option<SessionId>,
+    /// Shared by all connections, so they move along when it is rebound.
+    endpoint: Option<Endpoint>,
+    /// Built from `config` on first use. Keeping it keeps the TLS sessions
+    /// reconnects resume to send early data.
+    client_config: Option<quinn::ClientConfig>,
+}
+
+/// The server session that the paths of a multipath connection all join,
+/// so that flows can be resumed on any of them.
+pub(crate) struct Bond {
+    /// Picked here rather than by the server, so paths that connect at the
+    /// same time agree on it.
+    id: SessionId,
+    next_flow: AtomicU32,
+}
+
+impl Bond {
+    pub(crate) fn new() -> Self {
+        Self {
+            id: rand::random(),
+            next_flow: AtomicU32::new(0),
+        }
+    }
+}
+
+/// The authenticated QUIC connection to the server, which can be replaced
+/// while the tunnel keeps running.
+pub(crate) struct Session {
+    current: Mutex<Current>,
+    /// Wakes the supervisor when `wanted` changes.
+    changed: Notify,
+    link: watch::Sender<Link>,
+    next_flow: AtomicU32,
+    metrics: Arc<Metrics>,
+    /// Sends only through this interface, as one path of `bond`.
+    interface: Option<String>,
+    bond: Option<Arc<Bond>>,
+}
+
+impl Session {
+    pub(crate) fn new(config: Arc<Config>, aead_key: Key, metrics: Arc<Metrics>) -> Self {
+        Self {
+            current: Mutex::new(Current {
+                config,
+                aead_key,
+                profile: None,
+                connection: None,
+                wanted: true,
+                closed_by_server: false,
+                generation: 0,
+                reconnects: 0,
+                last_error: None,
+                session_id: None,
+                endpoint: None,
+                client_config: None,
+            }),
+            changed: Notify::new(),
+            link: watch::Sender::new(Link::Down),
+            next_flow: AtomicU32::new(0),
+            metrics,
+            interface: None,
+            bond: None,
+        }
+    }
+
+    /// Makes this the path over `interface` of a multipath connection.
+    pub(crate) fn on_path(mut self, interface: String, bond: Arc<Bond>) -> Self {
+        self.interface = Some(interface);
+        self.bond = Some(bond);
+        self
+    }
+
+    pub(crate) fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    pub(crate) fn connection(&self) -> Option<Connection> {
+        let current = self.current.lock().unwrap();
+        current.connection.as_ref().map(|(c, _)| c.clone())
+    }
+
+    /// Follows the connection as it is replaced.
+    pub(crate) fn link(&self) -> watch::Receiver<Link> {
+        self.link.subscribe()
+    }
+
+    /// Id for a new flow of a resumable session.
+    pub(crate) fn next_flow_id(&self) -> u32 {
+        let next_flow = match &self.bond {
+            Some(bond) => &bond.next_flow,
+            None => &self.next_flow,
+        };
+        next_flow.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// (Re)connects with the current config. The old connection, if any, is
+    /// only closed once the new one is authenticated.
+    pub(crate) async fn connect(&self) -> Result<()> {
+        self.reconnect(true).await
+    }
+
+    /// Connects with the current config, as the latest request if `request`
+    /// and otherwise on behalf of the one that is.
+    async fn reconnect(&self, request: bool) -> Result<()> {
+        let (config, aead_key, profile, generation) = {
+            let mut current = self.current.lock().unwrap();
+            if request {
+                current.generation += 1;
+            }
+            (
+                current.config.clone(),
+                current.aead_key,
+                current.profile.clone(),
+                current.generation,
+            )
+        };
+        self.replace(config, aead_key, profile, generation).await
+    }
+
+    /// Connects with another config and keeps it if that works.
+    pub(crate) async fn switch(
+        &self,
+        config: Arc<Config>,
+        aead_key: Key,
+        profile: Option<String>,
+    ) -> Result<()> {
+        let generation = {
+            let mut current = self.current.lock().unwrap();
+            current.generation += 1;
+            current.generation
+        };
+        self.replace(config, aead_key, profile, generation).await
+    }
+
+    /// Connects with `config` and makes it the current connection, unless
+    /// another request came after `generation`.
+    async fn replace(
+        &self,
+        config: Arc<Config>,
+        aead_key: Key,
+        profile: Option<String>,
+        generation: u64,
+    ) -> Result<()> {
+        // only sessions of the same config can be resumed
+        let (session_id, quic_config) = {
+            let current = self.current.lock().unwrap();
+            let same = Arc::ptr_eq(&current.config, &config);
+            (
+                match &self.bond {
+                    Some(bond) => Some(bond.id),
+                    None => current.session_id.filter(|_| same),
+                },
+                current.client_config.clone().filter(|_| same),
+            )
+        };
+        let join = self.bond.is_some();
+        let established = async {
+            let quic_config = match quic_config {
+                Some(quic_config) => quic_config,
+                None => client_config(&config, true)?,
+            };
+            let connecting = connect(&self.endpoint()?, &config, &quic_config)?;
+            let (connection, session) =
+                establish(connecting, &config, &aead_key, session_id, join).await?;
+            Ok::<_, anyhow::Error>((connection, session, quic_config))
+        };
+        let (connection, session, quic_config) = match established.await {
+            Ok(established) => established,
+            Err(e) => return Err(self.failed(e)),
+        };
+        let session = match session {
+            Ok(session) => Some(session),
+            Err(e) => {
+                log::warn!("flows will not survive reconnects: {:#}", e);
+                None
+            }
+        };
+
+        let mut current = self.current.lock().unwrap();
+        if current.generation != generation {
+            connection.close(DISCONNECTED, b"superseded");
+            return Err(anyhow!("superseded by a later connect or disconnect"));
+        }
+        let previous = current.config.server;
+        self.link.send_replace(Link::Up {
+            connection: connection.clone(),
+            window: session.as_ref().map(|session| session.window),
+            // a path that joined the bond takes over flows of the others,
+            // whether it started the server session or found it
+            resumed: session
+                .as_ref()
+                .is_some_and(|session| session.resumed || join),
+        });
+        let old = current
+            .connection
+            .replace((connection.clone(), Instant::now()));
+        current.session_id = session.as_ref().map(|session| session.id);
+        current.client_config = Some(quic_config);
+        current.config = config.clone();
+        current.aead_key = aead_key;
+        current.profile = profile.clone();
+        current.wanted = true;
+        current.closed_by_server = false;
+        current.last_error = None;
+        drop(current);
+        if let Some((old, _)) = old {
+            old.close(DISCONNECTED, b"reconnecting");
+        }
+        self.changed.notify_one();
+
+        log::info!(
+            "connected to {}{} ({}){}",
+            config.server,
+            self.path(),
+            profile.as_deref().unwrap_or("default profile"),
+            if session.as_ref().is_some_and(|session| session.resumed) {
+                ", session resumed"
+            } else {
+                ""
+            }
+        );
+        self.metrics.set_connection(
+            config.server,
+            self.interface(),
+            previous,
+            connection.clone(),
+        );
+        log_transport(&connection, &config, session.as_ref().map(|s| s.window));
+        Ok(())
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        let mut current = self.current.lock().unwrap();
+        if let Some(endpoint) = &current.endpoint {
+            return Ok(endpoint.clone());
+        }
+        let endpoint = bind(self.interface())?;
+        current.endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    /// Moves the endpoint to a fresh socket after local addresses changed.
+    /// QUIC migrates the connection to the new path instead of letting it
+    /// time out, and a pending reconnect is retried right away.
+    pub(crate) fn rebind(&self) -> Result<()> {
+        let endpoint = self.current.lock().unwrap().endpoint.clone();
+        if let Some(endpoint) = endpoint {
+            endpoint.rebind(socket(self.interface())?)?;
+            log::info!(
+                "network changed, now sending from {}{}",
+                endpoint.local_addr()?,
+                self.path()
+            );
+        }
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// ` over <interface>` for log lines of a path.
+    fn path(&self) -> String {
+        self.interface()
+            .map(|interface| format!(" over {}", interface))
+            .unwrap_or_default()
+    }
+
+    fn failed(&self, e: anyhow::Error) -> anyhow::Error {
+        self.current.lock().unwrap().last_error = Some(format!("{:#}", e));
+        e
+    }
+
+    /// Closes the connection and stops the supervisor from reconnecting
+    /// until `connect` or `switch` succeeds.
+    pub(crate) fn disconnect(&self) {
+        let mut current = self.current.lock().unwrap();
+        current.wanted = false;
+        current.closed_by_server = false;
+        current.generation += 1;
+        current.session_id = None;
+        self.link.send_replace(Link::Off);
+        if let Some((connection, _)) = current.connection.take() {
+            connection.close(DISCONNECTED, b"disconnected");
+            log::info!("disconnected");
+        }
+        self.changed.notify_one();
+    }
+
+    /// Keeps the connection up: waits for it to drop, then reconnects with
+    /// exponential backoff and jitter until it is back or a disconnect is
+    /// requested.
+    pub(crate) fn supervise(self: Arc<Self>, config: ReconnectConfig) {
+        tokio::spawn(async move {
+            let mut backoff = config.min_backoff;
+            let mut connected_before = false;
+            loop {
+                if let Some(connection) = self.connection() {
+                    let reason = connection.closed().await;
+                    self.lost(&connection, reason);
+                    continue;
+                }
+                if !self.current.lock().unwrap().wanted {
+                    self.changed.notified().await;
+                    continue;
+                }
+                match self.reconnect(false).await {
+                    Ok(()) => {
+                        if connected_before {
+                            self.current.lock().unwrap().reconnects += 1;
+                        }
+                        connected_before = true;
+                        backoff = config.min_backoff;
+                    }
+                    Err(e) => {
+                        let delay = backoff.mul_f64(rand::random_range(0.5..1.0));
+                        log::warn!(
+                            "connecting{} failed, retrying in {} ms: {:#}",
+                            self.path(),
+                            delay.as_millis(),
+                            e
+                        );
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            _ = self.changed.notified() => {}
+                        }
+                        backoff = (backoff * 2).min(config.max_backoff);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Forgets `connection` if it is still the current one.
+    fn lost(&self, connection: &Connection, reason: quinn::ConnectionError) {
+        let mut current = self.current.lock().unwrap();
+        let is_current = current
+            .connection
+            .as_ref()
+            .is_some_and(|(c, _)| c.stable_id() == connection.stable_id());
+        if is_current {
+            log::warn!(
+                "connection to {}{} lost: {}",
+                current.config.server,
+                self.path(),
+                reason
+            );
+            current.connection = None;
+            current.last_error = Some(reason.to_string());
+            let for_good = matches!(
+                &reason,
+                quinn::ConnectionError::ApplicationClosed(close)
+                    if CLOSED_FOR_GOOD.contains(&close.error_code)
+            );
+            if for_good {
+                // reconnecting would only undo what the server decided
+                log::warn!("not reconnecting{} until asked to", self.path());
+                current.wanted = false;
+                current.closed_by_server = true;
+                current.session_id = None;
+                self.link.send_replace(Link::Off);
+            } else {
+                self.link.send_replace(Link::Down);
+            }
+        }
+    }
+
+    /// Smoothed RTT of the connection, `None` while there is none.
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        let current = self.current.lock().unwrap();
+        current
+            .connection
+            .as_ref()
+            .filter(|(connection, _)| connection.close_reason().is_none())
+            .map(|(connection, _)| connection.rtt())
+    }
+
+    /// Share of the packets sent on the connection that were lost, `None`
+    /// while there is no connection.
+    pub(crate) fn loss(&self) -> Option<f64> {
+        let current = self.current.lock().unwrap();
+        current
+            .connection
+            .as_ref()
+            .filter(|(connection, _)| connection.close_reason().is_none())
+            .map(|(connection, _)| loss(connection))
+    }
+
+    /// Largest UDP payload the connection's path carries, as found by
+    /// quinn's MTU discovery, `None` while there is no connection.
+    pub(crate) fn path_mtu(&self) -> Option<u16> {
+        let current = self.current.lock().unwrap();
+        current
+            .connection
+            .as_ref()
+            .filter(|(connection, _)| connection.close_reason().is_none())
+            .map(|(connection, _)| connection.stats().path.current_mtu)
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        let current = self.current.lock().unwrap();
+        let live = current
+            .connection
+            .as_ref()
+            .filter(|(connection, _)| connection.close_reason().is_none());
+        let state = match (live, current.wanted) {
+            (Some(_), _) => LinkState::Connected,
+            (None, true) => LinkState::Reconnecting,
+            (None, false) if current.closed_by_server => LinkState::Closed,
+            (None, false) => LinkState::Disconnected,
+        };
+        Status {
+            profile: current.profile.clone(),
+            server: current.config.server.to_string(),
+            server_name: current.config.server_name.clone(),
+            interface: self.interface.clone(),
+            state,
+            rtt_ms: live.map(|(c, _)| c.rtt().as_secs_f64() * 1000.0),
+            loss_percent: live.map(|(c, _)| loss(c) * 100.0),
+            uptime_secs: live.map(|(_, since)| since.elapsed().as_secs()),
+            reconnects: current.reconnects,
+            last_error: current.last_error.clone(),
+        }
+    }
+}
+
+/// Logs the transport of a new connection, for tuning it. Only the stream
+/// window is the server's, the rest are our own settings.
+fn log_transport(connection: &Connection, config: &Config, server_window: Option<u32>) {
+    let path = connection.stats().path;
+    log::info!(
+        "transport: {:?} congestion control, RTT {:?}, MTU {}, congestion window {}, \
+         stream windows {} here and {} at the server",
+        config.transport.congestion,
+        connection.rtt(),
+        path.current_mtu,
+        path.cwnd,
+        config.transport.stream_receive_window,
+        server_window.map_or("unknown".to_string(), |window| window.to_string())
+    );
+}
+
+fn loss(connection: &Connection) -> f64 {
+    let path = connection.stats().path;
+    path.lost_packets as f64 / path.sent_packets.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use encryption::resume::{CMD_CONNECT, CMD_RESUME, CMD_SESSION, DEFAULT_STREAM_WINDOW};
+    use quinn::{RecvStream, SendStream};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::tcp::TcpUpstream;
+    use crate::tunnel::{FLOW_QUEUE, Response, VPNUpstream};
+
+    fn endpoint() -> (SocketAddr, Endpoint) {
+        let _ = rustls::crypto::CryptoProvider::install_default(
+            rustls::crypto::ring::default_provider(),
+        );
+        let cert = rcgen::generate_simple_self_signed(vec!["vpn".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let server_config = quinn::ServerConfig::with_single_cert(
+            vec![CertificateDer::from(cert.cert.der().to_vec())],
+            PrivateKeyDer::Pkcs8(key),
+        )
+        .unwrap();
+        let endpoint =
+            quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        (endpoint.local_addr().unwrap(), endpoint)
+    }
+
+    /// QUIC endpoint that accepts the no-auth method and keeps its connections.
+    /// Later streams are dropped, so sessions are not resumable.
+    fn server() -> (SocketAddr, Arc<Mutex<Vec<Connection>>>) {
+        let (addr, endpoint) = endpoint();
+        let connections = Arc::new(Mutex::new(vec![]));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let connection = incoming.await.unwrap();
+                let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+                let mut header = [0u8; 2];
+                recv.read_exact(&mut header).await.unwrap();
+                let mut methods = vec![0u8; header[1] as usize];
+                recv.read_exact(&mut methods).await.unwrap();
+                send.write_all(&[0x05, 0x00]).await.unwrap();
+                accepted.lock().unwrap().push(connection.clone());
+                tokio::spawn(async move { while connection.accept_bi().await.is_ok() {} });
+            }
+        });
+        (addr, connections)
+    }
+
+    /// What the echo server saw.
+    #[derive(Default)]
+    struct Echo {
+        /// Whether a path started the session, so the next ones find it.
+        started: Mutex<bool>,
+        /// Everything each flow sent, which is also what it got back.
+        flows: Mutex<HashMap<u32, Vec<u8>>>,
+        connected_on: Mutex<Option<Connection>>,
+        resumed_on: Mutex<Option<Connection>>,
+    }
+
+    /// QUIC endpoint with one resumable session that the paths of a bond
+    /// join, whose flows echo what they get.
+    fn echo_server() -> (SocketAddr, Arc<Echo>) {
+        let (addr, endpoint) = endpoint();
+        let echo = Arc::new(Echo::default());
+        let seen = echo.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let connection = incoming.await.unwrap();
+                let echo = seen.clone();
+                tokio::spawn(async move {
+                    while let Ok((send, recv)) = connection.accept_bi().await {
+                        let (echo, connection) = (echo.clone(), connection.clone());
+                        tokio::spawn(async move {
+                            let _ = echo_stream(send, recv, &echo, &connection).await;
+                        });
+                    }
+                });
+            }
+        });
+        (addr, echo)
+    }
+
+    async fn echo_stream(
+        mut send: SendStream,
+        mut recv: RecvStream,
+        echo: &Echo,
+        connection: &Connection,
+    ) -> Result<()> {
+        let mut head = [0u8; 2];
+        recv.read_exact(&mut head).await?;
+        let id = match head[1] {
+            CMD_SESSION => {
+                // |flags, reserved, session id, stream window|
+                let mut req = [0u8; 22];
+                recv.read_exact(&mut req).await?;
+                let resumed = std::mem::replace(&mut *echo.started.lock().unwrap(), true);
+                send.write_all(&[0x05, 0x00, resumed as u8]).await?;
+                send.write_all(&req[2..18]).await?;
+                send.write_all(&DEFAULT_STREAM_WINDOW.to_be_bytes()).await?;
+                return Ok(send.finish()?);
+            }
+            CMD_CONNECT => {
+                // |reserved, type, addr, port, flow id|
+                let mut req = [0u8; 12];
+                recv.read_exact(&mut req).await?;
+                send.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await?;
+                *echo.connected_on.lock().unwrap() = Some(connection.clone());
+                u32::from_be_bytes(req[8..].try_into().unwrap())
+            }
+            CMD_RESUME => {
+                // |reserved, reserved, flow id, received|
+                let mut req = [0u8; 14];
+                recv.read_exact(&mut req).await?;
+                let id = u32::from_be_bytes(req[2..6].try_into().unwrap());
+                let received = u64::from_be_bytes(req[6..].try_into().unwrap()) as usize;
+                let sent = echo.flows.lock().unwrap()[&id].clone();
+                send.write_all(&[0x05, 0x00, 0x00]).await?;
+                send.write_all(&(sent.len() as u64).to_be_bytes()).await?;
+                send.write_all(&sent[received..]).await?;
+                *echo.resumed_on.lock().unwrap() = Some(connection.clone());
+                id
+            }
+            methods => {
+                let mut offered = vec![0u8; methods as usize];
+                recv.read_exact(&mut offered).await?;
+                return Ok(send.write_all(&[0x05, 0x00]).await?);
+            }
+        };
+        let mut buf = [0u8; 4096];
+        while let Some(n) = recv.read(&mut buf).await? {
+            let sent = &buf[..n];
+            echo.flows
+                .lock()
+                .unwrap()
+                .entry(id)
+                .or_default()
+                .extend_from_slice(sent);
+            send.write_all(&buf[..n]).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads `len` bytes of responses.
+    async fn read(rx: &mut mpsc::Receiver<Response>, len: usize) -> Vec<u8> {
+        let mut got = vec![];
+        while got.len() < len {
+            let response = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(!response.reset);
+            got.extend_from_slice(&response.payload);
+        }
+        got
+    }
+
+    #[tokio::test]
+    async fn test_flow_fails_over_to_other_path() {
+        let (addr, echo) = echo_server();
+        let config: Config = toml::from_str(&format!(
+            "server = \"{}\"\n[trust]\nmode = \"insecure\"",
+            addr
+        ))
+        .unwrap();
+        let (config, metrics) = (Arc::new(config), Arc::new(Metrics::default()));
+        let bond = Arc::new(Bond::new());
+        let mut paths = vec![];
+        for _ in 0..2 {
+            // a bond without binding to interfaces
+            let mut path = Session::new(config.clone(), [0u8; 32].into(), metrics.clone());
+            path.bond = Some(bond.clone());
+            path.connect().await.unwrap();
+            paths.push(Arc::new(path));
+        }
+        // including the path that started the server session
+        for path in &paths {
+            assert!(matches!(
+                *path.link().borrow(),
+                Link::Up { resumed: true, .. }
+            ));
+        }
+
+        let mut upstream = TcpUpstream::new(paths, metrics);
+        let (up_tx, up_rx) = mpsc::channel(FLOW_QUEUE);
+        let (down_tx, mut down_rx) = mpsc::channel(FLOW_QUEUE);
+        let key = (Ipv4Addr::new(10, 0, 0, 1), 80, 40000);
+        let _notify = upstream
+            .new_connection(key, None, up_rx, down_tx, None)
+            .unwrap();
+        up_tx.send(b"hello".to_vec()).await.unwrap();
+        assert_eq!(read(&mut down_rx, 5).await, b"hello");
+
+        let killed = echo.connected_on.lock().unwrap().take().unwrap();
+        killed.close(VarInt::from_u32(0x53), b"bye");
+        up_tx.send(b" world".to_vec()).await.unwrap();
+        assert_eq!(read(&mut down_rx, 6).await, b" world");
+        let resumed_on = echo.resumed_on.lock().unwrap().take().unwrap();
+        assert_ne!(resumed_on.stable_id(), killed.stable_id());
+        assert_eq!(echo.flows.lock().unwrap()[&0], b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_loss() {
+        let (addr, connections) = server();
+        let config: Config = toml::from_str(&format!(
+            "server = \"{}\"\n[trust]\nmode = \"insecure\"",
+            addr
+        ))
+        .unwrap();
+        let session = Arc::new(Session::new(
+            Arc::new(config),
+            [0u8; 32].into(),
+            Arc::new(Metrics::default()),
+        ));
+        let mut link = session.link();
+        session.clone().supervise(ReconnectConfig {
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(200),
+        });
+        let id = |link: &Link| match link {
+            Link::Up { connection, .. } => Some(connection.stable_id()),
+            _ => None,
+        };
+        let wait = Duration::from_secs(5);
+
+        let first = tokio::time::timeout(wait, link.wait_for(|l| id(l).is_some()))
+            .await
+            .unwrap()
+            .map(|l| id(&l))
+            .unwrap();
+        assert_eq!(session.status().state, LinkState::Connected);
+        connections.lock().unwrap()[0].close(VarInt::from_u32(0x53), b"bye");
+        tokio::time::timeout(
+            wait,
+            link.wait_for(|l| id(l).is_some_and(|id| Some(id) != first)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let status = session.status();
+        assert_eq!(status.state, LinkState::Connected);
+        assert_eq!(status.reconnects, 1);
+
+        session.disconnect();
+        assert_eq!(session.status().state, LinkState::Disconnected);
+        assert!(matches!(*link.borrow(), Link::Off));
+    }
+
+    #[tokio::test]
+    async fn test_kicked_is_not_retried() {
+        let (addr, connections) = server();
+        let config: Config = toml::from_str(&format!(
+            "server = \"{}\"\n[trust]\nmode = \"insecure\"",
+            addr
+        ))
+        .unwrap();
+        let session = Arc::new(Session::new(
+            Arc::new(config),
+            [0u8; 32].into(),
+            Arc::new(Metrics::default()),
+        ));
+        session.connect().await.unwrap();
+        let mut link = session.link();
+        session.clone().supervise(ReconnectConfig {
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(200),
+        });
+
+        connections.lock().unwrap()[0].close(VarInt::from_u32(0x52), b"kicked by admin");
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            link.wait_for(|l| matches!(l, Link::Off)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(session.status().state, LinkState::Closed);
+
+        // until asked to
+        session.connect().await.unwrap();
+        assert_eq!(session.status().state, LinkState::Connected);
+        assert_eq!(connections.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_during_connect() {
+        let (addr, endpoint) = endpoint();
+        let (asked, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let (server_asked, server_release) = (asked.clone(), release.clone());
+        tokio::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+            let mut header = [0u8; 2];
+            recv.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            recv.read_exact(&mut methods).await.unwrap();
+            // the login is held until the client disconnected
+            server_asked.notify_one();
+            server_release.notified().await;
+            send.write_all(&[0x05, 0x00]).await.unwrap();
+            // no sessions either
+            while connection.accept_bi().await.is_ok() {}
+        });
+        let config: Config = toml::from_str(&format!(
+            "server = \"{}\"\n[trust]\nmode = \"insecure\"",
+            addr
+        ))
+        .unwrap();
+        let session = Arc::new(Session::new(
+            Arc::new(config),
+            [0u8; 32].into(),
+            Arc::new(Metrics::default()),
+        ));
+
+        let connecting = tokio::spawn({
+            let session = session.clone();
+            async move { session.connect().await }
+        });
+        asked.notified().await;
+        session.disconnect();
+        release.notify_one();
+        assert!(connecting.await.unwrap().is_err());
+        assert_eq!(session.status().state, LinkState::Disconnected);
+        assert!(session.connection().is_none());
+    }
+}
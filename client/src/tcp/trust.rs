@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rustls::{
+    CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::parse_x509_certificate;
+
+use super::insecure_verifier::SkipServerVerification;
+use crate::config::Trust;
+
+/// SHA-256 of the server certificate's SubjectPublicKeyInfo.
+type Pin = [u8; 32];
+
+pub(crate) fn server_verifier(trust: &Trust) -> Result<Arc<dyn ServerCertVerifier>> {
+    Ok(match trust {
+        Trust::Ca { ca } => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("reading server CA {}", ca.display()))?
+            {
+                roots.add(cert?)?;
+            }
+            WebPkiServerVerifier::builder(Arc::new(roots)).build()?
+        }
+        Trust::Pin { pins } => {
+            let pins = pins
+                .iter()
+                .map(|pin| parse_pin(pin))
+                .collect::<Result<_>>()?;
+            Arc::new(KeyVerification::new(KeyPolicy::Pinned(pins)))
+        }
+        Trust::Tofu { known_hosts } => Arc::new(KeyVerification::new(KeyPolicy::TrustOnFirstUse(
+            KnownHosts::open(known_hosts)?,
+        ))),
+        Trust::Insecure => {
+            log::warn!("==================================================================");
+            log::warn!("trust mode is 'insecure': the server certificate is NOT verified,");
+            log::warn!("anyone on the path can impersonate the VPN server");
+            log::warn!("==================================================================");
+            SkipServerVerification::new()
+        }
+    })
+}
+
+fn spki_pin(cert: &CertificateDer<'_>) -> Result<Pin, rustls::Error> {
+    let (_, cert) = parse_x509_certificate(cert)
+        .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    Ok(Sha256::digest(cert.public_key().raw).into())
+}
+
+fn format_pin(pin: &Pin) -> String {
+    format!("sha256/{}", STANDARD.encode(pin))
+}
+
+fn parse_pin(pin: &str) -> Result<Pin> {
+    let encoded = pin
+        .strip_prefix("sha256/")
+        .ok_or_else(|| anyhow!("pin {:?} must start with sha256/", pin))?;
+    STANDARD
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| anyhow!("pin {:?} is not a SHA-256 digest", pin))
+}
+
+/// known_hosts-style file: one `<server name> sha256/<base64>` per line.
+#[derive(Debug)]
+struct KnownHosts {
+    path: PathBuf,
+    hosts: Mutex<HashMap<String, Pin>>,
+}
+
+impl KnownHosts {
+    fn open(path: &Path) -> Result<Self> {
+        let raw = match std::fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+
+        let mut hosts = HashMap::new();
+        for line in raw.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (host, pin) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("{}: malformed line {:?}", path.display(), line))?;
+            hosts.insert(host.to_string(), parse_pin(pin.trim())?);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    fn check(&self, host: &str, pin: Pin) -> Result<(), rustls::Error> {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get(host) {
+            Some(known) if *known == pin => Ok(()),
+            Some(known) => {
+                log::error!(
+                    "SERVER KEY FOR {} CHANGED: expected {}, got {}. Someone may be impersonating the server; if the key was rotated on purpose, remove the entry from {}",
+                    host,
+                    format_pin(known),
+                    format_pin(&pin),
+                    self.path.display()
+                );
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            None => {
+                log::warn!(
+                    "first connection to {}, trusting key {} from now on",
+                    host,
+                    format_pin(&pin)
+                );
+                let line = format!("{} {}\n", host, format_pin(&pin));
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .and_then(|mut file| file.write_all(line.as_bytes()))
+                    .map_err(|e| rustls::Error::General(format!("writing known hosts: {}", e)))?;
+                hosts.insert(host.to_string(), pin);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum KeyPolicy {
+    Pinned(Vec<Pin>),
+    TrustOnFirstUse(KnownHosts),
+}
+
+/// Trusts the server by its public key rather than by a chain to a CA.
+#[derive(Debug)]
+struct KeyVerification {
+    provider: Arc<CryptoProvider>,
+    policy: KeyPolicy,
+}
+
+impl KeyVerification {
+    fn new(policy: KeyPolicy) -> Self {
+        Self {
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+            policy,
+        }
+    }
+}
+
+impl ServerCertVerifier for KeyVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pin = spki_pin(end_entity)?;
+        match &self.policy {
+            KeyPolicy::Pinned(pins) if pins.contains(&pin) => {}
+            KeyPolicy::Pinned(_) => {
+                log::error!(
+                    "server {} presented unpinned key {}",
+                    server_name.to_str(),
+                    format_pin(&pin)
+                );
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+            KeyPolicy::TrustOnFirstUse(known_hosts) => {
+                known_hosts.check(&server_name.to_str(), pin)?
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_key() -> CertificateDer<'static> {
+        rcgen::generate_simple_self_signed(vec!["vpn".to_string()])
+            .unwrap()
+            .cert
+            .der()
+            .clone()
+    }
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let path = std::env::temp_dir().join(format!("vpn-known-hosts-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (first, second) = (
+            spki_pin(&server_key()).unwrap(),
+            spki_pin(&server_key()).unwrap(),
+        );
+
+        let known_hosts = KnownHosts::open(&path).unwrap();
+        assert!(known_hosts.check("vpn", first).is_ok());
+        assert!(known_hosts.check("vpn", first).is_ok());
+        assert!(known_hosts.check("vpn", second).is_err());
+        assert!(known_hosts.check("other", second).is_ok());
+
+        // the decision survives a restart
+        let known_hosts = KnownHosts::open(&path).unwrap();
+        assert!(known_hosts.check("vpn", first).is_ok());
+        assert!(known_hosts.check("vpn", second).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pin_roundtrip() {
+        let pin = spki_pin(&server_key()).unwrap();
+        assert_eq!(parse_pin(&format_pin(&pin)).unwrap(), pin);
+        assert!(parse_pin("sha1/AAAA").is_err());
+    }
+}
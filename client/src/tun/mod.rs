@@ -3,19 +3,23 @@ use anyhow::Result;
 use crate::tunnel::L3Stream;
 use std::io::{Read, Write};
 use tokio::io::{self, Interest, Ready, unix::AsyncFd};
-use tun::{Device, configure};
+use tun::{AbstractDevice, Device, configure};
+
+/// Name of the tunnel interface.
+pub(crate) const NAME: &str = "tun0";
 
 pub(crate) struct Tun {
     fd: AsyncFd<Device>,
 }
 
 impl Tun {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(mtu: u16) -> Self {
         let mut config = configure();
         config
-            .tun_name("tun0")
+            .tun_name(NAME)
             .address("10.0.0.2")
             .destination("10.0.0.1")
+            .mtu(mtu)
             .up();
         let dev = tun::create(&config).expect("creating tun device");
         dev.set_nonblock()
@@ -27,6 +31,11 @@ impl Tun {
 }
 
 impl L3Stream for Tun {
+    fn set_mtu(&mut self, mtu: u16) -> Result<()> {
+        self.fd.get_mut().set_mtu(mtu)?;
+        Ok(())
+    }
+
     async fn do_io(
         &mut self,
         read_buf: &mut [u8],
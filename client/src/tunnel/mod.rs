@@ -1,38 +1,119 @@
 use std::{
     collections::{HashMap, VecDeque},
+    fmt,
     net::{IpAddr, Ipv4Addr},
     sync::Arc,
 };
 
-use anyhow::Result;
-use etherparse::{Ipv4HeaderSlice, PacketBuilder, TcpHeaderSlice, ip_number::TCP};
+use anyhow::{Result, anyhow};
+
+use crate::metrics::{Dropped, FlowState, Metrics};
+use crate::mtu;
+use etherparse::{
+    Icmpv4Type, Ipv4HeaderSlice, PacketBuilder, TcpHeaderSlice, TcpOptionElement,
+    icmpv4::DestUnreachableHeader, ip_number::TCP,
+};
 use rand::RngCore;
 use rand::rngs::ThreadRng;
+use serde::Serialize;
 use std::net::SocketAddr;
 use tokio::sync::{
     Notify,
-    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender, error::TrySendError},
+    oneshot, watch,
 };
 
+mod stack;
+
+pub(crate) use stack::Stack;
+
 pub(crate) type FlowKey = (Ipv4Addr, u16, u16);
 
+/// Chunks of a flow's data queued toward its stream before the kernel side
+/// has to wait, so a slow stream pushes back instead of filling memory.
+pub(crate) const FLOW_QUEUE: usize = 16;
+
+/// Responses of all flows queued toward the TUN before their streams wait.
+const RESPONSE_QUEUE: usize = 1024;
+
 type FlowTable = HashMap<FlowKey, TcpFlow>;
 
 struct TcpFlow {
+    state: FlowState,
     our_seq: u32,
     our_ack: u32,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
-    sender: UnboundedSender<Vec<u8>>,
+    sender: Sender<Vec<u8>>,
     notify: Arc<Notify>,
+    /// Payload bytes from the kernel to the upstream.
+    bytes_up: u64,
+    /// Payload bytes from the upstream to the kernel.
+    bytes_down: u64,
+    /// Largest segment toward the kernel, what it announced in its SYN and
+    /// at most our own MSS.
+    mss: u16,
+    /// The server finished its side and our FIN is sent, the kernel may
+    /// still send.
+    finished: bool,
 }
 
+/// A flow table entry as listed by `client-ctl flows`.
+#[derive(Debug, Serialize)]
+pub(crate) struct FlowInfo {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub state: FlowState,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+/// Asks the tunnel loop, which owns the flow table, for a snapshot of it.
+pub(crate) type FlowQuery = oneshot::Sender<Vec<FlowInfo>>;
+
+/// Data of a flow from its stream. An empty payload means the server
+/// closed its side, the kernel may still send.
 pub(crate) struct Response {
     pub payload: Vec<u8>,
     pub flow_key: FlowKey,
+    /// The upstream stream failed; the flow is reset toward the kernel.
+    pub reset: bool,
 }
 
+impl Response {
+    pub(crate) fn reset(flow_key: FlowKey) -> Self {
+        Self {
+            payload: vec![],
+            flow_key,
+            reset: true,
+        }
+    }
+
+    pub(crate) fn end(flow_key: FlowKey) -> Self {
+        Self {
+            payload: vec![],
+            flow_key,
+            reset: false,
+        }
+    }
+}
+
+/// The error a flow fails to open with when the server could not connect
+/// it, with the SOCKS5 reply status the server gave.
+#[derive(Debug)]
+pub(crate) struct ConnectFailed(pub u8);
+
+impl fmt::Display for ConnectFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SOCKS5 connect failed, status {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectFailed {}
+
 pub(crate) trait L3Stream {
+    fn set_mtu(&mut self, mtu: u16) -> Result<()>;
+
     async fn do_io(
         &mut self,
         read_buf: &mut [u8],
@@ -41,11 +122,20 @@ pub(crate) trait L3Stream {
 }
 
 pub(crate) trait VPNUpstream {
+    /// Opens a flow to `key`'s destination, or to `host` if given: a name
+    /// for the server to resolve, or an address literal. `opened` gets
+    /// whether the server connected it.
+    ///
+    /// The flow sends what arrives on `rx` and finishes its stream once `rx`
+    /// is closed, while the server's data keeps coming on `tx`. Notifying
+    /// the returned `Notify` aborts the flow.
     fn new_connection(
         &mut self,
         key: FlowKey,
-        rx: UnboundedReceiver<Vec<u8>>,
-        tx: UnboundedSender<Response>,
+        host: Option<String>,
+        rx: Receiver<Vec<u8>>,
+        tx: Sender<Response>,
+        opened: Option<oneshot::Sender<Result<()>>>,
     ) -> Result<Arc<tokio::sync::Notify>>;
 }
 
@@ -53,14 +143,25 @@ pub(crate) struct Tunnel<IPv4STREAM, UPSTREAM> {
     tun: IPv4STREAM,
     upstream: UPSTREAM,
     flow_table: FlowTable,
-    response_ipv4_stream: UnboundedReceiver<Response>,
-    shared_channel: UnboundedSender<Response>,
+    response_ipv4_stream: Receiver<Response>,
+    shared_channel: Sender<Response>,
     rng: ThreadRng,
+    metrics: Arc<Metrics>,
+    flow_queries: UnboundedSender<FlowQuery>,
+    flow_query_stream: UnboundedReceiver<FlowQuery>,
+    /// The TUN MTU, following the QUIC path MTU.
+    mtu: watch::Receiver<u16>,
 }
 
 impl<IPv4STREAM, UPSTREAM> Tunnel<IPv4STREAM, UPSTREAM> {
-    pub(crate) fn new(tun: IPv4STREAM, upstream: UPSTREAM) -> Self {
-        let (shared_channel, response_ipv4_stream) = mpsc::unbounded_channel::<Response>();
+    pub(crate) fn new(
+        tun: IPv4STREAM,
+        upstream: UPSTREAM,
+        mtu: watch::Receiver<u16>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (shared_channel, response_ipv4_stream) = mpsc::channel::<Response>(RESPONSE_QUEUE);
+        let (flow_queries, flow_query_stream) = mpsc::unbounded_channel::<FlowQuery>();
         Self {
             tun,
             upstream,
@@ -68,8 +169,29 @@ impl<IPv4STREAM, UPSTREAM> Tunnel<IPv4STREAM, UPSTREAM> {
             shared_channel,
             response_ipv4_stream,
             rng: rand::rng(),
+            metrics,
+            flow_queries,
+            flow_query_stream,
+            mtu,
         }
     }
+
+    pub(crate) fn flow_queries(&self) -> UnboundedSender<FlowQuery> {
+        self.flow_queries.clone()
+    }
+
+    fn flows(&self) -> Vec<FlowInfo> {
+        self.flow_table
+            .values()
+            .map(|flow| FlowInfo {
+                local: flow.local_addr,
+                remote: flow.remote_addr,
+                state: flow.state,
+                bytes_up: flow.bytes_up,
+                bytes_down: flow.bytes_down,
+            })
+            .collect()
+    }
 }
 
 impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
@@ -78,8 +200,18 @@ impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
         ip_hdr: Ipv4HeaderSlice<'_>,
         packet: &[u8],
     ) -> Result<Option<Vec<u8>>> {
+        let mtu = *self.mtu.borrow();
+        if packet.len() > mtu as usize {
+            // sized for the MTU before it shrank; the kernel lowers its
+            // path MTU and resends smaller
+            self.metrics.dropped(Dropped::TooBig);
+            return Ok(ip_hdr
+                .dont_fragment()
+                .then(|| craft_frag_needed(&ip_hdr, packet, mtu)));
+        }
         if ip_hdr.protocol() != TCP {
             // support only TCP for now
+            self.metrics.dropped(Dropped::Protocol);
             return Ok(None);
         }
 
@@ -98,28 +230,52 @@ impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
             let key = (dst_ip, dst_port, src_port);
             let flow = self.flow_table.get_mut(&key);
             if let Some(flow) = flow {
+                if flow.state == FlowState::SynReceived && tcp_hdr.ack() && !tcp_hdr.syn() {
+                    flow.state = FlowState::Established;
+                    self.metrics
+                        .flow_moved(FlowState::SynReceived, FlowState::Established);
+                }
+                // only what continues the flow is taken, anything else gets
+                // the ACK of what we have for the kernel to send again
+                let taken = seq == flow.our_ack && (payload.is_empty() || flow.forward(payload)?);
+                if (tcp_hdr.fin() || !payload.is_empty()) && !taken {
+                    return Ok(Some(craft_ipv4_tcp(
+                        flow.remote_addr,
+                        flow.local_addr,
+                        flow.our_seq,
+                        flow.our_ack,
+                        0x10, // ACK
+                        &[],
+                        &[],
+                    )));
+                }
                 if tcp_hdr.fin() {
-                    log::debug!("killing connection");
-                    flow.our_ack = seq.wrapping_add(1);
-                    flow.our_seq = ack;
-                    flow.sender.send(payload.to_vec())?;
-                    flow.notify.notify_one();
+                    flow.our_ack = seq.wrapping_add(payload.len() as u32).wrapping_add(1);
+                    let flags = if flow.finished {
+                        // both sides are done, dropping the sender finishes
+                        // the stream with everything queued
+                        0x10 // ACK
+                    } else {
+                        log::debug!("killing connection");
+                        flow.notify.notify_one();
+                        0x11 // ACK + FIN
+                    };
                     let response = craft_ipv4_tcp(
                         flow.remote_addr,
                         flow.local_addr,
                         flow.our_seq,
                         flow.our_ack,
-                        0x11, // ACK + FIN,
+                        flags,
+                        &[],
                         &[],
                     );
+                    self.metrics.flow_closed(flow.state);
                     self.flow_table.remove(&key);
                     return Ok(Some(response));
                 }
 
                 if !payload.is_empty() {
                     flow.our_ack = seq.wrapping_add(payload.len() as u32);
-                    flow.our_seq = ack;
-                    flow.sender.send(payload.to_vec())?;
                     return Ok(Some(craft_ipv4_tcp(
                         flow.remote_addr,
                         flow.local_addr,
@@ -127,6 +283,7 @@ impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
                         flow.our_ack,
                         0x10, // ACK
                         &[],
+                        &[],
                     )));
                 }
             } else if tcp_hdr.syn() {
@@ -134,31 +291,57 @@ impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
                 let src = SocketAddr::from((src_ip, src_port));
                 let our_isn: u32 = self.rng.next_u32();
                 let kernel_next = seq.wrapping_add(1);
+                let our_mss = mtu::mss(mtu);
+                // 536 is what TCP assumes without the option
+                let kernel_mss = tcp_hdr
+                    .options_iterator()
+                    .find_map(|option| match option {
+                        Ok(TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+                        _ => None,
+                    })
+                    .unwrap_or(536);
 
-                let (tx, rx) = mpsc::unbounded_channel::<Vec<u8>>();
-                let notify = self
-                    .upstream
-                    .new_connection(key, rx, self.shared_channel.clone())?;
+                let (tx, rx) = mpsc::channel::<Vec<u8>>(FLOW_QUEUE);
+                let notify = self.upstream.new_connection(
+                    key,
+                    None,
+                    rx,
+                    self.shared_channel.clone(),
+                    None,
+                )?;
+                self.metrics.flow_opened(FlowState::SynReceived);
                 self.flow_table.insert(
                     key,
                     TcpFlow {
-                        our_seq: our_isn,
+                        state: FlowState::SynReceived,
+                        // the SYN counts as one
+                        our_seq: our_isn.wrapping_add(1),
                         our_ack: kernel_next,
                         local_addr: src,
                         remote_addr: dst,
                         sender: tx,
                         notify,
+                        bytes_up: 0,
+                        bytes_down: 0,
+                        mss: kernel_mss.min(our_mss),
+                        finished: false,
                     },
                 );
+                // clamps what the kernel sends to what fits a QUIC packet
                 return Ok(Some(craft_ipv4_tcp(
                     dst,
                     src,
                     our_isn,
                     kernel_next,
                     0x12,
+                    &[TcpOptionElement::MaximumSegmentSize(our_mss)],
                     &[],
                 )));
+            } else {
+                self.metrics.dropped(Dropped::UnknownFlow);
             }
+        } else {
+            self.metrics.dropped(Dropped::Unparseable);
         }
 
         Ok(None)
@@ -173,11 +356,13 @@ impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
             if response.is_none() {
                 response = responses.pop_front();
             }
+            let pending = response.as_ref().map(Vec::len);
 
             tokio::select! {
                 io_result = self.tun.do_io(&mut buf, &mut response) => {
                     let packet = match io_result {
                         Ok(read_size) => {
+                            self.metrics.packet_read(read_size);
                             &buf[..read_size]
                         },
                         Err(err) => {
@@ -193,23 +378,101 @@ impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Tunnel<TUN, UPSTREAM> {
                         },
                         Err(err) => {
                             log::warn!("error parsing ipv4 packet, skipping it: {}", err);
-                            continue;
+                            self.metrics.dropped(Dropped::Unparseable);
                         },
                     };
                 },
                 Some(response) = self.response_ipv4_stream.recv() => {
-                    if let Some(flow) = self.flow_table.get(&response.flow_key) {
-                        responses.push_back(craft_ipv4_tcp(
-                            flow.remote_addr,
-                            flow.local_addr,
-                            flow.our_seq,
-                            flow.our_ack,
-                            0x18, // PSH + ACK
-                            &response.payload,
-                        ));
+                    self.respond(response, &mut responses);
+                },
+                Ok(()) = self.mtu.changed() => {
+                    let mtu = *self.mtu.borrow_and_update();
+                    if let Err(err) = self.tun.set_mtu(mtu) {
+                        log::warn!("setting tunnel MTU to {}: {:#}", mtu, err);
                     }
                 },
+                Some(query) = self.flow_query_stream.recv() => {
+                    let _ = query.send(self.flows());
+                },
             };
+
+            if let Some(len) = pending
+                && response.is_none()
+            {
+                self.metrics.packet_written(len);
+            }
+        }
+    }
+}
+
+impl<TUN, UPSTREAM> Tunnel<TUN, UPSTREAM> {
+    /// Turns data or the end of a flow's stream into segments for the kernel.
+    fn respond(&mut self, response: Response, segments: &mut VecDeque<Vec<u8>>) {
+        if response.reset {
+            if let Some(flow) = self.flow_table.remove(&response.flow_key) {
+                log::debug!("resetting flow {:?}", response.flow_key);
+                self.metrics.flow_closed(flow.state);
+                segments.push_back(craft_ipv4_tcp(
+                    flow.remote_addr,
+                    flow.local_addr,
+                    flow.our_seq,
+                    flow.our_ack,
+                    0x14, // RST + ACK
+                    &[],
+                    &[],
+                ));
+            }
+            return;
+        }
+        let Some(flow) = self.flow_table.get_mut(&response.flow_key) else {
+            return;
+        };
+        if response.payload.is_empty() {
+            // the server finished its side
+            if !flow.finished {
+                flow.finished = true;
+                segments.push_back(craft_ipv4_tcp(
+                    flow.remote_addr,
+                    flow.local_addr,
+                    flow.our_seq,
+                    flow.our_ack,
+                    0x11, // ACK + FIN
+                    &[],
+                    &[],
+                ));
+                // the FIN counts as one
+                flow.our_seq = flow.our_seq.wrapping_add(1);
+            }
+            return;
+        }
+        flow.bytes_down += response.payload.len() as u64;
+        let mss = flow.mss.min(mtu::mss(*self.mtu.borrow()));
+        for segment in response.payload.chunks(mss as usize) {
+            segments.push_back(craft_ipv4_tcp(
+                flow.remote_addr,
+                flow.local_addr,
+                flow.our_seq,
+                flow.our_ack,
+                0x18, // PSH + ACK
+                &[],
+                segment,
+            ));
+            flow.our_seq = flow.our_seq.wrapping_add(segment.len() as u32);
+        }
+    }
+}
+
+impl TcpFlow {
+    /// Queues kernel data for the stream. `false` if the queue is full, the
+    /// data is then not acknowledged and the kernel sends it again.
+    fn forward(&mut self, payload: &[u8]) -> Result<bool> {
+        match self.sender.try_send(payload.to_vec()) {
+            Ok(()) => {
+                self.bytes_up += payload.len() as u64;
+                Ok(true)
+            }
+            Err(TrySendError::Full(_)) => Ok(false),
+            Err(TrySendError::Closed(_)) => Err(anyhow!("flow {:?} is closed", self.remote_addr)),
         }
     }
 }
@@ -220,6 +483,7 @@ pub(crate) fn craft_ipv4_tcp(
     seq: u32,
     ack: u32,
     flags: u8,
+    options: &[TcpOptionElement],
     payload: &[u8],
 ) -> Vec<u8> {
     let src_ip = match src.ip() {
@@ -240,12 +504,20 @@ pub(crate) fn craft_ipv4_tcp(
     if flags & 0x01 != 0 {
         builder = builder.fin();
     }
+    if flags & 0x04 != 0 {
+        builder = builder.rst();
+    }
     if flags & 0x10 != 0 {
         builder = builder.ack(ack);
     }
     if flags & 0x08 != 0 {
         builder = builder.psh();
     }
+    if !options.is_empty() {
+        builder = builder
+            .options(options)
+            .expect("TCP options fit the header");
+    }
     let mut buf = Vec::<u8>::with_capacity(builder.size(payload.len()));
     builder
         .write(&mut buf, payload)
@@ -254,3 +526,135 @@ pub(crate) fn craft_ipv4_tcp(
     log::debug!("responded with: seq {}, ack {}", seq, ack);
     buf
 }
+
+/// ICMP "fragmentation needed" for `packet`, as if from the router in front
+/// of its destination. It quotes the IP header and the first 8 bytes past
+/// it, which is what the kernel matches to a socket.
+fn craft_frag_needed(ip_hdr: &Ipv4HeaderSlice, packet: &[u8], mtu: u16) -> Vec<u8> {
+    let quoted = &packet[..packet.len().min(ip_hdr.slice().len() + 8)];
+    let builder = PacketBuilder::ipv4(ip_hdr.destination(), ip_hdr.source(), 64).icmpv4(
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FragmentationNeeded {
+            next_hop_mtu: mtu,
+        }),
+    );
+    let mut buf = Vec::<u8>::with_capacity(builder.size(quoted.len()));
+    builder
+        .write(&mut buf, quoted)
+        .expect("crafting kernel packet");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Never has packets, the tests feed them to `process_packet`.
+    struct NoTun;
+
+    impl L3Stream for NoTun {
+        fn set_mtu(&mut self, _mtu: u16) -> Result<()> {
+            Ok(())
+        }
+
+        async fn do_io(
+            &mut self,
+            _read_buf: &mut [u8],
+            _write_buf: &mut Option<Vec<u8>>,
+        ) -> Result<usize> {
+            std::future::pending().await
+        }
+    }
+
+    /// Keeps what each flow sends.
+    #[derive(Default)]
+    struct Upstream(Vec<Receiver<Vec<u8>>>);
+
+    impl VPNUpstream for Upstream {
+        fn new_connection(
+            &mut self,
+            _key: FlowKey,
+            _host: Option<String>,
+            rx: Receiver<Vec<u8>>,
+            _tx: Sender<Response>,
+            _opened: Option<oneshot::Sender<Result<()>>>,
+        ) -> Result<Arc<Notify>> {
+            self.0.push(rx);
+            Ok(Arc::new(Notify::new()))
+        }
+    }
+
+    /// The kernel sending `flags`, `seq`, `ack` and `payload` to the remote.
+    fn kernel_segment(
+        tunnel: &mut Tunnel<NoTun, Upstream>,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> Option<Vec<u8>> {
+        let kernel = "10.0.0.2:40000".parse().unwrap();
+        let remote = "1.1.1.1:443".parse().unwrap();
+        let segment = craft_ipv4_tcp(kernel, remote, seq, ack, flags, &[], payload);
+        let ip_hdr = Ipv4HeaderSlice::from_slice(&segment).unwrap();
+        tunnel.process_packet(ip_hdr, &segment).unwrap()
+    }
+
+    /// Flags, sequence and acknowledgment number of a segment to the kernel.
+    fn parse(segment: &[u8]) -> (bool, u32, u32) {
+        let ip_hdr = Ipv4HeaderSlice::from_slice(segment).unwrap();
+        let tcp_hdr = TcpHeaderSlice::from_slice(&segment[ip_hdr.slice().len()..]).unwrap();
+        (
+            tcp_hdr.fin(),
+            tcp_hdr.sequence_number(),
+            tcp_hdr.acknowledgment_number(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_server_finishes_first() {
+        let (_mtu, mtu) = watch::channel(1500);
+        let mut tunnel = Tunnel::new(NoTun, Upstream::default(), mtu, Arc::default());
+        let key = (Ipv4Addr::new(1, 1, 1, 1), 443, 40000);
+
+        let syn_ack = kernel_segment(&mut tunnel, 100, 0, 0x02, &[]).unwrap();
+        let (_, isn, _) = parse(&syn_ack);
+        kernel_segment(&mut tunnel, 101, isn + 1, 0x10, &[]);
+
+        let mut segments = VecDeque::new();
+        tunnel.respond(Response::end(key), &mut segments);
+        let fin = segments.pop_front().unwrap();
+        assert_eq!(parse(&fin), (true, isn + 1, 101));
+        // only once
+        tunnel.respond(Response::end(key), &mut segments);
+        assert!(segments.is_empty());
+
+        // the kernel's side stays open
+        let ack = kernel_segment(&mut tunnel, 101, isn + 2, 0x18, b"hi").unwrap();
+        assert_eq!(parse(&ack), (false, isn + 2, 103));
+        let ack = kernel_segment(&mut tunnel, 103, isn + 2, 0x11, &[]).unwrap();
+        assert_eq!(parse(&ack), (false, isn + 2, 104));
+        assert!(tunnel.flow_table.is_empty());
+
+        // and its data reaches the stream, which is then finished
+        let rx = &mut tunnel.upstream.0[0];
+        assert_eq!(rx.recv().await.unwrap(), b"hi");
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_craft_frag_needed() {
+        let kernel = "10.0.0.2:40000".parse().unwrap();
+        let remote = "1.1.1.1:443".parse().unwrap();
+        let segment = craft_ipv4_tcp(kernel, remote, 1, 2, 0x18, &[], &[0; 1300]);
+        let ip_hdr = Ipv4HeaderSlice::from_slice(&segment).unwrap();
+
+        let icmp = craft_frag_needed(&ip_hdr, &segment, 1180);
+        let reply = Ipv4HeaderSlice::from_slice(&icmp).unwrap();
+        assert_eq!(reply.source(), [1, 1, 1, 1]);
+        assert_eq!(reply.destination(), [10, 0, 0, 2]);
+        let body = &icmp[reply.slice().len()..];
+        // type 3 code 4, then the MTU after the checksum and an unused word
+        assert_eq!(&body[..2], [3, 4]);
+        assert_eq!(&body[6..8], 1180u16.to_be_bytes());
+        assert_eq!(&body[8..], &segment[..28]);
+    }
+}
@@ -0,0 +1,676 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
+
+use anyhow::Result;
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice, ip_number::TCP};
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, Device, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{HardwareAddress, IpCidr, IpListenEndpoint},
+};
+use tokio::sync::{
+    Notify,
+    mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender, error::TrySendError},
+    watch,
+};
+
+use super::{
+    FLOW_QUEUE, FlowInfo, FlowKey, FlowQuery, L3Stream, Response, VPNUpstream, craft_frag_needed,
+};
+use crate::metrics::{Dropped, FlowState, Metrics};
+
+/// Socket buffer per direction of each flow. The receive buffer is the
+/// window the kernel gets.
+const BUFFER: usize = 64 * 1024;
+
+/// Unacknowledged data or keep-alives are given up on after this long.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Address of the stack on the TUN. A default route through it makes the
+/// stack take packets for any destination.
+const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+/// Longest wait between polls of the stack when no timer is due.
+const IDLE: Duration = Duration::from_secs(1);
+
+/// A tunnel engine running smoltcp's TCP instead of `Tunnel`'s own: each
+/// SYN from the kernel gets a socket listening on its destination, and the
+/// data of accepted sockets goes to and from `UPSTREAM` the same way.
+/// Retransmission, windows and TCP options are smoltcp's.
+pub(crate) struct Stack<IPv4STREAM, UPSTREAM> {
+    tun: IPv4STREAM,
+    upstream: UPSTREAM,
+    iface: Interface,
+    device: Queue,
+    sockets: SocketSet<'static>,
+    flows: HashMap<FlowKey, Flow>,
+    metrics: Arc<Metrics>,
+    flow_queries: UnboundedSender<FlowQuery>,
+    flow_query_stream: UnboundedReceiver<FlowQuery>,
+    mtu: watch::Receiver<u16>,
+}
+
+struct Flow {
+    handle: SocketHandle,
+    state: FlowState,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    /// Dropped once the kernel closed its side. While it is full, data is
+    /// left in the socket and the kernel's window closes.
+    sender: Option<Sender<Vec<u8>>>,
+    notify: Arc<Notify>,
+    /// Data from the stream, only taken while the socket has room, so the
+    /// stream waits on the kernel's window. `None` once the stream is done.
+    responses: Option<Receiver<Response>>,
+    /// Upstream data the socket had no room for, at most one response.
+    pending: Vec<u8>,
+    /// The server finished its side, the socket is closed once `pending`
+    /// is out.
+    finishing: bool,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+impl<IPv4STREAM, UPSTREAM> Stack<IPv4STREAM, UPSTREAM> {
+    pub(crate) fn new(
+        tun: IPv4STREAM,
+        upstream: UPSTREAM,
+        mtu: watch::Receiver<u16>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let mut device = Queue {
+            rx: None,
+            tx: VecDeque::new(),
+            mtu: *mtu.borrow() as usize,
+        };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::random();
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(ADDRESS.into(), 32))
+                .expect("one address fits");
+        });
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(ADDRESS)
+            .expect("one route fits");
+        iface.set_any_ip(true);
+
+        let (flow_queries, flow_query_stream) = mpsc::unbounded_channel::<FlowQuery>();
+        Self {
+            tun,
+            upstream,
+            iface,
+            device,
+            sockets: SocketSet::new(vec![]),
+            flows: HashMap::new(),
+            metrics,
+            flow_queries,
+            flow_query_stream,
+            mtu,
+        }
+    }
+
+    pub(crate) fn flow_queries(&self) -> UnboundedSender<FlowQuery> {
+        self.flow_queries.clone()
+    }
+
+    fn flows(&self) -> Vec<FlowInfo> {
+        self.flows
+            .values()
+            .map(|flow| FlowInfo {
+                local: flow.local_addr,
+                remote: flow.remote_addr,
+                state: flow.state,
+                bytes_up: flow.bytes_up,
+                bytes_down: flow.bytes_down,
+            })
+            .collect()
+    }
+}
+
+impl<TUN: L3Stream, UPSTREAM: VPNUpstream> Stack<TUN, UPSTREAM> {
+    /// Queues `packet` for the stack, opening a socket first if it starts a
+    /// flow. Packets are handed over one per poll, so a SYN always meets the
+    /// socket opened for it.
+    fn process_packet(&mut self, ip_hdr: Ipv4HeaderSlice<'_>, packet: &[u8]) -> Result<()> {
+        let mtu = *self.mtu.borrow();
+        if packet.len() > mtu as usize {
+            self.metrics.dropped(Dropped::TooBig);
+            if ip_hdr.dont_fragment() {
+                self.device
+                    .tx
+                    .push_back(craft_frag_needed(&ip_hdr, packet, mtu));
+            }
+            return Ok(());
+        }
+        if ip_hdr.protocol() != TCP {
+            // smoltcp would answer pings for every address
+            self.metrics.dropped(Dropped::Protocol);
+            return Ok(());
+        }
+        let Ok(tcp_hdr) = TcpHeaderSlice::from_slice(&packet[ip_hdr.slice().len()..]) else {
+            self.metrics.dropped(Dropped::Unparseable);
+            return Ok(());
+        };
+
+        let dst_ip = Ipv4Addr::from(ip_hdr.destination());
+        let key = (dst_ip, tcp_hdr.destination_port(), tcp_hdr.source_port());
+        if !self.flows.contains_key(&key) {
+            if tcp_hdr.syn() && !tcp_hdr.ack() {
+                let local_addr = SocketAddr::from((ip_hdr.source(), tcp_hdr.source_port()));
+                self.accept(key, local_addr)?;
+            } else {
+                // smoltcp resets it
+                self.metrics.dropped(Dropped::UnknownFlow);
+            }
+        }
+        self.device.rx = Some(packet.to_vec());
+        Ok(())
+    }
+
+    fn accept(&mut self, key: FlowKey, local_addr: SocketAddr) -> Result<()> {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; BUFFER]),
+            tcp::SocketBuffer::new(vec![0; BUFFER]),
+        );
+        socket.set_nagle_enabled(false);
+        socket.set_timeout(Some(TIMEOUT.into()));
+        socket.listen(IpListenEndpoint {
+            addr: Some(key.0.into()),
+            port: key.1,
+        })?;
+
+        let (tx, rx) = mpsc::channel::<Vec<u8>>(FLOW_QUEUE);
+        let (responses_tx, responses) = mpsc::channel::<Response>(FLOW_QUEUE);
+        let notify = self
+            .upstream
+            .new_connection(key, None, rx, responses_tx, None)?;
+        self.metrics.flow_opened(FlowState::SynReceived);
+        self.flows.insert(
+            key,
+            Flow {
+                handle: self.sockets.add(socket),
+                state: FlowState::SynReceived,
+                local_addr,
+                remote_addr: SocketAddr::from((key.0, key.1)),
+                sender: Some(tx),
+                notify,
+                responses: Some(responses),
+                pending: vec![],
+                finishing: false,
+                bytes_up: 0,
+                bytes_down: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Runs the stack, then moves data between the sockets and their
+    /// streams and drops the flows that are over.
+    fn poll(&mut self) {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+        let mut over = vec![];
+        for (key, flow) in self.flows.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(flow.handle);
+            while socket.can_recv() {
+                let permit = match flow.sender.as_ref().map(Sender::try_reserve) {
+                    Some(Ok(permit)) => Some(permit),
+                    Some(Err(TrySendError::Full(()))) => break,
+                    // nobody takes it anymore
+                    _ => None,
+                };
+                let Ok(data) = socket.recv(|buf| (buf.len(), buf.to_vec())) else {
+                    break;
+                };
+                flow.bytes_up += data.len() as u64;
+                if let Some(permit) = permit {
+                    permit.send(data);
+                }
+            }
+            if !flow.pending.is_empty() {
+                let sent = socket.send_slice(&flow.pending).unwrap_or(0);
+                flow.pending.drain(..sent);
+            }
+            if flow.finishing && flow.pending.is_empty() {
+                flow.finishing = false;
+                socket.close();
+            }
+
+            let state = match socket.state() {
+                // never got its SYN
+                tcp::State::Listen => None,
+                tcp::State::SynReceived => Some(FlowState::SynReceived),
+                tcp::State::TimeWait => None,
+                // aborted ones are kept until their RST is out
+                tcp::State::Closed if socket.remote_endpoint().is_none() => None,
+                _ => Some(FlowState::Established),
+            };
+            match state {
+                Some(state) if state != flow.state => {
+                    self.metrics.flow_moved(flow.state, state);
+                    flow.state = state;
+                }
+                Some(_) => {}
+                None => over.push(*key),
+            }
+
+            // FIN from the kernel: the stream is finished once everything
+            // before it went out, while the server may go on sending
+            let kernel_done = !matches!(
+                socket.state(),
+                tcp::State::Listen
+                    | tcp::State::SynReceived
+                    | tcp::State::Established
+                    | tcp::State::FinWait1
+                    | tcp::State::FinWait2
+            );
+            if kernel_done && !socket.can_recv() && flow.sender.take().is_some() {
+                log::debug!("kernel closed {:?}", key);
+            }
+        }
+
+        // closed both ways, reset or timed out; a stream still open is
+        // given up on
+        for key in over {
+            if let Some(flow) = self.flows.remove(&key) {
+                self.sockets.remove(flow.handle);
+                self.metrics.flow_closed(flow.state);
+                flow.notify.notify_one();
+            }
+        }
+
+        // sends what the sockets just got
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+    }
+
+    /// Takes what the stream of flow `key` sent, `None` if its sender is
+    /// gone.
+    fn respond(&mut self, key: FlowKey, response: Option<Response>) {
+        let Some(flow) = self.flows.get_mut(&key) else {
+            return;
+        };
+        match response {
+            Some(response) if response.reset => {
+                log::debug!("resetting flow {:?}", key);
+                self.sockets.get_mut::<tcp::Socket>(flow.handle).abort();
+            }
+            Some(response) if !response.payload.is_empty() => {
+                flow.bytes_down += response.payload.len() as u64;
+                flow.pending.extend_from_slice(&response.payload);
+            }
+            // the server finished its side
+            response => {
+                if response.is_none() {
+                    flow.responses = None;
+                }
+                flow.finishing = true;
+            }
+        }
+    }
+
+    pub(crate) async fn loop_read(&mut self) {
+        let mut buf = [0u8; 65534];
+        let mut response = None;
+
+        loop {
+            self.poll();
+            if response.is_none() {
+                response = self.device.tx.pop_front();
+            }
+            let pending = response.as_ref().map(Vec::len);
+            let delay = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map_or(IDLE, Duration::from);
+
+            tokio::select! {
+                io_result = self.tun.do_io(&mut buf, &mut response) => {
+                    let packet = match io_result {
+                        Ok(read_size) => {
+                            self.metrics.packet_read(read_size);
+                            &buf[..read_size]
+                        },
+                        Err(err) => {
+                            log::error!("error from tun io, {}", err);
+                            return;
+                        }
+                    };
+                    match Ipv4HeaderSlice::from_slice(packet) {
+                        Ok(header) => {
+                            if let Err(err) = self.process_packet(header, packet) {
+                                log::warn!("accepting flow: {:#}", err);
+                            }
+                        },
+                        Err(err) => {
+                            log::warn!("error parsing ipv4 packet, skipping it: {}", err);
+                            self.metrics.dropped(Dropped::Unparseable);
+                        },
+                    };
+                },
+                Ok(()) = self.mtu.changed() => {
+                    let mtu = *self.mtu.borrow_and_update();
+                    // new segments and the MSS of new flows follow it
+                    self.device.mtu = mtu as usize;
+                    if let Err(err) = self.tun.set_mtu(mtu) {
+                        log::warn!("setting tunnel MTU to {}: {:#}", mtu, err);
+                    }
+                },
+                Some(query) = self.flow_query_stream.recv() => {
+                    let _ = query.send(self.flows());
+                },
+                ready = ready(&mut self.flows, &self.sockets) => match ready {
+                    FlowsReady::Writable => {},
+                    FlowsReady::Responses(responses) => {
+                        for (key, response) in responses {
+                            self.respond(key, response);
+                        }
+                    },
+                },
+                _ = tokio::time::sleep(delay) => {},
+            };
+
+            if let Some(len) = pending
+                && response.is_none()
+            {
+                self.metrics.packet_written(len);
+            }
+        }
+    }
+}
+
+/// What the flows are ready for.
+enum FlowsReady {
+    /// A stream that was full can take data again.
+    Writable,
+    /// Data from the streams of flows whose sockets have room, or `None`
+    /// for streams that are done. One each, so busy flows don't starve the
+    /// others.
+    Responses(Vec<(FlowKey, Option<Response>)>),
+}
+
+/// Waits until a flow can move data on, in either direction.
+async fn ready(flows: &mut HashMap<FlowKey, Flow>, sockets: &SocketSet<'_>) -> FlowsReady {
+    let mut full = vec![];
+    let mut open = vec![];
+    for (key, flow) in flows.iter_mut() {
+        if let Some(sender) = flow.sender.as_ref().filter(|sender| sender.capacity() == 0) {
+            full.push(Box::pin(sender.reserve()));
+        }
+        let socket = sockets.get::<tcp::Socket>(flow.handle);
+        let room = flow.pending.is_empty() && socket.send_queue() < socket.send_capacity();
+        if let Some(responses) = flow.responses.as_mut().filter(|_| room) {
+            open.push((*key, responses));
+        }
+    }
+    poll_fn(|cx| {
+        if full
+            .iter_mut()
+            .any(|reserve| reserve.as_mut().poll(cx).is_ready())
+        {
+            return Poll::Ready(FlowsReady::Writable);
+        }
+        let responses: Vec<_> = open
+            .iter_mut()
+            .filter_map(|(key, responses)| match responses.poll_recv(cx) {
+                Poll::Ready(response) => Some((*key, response)),
+                Poll::Pending => None,
+            })
+            .collect();
+        if responses.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(FlowsReady::Responses(responses))
+        }
+    })
+    .await
+}
+
+/// The stack's side of the TUN: the packet read last, and the packets the
+/// stack sent that are not written yet.
+struct Queue {
+    rx: Option<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.take()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::TcpOptionElement;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::tunnel::craft_ipv4_tcp;
+
+    /// Never has packets, the test hands them to the stack itself.
+    struct NoTun;
+
+    impl L3Stream for NoTun {
+        fn set_mtu(&mut self, _: u16) -> Result<()> {
+            Ok(())
+        }
+
+        async fn do_io(&mut self, _: &mut [u8], _: &mut Option<Vec<u8>>) -> Result<usize> {
+            std::future::pending().await
+        }
+    }
+
+    /// Keeps what the kernel sends on each flow.
+    #[derive(Default)]
+    struct Upstream {
+        flows: Vec<(Receiver<Vec<u8>>, Arc<Notify>)>,
+    }
+
+    impl VPNUpstream for Upstream {
+        fn new_connection(
+            &mut self,
+            _: FlowKey,
+            _: Option<String>,
+            rx: Receiver<Vec<u8>>,
+            _: Sender<Response>,
+            _: Option<oneshot::Sender<Result<()>>>,
+        ) -> Result<Arc<Notify>> {
+            let notify = Arc::new(Notify::new());
+            self.flows.push((rx, notify.clone()));
+            Ok(notify)
+        }
+    }
+
+    fn stack() -> Stack<NoTun, Upstream> {
+        let (_, mtu) = watch::channel(1180);
+        Stack::new(NoTun, Upstream::default(), mtu, Arc::default())
+    }
+
+    /// Feeds `packet` to the stack and returns what it sent back.
+    fn send(stack: &mut Stack<NoTun, Upstream>, packet: Vec<u8>) -> Vec<Vec<u8>> {
+        let header = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+        stack.process_packet(header, &packet).unwrap();
+        stack.poll();
+        stack.device.tx.drain(..).collect()
+    }
+
+    fn tcp(packet: &[u8]) -> TcpHeaderSlice<'_> {
+        let ip_hdr = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        TcpHeaderSlice::from_slice(&packet[ip_hdr.slice().len()..]).unwrap()
+    }
+
+    /// Opens a flow from `kernel` with initial sequence number 100 and
+    /// returns the stack's next sequence number.
+    fn open(stack: &mut Stack<NoTun, Upstream>, kernel: SocketAddr, remote: SocketAddr) -> u32 {
+        let replies = send(
+            stack,
+            craft_ipv4_tcp(kernel, remote, 100, 0, 0x02, &[], &[]),
+        );
+        let next = tcp(&replies[0]).sequence_number().wrapping_add(1);
+        send(
+            stack,
+            craft_ipv4_tcp(kernel, remote, 101, next, 0x10, &[], &[]),
+        );
+        next
+    }
+
+    #[test]
+    fn test_stack() {
+        let mut stack = stack();
+        let kernel = "10.0.0.2:40000".parse().unwrap();
+        let remote = "1.1.1.1:443".parse().unwrap();
+
+        let mss = [TcpOptionElement::MaximumSegmentSize(1460)];
+        let replies = send(
+            &mut stack,
+            craft_ipv4_tcp(kernel, remote, 100, 0, 0x02, &mss, &[]),
+        );
+        assert_eq!(replies.len(), 1);
+        let ip_hdr = Ipv4HeaderSlice::from_slice(&replies[0]).unwrap();
+        let syn_ack = tcp(&replies[0]);
+        assert_eq!(ip_hdr.source(), [1, 1, 1, 1]);
+        assert!(syn_ack.syn() && syn_ack.ack());
+        assert_eq!(syn_ack.acknowledgment_number(), 101);
+        // what fits the tunnel MTU
+        assert!(
+            syn_ack
+                .options_iterator()
+                .any(|option| matches!(option, Ok(TcpOptionElement::MaximumSegmentSize(1140))))
+        );
+
+        let next = syn_ack.sequence_number().wrapping_add(1);
+        send(
+            &mut stack,
+            craft_ipv4_tcp(kernel, remote, 101, next, 0x18, &[], b"hello"),
+        );
+        assert_eq!(stack.upstream.flows[0].0.try_recv().unwrap(), b"hello");
+        assert_eq!(stack.flows()[0].state, FlowState::Established);
+
+        let key = (Ipv4Addr::new(1, 1, 1, 1), 443, 40000);
+        let world = Response {
+            payload: b"world".to_vec(),
+            flow_key: key,
+            reset: false,
+        };
+        stack.respond(key, Some(world));
+        stack.poll();
+        assert!(
+            stack
+                .device
+                .tx
+                .iter()
+                .any(|packet| packet.ends_with(b"world"))
+        );
+
+        // a stream that does not keep up leaves the data in the socket
+        let mut seq = 106;
+        for _ in 0..=FLOW_QUEUE {
+            let packet = craft_ipv4_tcp(kernel, remote, seq, next + 5, 0x18, &[], b"more");
+            send(&mut stack, packet);
+            seq += 4;
+        }
+        let socket = stack.sockets.get::<tcp::Socket>(stack.flows[&key].handle);
+        assert_eq!(socket.recv_queue(), 4);
+        stack.upstream.flows[0].0.try_recv().unwrap();
+        stack.poll();
+        let socket = stack.sockets.get::<tcp::Socket>(stack.flows[&key].handle);
+        assert_eq!(socket.recv_queue(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stack_close() {
+        let mut stack = stack();
+        let kernel = "10.0.0.2:40000".parse().unwrap();
+        let remote = "1.1.1.1:443".parse().unwrap();
+        let key = (Ipv4Addr::new(1, 1, 1, 1), 443, 40000);
+        let next = open(&mut stack, kernel, remote);
+
+        // the kernel is done sending, the server is not
+        send(
+            &mut stack,
+            craft_ipv4_tcp(kernel, remote, 101, next, 0x11, &[], &[]),
+        );
+        let (rx, notify) = &mut stack.upstream.flows[0];
+        assert!(rx.try_recv().is_err() && rx.is_closed());
+        let notified = tokio::time::timeout(Duration::from_millis(10), notify.notified());
+        assert!(notified.await.is_err());
+        let bye = Response {
+            payload: b"bye".to_vec(),
+            flow_key: key,
+            reset: false,
+        };
+        stack.respond(key, Some(bye));
+        stack.respond(key, Some(Response::end(key)));
+        stack.poll();
+        let replies: Vec<_> = stack.device.tx.drain(..).collect();
+        assert!(replies[0].ends_with(b"bye"));
+        assert!(replies.iter().any(|packet| tcp(packet).fin()));
+
+        // its ACK of our FIN ends the flow
+        let fin = next.wrapping_add(4);
+        send(
+            &mut stack,
+            craft_ipv4_tcp(kernel, remote, 102, fin, 0x10, &[], &[]),
+        );
+        assert!(stack.flows().is_empty());
+
+        // a reset aborts the stream
+        let kernel = "10.0.0.2:40001".parse().unwrap();
+        let next = open(&mut stack, kernel, remote);
+        send(
+            &mut stack,
+            craft_ipv4_tcp(kernel, remote, 101, next, 0x14, &[], &[]),
+        );
+        assert!(stack.flows().is_empty());
+        let notify = stack.upstream.flows[1].1.clone();
+        let notified = tokio::time::timeout(Duration::from_millis(10), notify.notified());
+        assert!(notified.await.is_ok());
+    }
+}
@@ -1,3 +1,8 @@
 pub mod aead;
+pub mod local;
+pub mod metrics;
+pub mod pki;
+pub mod resume;
 pub mod stream;
+pub mod transport;
 pub use chacha20poly1305::Key;
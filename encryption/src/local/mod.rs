@@ -0,0 +1,70 @@
+use std::{
+    fs::{DirBuilder, Permissions},
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::Path,
+};
+
+use anyhow::{Context, Result, anyhow};
+use tokio::net::UnixListener;
+
+/// Binds a Unix socket at `path` that only its owner can connect to,
+/// replacing a socket left over from a previous run.
+///
+/// The socket is bound in a fresh 0700 directory next to `path`, and moved
+/// into place once its mode is set, so nobody can connect in between.
+pub fn bind_private(path: &Path) -> Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing stale socket {}", path.display()));
+        }
+        _ => {}
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a socket path", path.display()))?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("creating {}", staging.display()))?;
+
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged)
+        .with_context(|| format!("binding socket {}", path.display()))
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path)
+                .with_context(|| format!("moving socket to {}", path.display()))?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_dir_all(&staging);
+    bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_private() {
+        let dir = std::env::temp_dir().join(format!("bind-private-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        std::fs::write(&path, b"stale").unwrap();
+
+        let listener = bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+        // nothing but the socket is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
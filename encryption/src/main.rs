@@ -1,17 +1,84 @@
-use std::{fs::File, io::Write};
+use std::{path::Path, time::Duration};
 
-use encryption::aead::generate_key;
+use anyhow::{Context, Result, anyhow};
+use encryption::{
+    aead::generate_key,
+    pki::{Ca, write_private},
+};
 
-fn main() {
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const CA_VALIDITY_DAYS: u32 = 3650;
+const SERVER_VALIDITY_DAYS: u32 = 365;
+const CRL_VALIDITY_DAYS: u32 = 30;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => self_signed(),
+        Some("ca") => {
+            let name = args.get(1).map_or("vpn ca", String::as_str);
+            let ca = Ca::generate(name, DAY * CA_VALIDITY_DAYS)?;
+            write("ca.pem", ca.cert_pem())?;
+            write_private(Path::new("ca.key"), ca.key_pem().as_bytes())?;
+            write("crl.pem", &ca.crl(&[], DAY * CRL_VALIDITY_DAYS)?)
+        }
+        Some("server-cert") if args.len() > 1 => {
+            let (issued, key) = load_ca()?.issue_server(&args[1..], DAY * SERVER_VALIDITY_DAYS)?;
+            println!("issued server certificate {}", issued.serial);
+            write("server.pem", &issued.cert_pem)?;
+            write_private(Path::new("server.key"), key.as_bytes())
+        }
+        Some("revoke") if args.len() > 1 => {
+            let mut revoked = read_revoked()?;
+            revoked.extend_from_slice(&args[1..]);
+            // a serial that does not parse must not end up in `revoked`
+            let crl = crl(&revoked)?;
+            write("revoked", &(revoked.join("\n") + "\n"))?;
+            write("crl.pem", &crl)
+        }
+        Some("crl") => write("crl.pem", &crl(&read_revoked()?)?),
+        Some(_) => Err(anyhow!(
+            "usage: encryption [ca [name] | server-cert <name>... | revoke <serial>... | crl]"
+        )),
+    }
+}
+
+/// Self-signed `localhost` certificate and AEAD key for the docker demo.
+fn self_signed() -> Result<()> {
     let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
-    let mut key_file = File::create("key.pem").unwrap();
-    key_file
-        .write_all(cert.signing_key.serialize_pem().as_bytes())
-        .unwrap();
-    let mut cert_file = File::create("cert.pem").unwrap();
-    cert_file.write_all(cert.cert.pem().as_bytes()).unwrap();
-
-    let aead_key = generate_key();
-    let mut aead_file = File::create("xchacha20.key").unwrap();
-    aead_file.write_all(&aead_key).unwrap();
+    write_private(
+        Path::new("key.pem"),
+        cert.signing_key.serialize_pem().as_bytes(),
+    )?;
+    write("cert.pem", &cert.cert.pem())?;
+
+    write_private(Path::new("xchacha20.key"), &generate_key())
+}
+
+fn load_ca() -> Result<Ca> {
+    let cert = std::fs::read_to_string("ca.pem").context("reading ca.pem, run `encryption ca`")?;
+    let key = std::fs::read_to_string("ca.key").context("reading ca.key")?;
+    Ca::load(&cert, &key)
+}
+
+fn read_revoked() -> Result<Vec<String>> {
+    match std::fs::read_to_string("revoked") {
+        Ok(raw) => Ok(raw
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// CRLs expire, re-run `encryption crl` before `CRL_VALIDITY_DAYS` pass.
+fn crl(revoked: &[String]) -> Result<String> {
+    load_ca()?.crl(revoked, DAY * CRL_VALIDITY_DAYS)
+}
+
+fn write(path: &str, contents: &str) -> Result<()> {
+    std::fs::write(Path::new(path), contents).with_context(|| format!("writing {}", path))
 }
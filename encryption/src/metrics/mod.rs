@@ -0,0 +1,87 @@
+//! The HTTP endpoint Prometheus scrapes, shared by client and server. Only
+//! `GET /metrics` is answered, one request per connection.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding metrics listener {}", addr))
+}
+
+/// Escapes `value` for use between the quotes of a label, names of users
+/// come from config files and the admin API.
+pub fn label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Answers scrapes on `listener` with the text `render` returns.
+pub fn serve<F>(listener: TcpListener, render: F) -> Result<()>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    log::info!("metrics on http://{}/metrics", listener.local_addr()?);
+    let render = Arc::new(render);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::warn!("metrics: {}", e);
+                    continue;
+                }
+            };
+            let render = render.clone();
+            tokio::spawn(async move {
+                let scrape =
+                    tokio::time::timeout(Duration::from_secs(5), respond(stream, &*render));
+                if let Ok(Err(e)) = scrape.await {
+                    log::warn!("metrics: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    render: &(dyn Fn() -> String + Sync),
+) -> std::io::Result<()> {
+    let mut request = vec![0u8; 2048];
+    let mut len = 0;
+    while !request[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < request.len() {
+        let n = stream.read(&mut request[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let response = if request[..len].starts_with(b"GET /metrics ") {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
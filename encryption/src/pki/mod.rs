@@ -0,0 +1,222 @@
+use aead::{OsRng, rand_core::RngCore};
+use anyhow::{Context, Result, anyhow};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertificateRevocationListParams,
+    CertificateSigningRequestParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyIdMethod, KeyPair, KeyUsagePurpose, RevokedCertParams, SerialNumber,
+};
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path, time::Duration};
+use time::OffsetDateTime;
+
+/// Private certificate authority issuing server and client certificates.
+pub struct Ca {
+    cert_pem: String,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+/// A client's certificate signing request, parsed and its signature checked.
+pub struct ClientCsr(CertificateSigningRequestParams);
+
+impl ClientCsr {
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        Ok(Self(CertificateSigningRequestParams::from_der(
+            &der.into(),
+        )?))
+    }
+}
+
+/// A freshly signed certificate and its serial, which is what revocation refers to.
+pub struct Issued {
+    pub cert_pem: String,
+    pub serial: String,
+}
+
+impl Ca {
+    pub fn generate(name: &str, validity: Duration) -> Result<Self> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = common_name(name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        set_validity(&mut params, validity);
+
+        let key = KeyPair::generate()?;
+        let cert = params.self_signed(&key)?;
+        Self::load(&cert.pem(), &key.serialize_pem())
+    }
+
+    pub fn load(cert_pem: &str, key_pem: &str) -> Result<Self> {
+        let key = KeyPair::from_pem(key_pem)?;
+        Ok(Self {
+            cert_pem: cert_pem.to_string(),
+            issuer: Issuer::from_ca_cert_pem(cert_pem, key)?,
+        })
+    }
+
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    pub fn key_pem(&self) -> String {
+        self.issuer.key().serialize_pem()
+    }
+
+    /// Server certificate for the given DNS names or IP addresses; returns the
+    /// chain (leaf followed by the CA) and the private key.
+    pub fn issue_server(&self, names: &[String], validity: Duration) -> Result<(Issued, String)> {
+        let mut params = CertificateParams::new(names.to_vec())?;
+        params.distinguished_name = common_name(names.first().map_or("vpn", String::as_str));
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+        let key = KeyPair::generate()?;
+        let issued = self.sign(params, &key, validity)?;
+        Ok((issued, key.serialize_pem()))
+    }
+
+    /// Client certificate for `user` over the key in `csr`. Whatever subject
+    /// or extensions the CSR asks for are ignored.
+    pub fn sign_client_csr(
+        &self,
+        csr: &ClientCsr,
+        user: &str,
+        validity: Duration,
+    ) -> Result<Issued> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = common_name(user);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+
+        self.sign(params, &csr.0.public_key, validity)
+    }
+
+    fn sign(
+        &self,
+        mut params: CertificateParams,
+        key: &impl rcgen::PublicKeyData,
+        validity: Duration,
+    ) -> Result<Issued> {
+        let mut serial = [0u8; 16];
+        OsRng.fill_bytes(&mut serial);
+        serial[0] &= 0x7f; // keep it positive
+        params.serial_number = Some(SerialNumber::from_slice(&serial));
+        set_validity(&mut params, validity);
+
+        let cert = params.signed_by(key, &self.issuer)?;
+        Ok(Issued {
+            cert_pem: format!("{}{}", cert.pem(), self.cert_pem),
+            serial: hex_serial(&serial),
+        })
+    }
+
+    /// CRL listing `revoked` serials (hex, as in [`Issued::serial`]).
+    pub fn crl(&self, revoked: &[String], validity: Duration) -> Result<String> {
+        let now = OffsetDateTime::now_utc();
+        let revoked_certs = revoked
+            .iter()
+            .map(|serial| {
+                Ok(RevokedCertParams {
+                    serial_number: SerialNumber::from_slice(&parse_hex(serial)?),
+                    revocation_time: now,
+                    reason_code: None,
+                    invalidity_date: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let params = CertificateRevocationListParams {
+            this_update: now,
+            next_update: now + validity,
+            crl_number: SerialNumber::from_slice(&now.unix_timestamp().to_be_bytes()),
+            issuing_distribution_point: None,
+            revoked_certs,
+            key_identifier_method: KeyIdMethod::Sha256,
+        };
+        Ok(params.signed_by(&self.issuer)?.pem()?)
+    }
+}
+
+/// Writes a private key readable by its owner only, whatever the umask.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Key pair and DER encoded CSR a client sends to enroll.
+pub fn client_csr(name: &str) -> Result<(Vec<u8>, String)> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = common_name(name);
+    let key = KeyPair::generate()?;
+    let csr = params.serialize_request(&key)?;
+    Ok((csr.der().to_vec(), key.serialize_pem()))
+}
+
+fn common_name(name: &str) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, name);
+    dn
+}
+
+fn set_validity(params: &mut CertificateParams, validity: Duration) {
+    let now = OffsetDateTime::now_utc();
+    // tolerate some clock skew between issuer and verifier
+    params.not_before = now - Duration::from_secs(300);
+    params.not_after = now + validity;
+}
+
+fn hex_serial(serial: &[u8]) -> String {
+    serial.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(serial: &str) -> Result<Vec<u8>> {
+    if !serial.len().is_multiple_of(2) || !serial.is_ascii() {
+        return Err(anyhow!("invalid serial {:?}", serial));
+    }
+    (0..serial.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&serial[i..i + 2], 16)
+                .map_err(|_| anyhow!("invalid serial {:?}", serial))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use x509_parser::prelude::{FromDer, X509Certificate, parse_x509_pem};
+
+    use super::*;
+
+    fn leaf(pem: &str) -> Vec<u8> {
+        parse_x509_pem(pem.as_bytes()).unwrap().1.contents
+    }
+
+    #[test]
+    fn test_client_enrollment() {
+        let ca = Ca::generate("test ca", Duration::from_secs(86400)).unwrap();
+        let (csr, _key) = client_csr("whatever the client asks for").unwrap();
+        let csr = ClientCsr::from_der(&csr).unwrap();
+        let issued = ca
+            .sign_client_csr(&csr, "alice", Duration::from_secs(86400))
+            .unwrap();
+
+        let der = leaf(&issued.cert_pem);
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+        let cn = cert.subject().iter_common_name().next().unwrap();
+        assert_eq!(cn.as_str().unwrap(), "alice");
+        assert_eq!(cert.issuer().to_string(), "CN=test ca");
+        assert_eq!(cert.raw_serial_as_string().replace(':', ""), issued.serial);
+
+        // the CA reloads from its own PEMs
+        let ca = Ca::load(ca.cert_pem(), &ca.key_pem()).unwrap();
+        assert!(ca.crl(&[issued.serial], Duration::from_secs(86400)).is_ok());
+    }
+}
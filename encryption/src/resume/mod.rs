@@ -0,0 +1,112 @@
+//! Session resumption, shared by client and server: the SOCKS5 command
+//! extensions and the buffer that unconfirmed stream data is replayed from.
+//!
+//! - `SESSION`: `|ver, 0x80, flags, rsv, id(16), window(4)|`, an all-zero id
+//!   asks for a new session. Reply `|ver, 0x00, resumed, id(16), window(4)|`.
+//!   `window` is the sender's stream receive window. With `SESSION_JOIN`
+//!   in `flags` the id is picked by the client, the session is created under
+//!   it if there is none, and the connection is added to the session's
+//!   connections instead of replacing them.
+//! - `CONNECT` with a flow id: a regular CONNECT request with command `0x81`
+//!   and a big-endian `u32` flow id after the port.
+//! - `RESUME`: `|ver, 0x82, rsv, rsv, flow(4), received(8)|` where `received`
+//!   counts the bytes the client got from the target. Reply
+//!   `|ver, status, rsv, received(8)|` with the bytes of client data that
+//!   reached the target, followed by the target data the client missed.
+
+use std::collections::VecDeque;
+
+pub const CMD_SESSION: u8 = 0x80;
+pub const CMD_CONNECT: u8 = 0x81;
+pub const CMD_RESUME: u8 = 0x82;
+
+/// `SESSION` flag of connections over several paths that share a session.
+pub const SESSION_JOIN: u8 = 0x01;
+
+pub type SessionId = [u8; 16];
+
+/// Stream receive window unless configured otherwise. The receiver never
+/// lets more unread data than its window be in flight on a stream, which
+/// bounds what a sender may have to replay.
+pub const DEFAULT_STREAM_WINDOW: u32 = 1024 * 1024;
+
+/// The largest stream receive window a peer may announce, which bounds the
+/// replay buffers kept for it.
+pub const MAX_STREAM_WINDOW: u32 = 64 * 1024 * 1024;
+
+/// Room for a chunk held between reading and writing, on top of the window.
+const REPLAY_SLACK: usize = 64 * 1024;
+
+/// The most recent bytes written to a stream, addressed by their offset
+/// from the start of the flow.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    data: VecDeque<u8>,
+    /// Offset of `data[0]`.
+    start: u64,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    /// Keeps enough to replay to a peer whose stream receive window is
+    /// `window`.
+    pub fn new(window: u32) -> Self {
+        Self {
+            data: VecDeque::new(),
+            start: 0,
+            capacity: window as usize + REPLAY_SLACK,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        let excess = self.data.len().saturating_sub(self.capacity);
+        self.data.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// The most the buffer grows to.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Offset one past the last byte pushed.
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Everything from `offset` on, or `None` if that is no longer (or not
+    /// yet) in the buffer.
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start || offset > self.end() {
+            return None;
+        }
+        Some(
+            self.data
+                .range((offset - self.start) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut buffer = ReplayBuffer::new(DEFAULT_STREAM_WINDOW);
+        let capacity = DEFAULT_STREAM_WINDOW as usize + REPLAY_SLACK;
+        buffer.push(b"hello ");
+        buffer.push(b"world");
+        assert_eq!(buffer.since(6).unwrap(), b"world");
+        assert_eq!(buffer.since(11).unwrap(), b"");
+        assert_eq!(buffer.since(12), None);
+
+        buffer.push(&vec![0u8; capacity]);
+        assert_eq!(buffer.end(), 11 + capacity as u64);
+        assert_eq!(buffer.since(10), None);
+        assert_eq!(buffer.since(11).unwrap().len(), capacity);
+    }
+}
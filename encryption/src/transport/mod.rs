@@ -0,0 +1,100 @@
+//! QUIC transport settings, shared by client and server. Each side applies
+//! its own to the connections it makes or accepts: only the stream receive
+//! window is announced to the peer, in `SESSION`.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow};
+use quinn::{
+    VarInt,
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+};
+use serde::Deserialize;
+
+use crate::resume::{DEFAULT_STREAM_WINDOW, MAX_STREAM_WINDOW};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    pub congestion: Congestion,
+    /// RTT assumed until the first sample, raise it for satellite links.
+    #[serde(with = "humantime_serde")]
+    pub initial_rtt: Duration,
+    /// Unread bytes the peer may send on one stream. The peer keeps this
+    /// much of every flow to resume it.
+    pub stream_receive_window: u32,
+    /// Unread bytes the peer may send on all streams together, unlimited if
+    /// not set.
+    pub receive_window: Option<u64>,
+    /// Unacknowledged bytes sent to the peer on all streams together.
+    pub send_window: u64,
+    /// Streams the peer may open at once.
+    pub max_concurrent_streams: u32,
+    /// Unread datagram bytes, `None` disables receiving datagrams.
+    pub datagram_receive_buffer: Option<usize>,
+    pub datagram_send_buffer: usize,
+    /// UDP payload size used before path MTU discovery finds a larger one.
+    pub initial_mtu: u16,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Congestion {
+    #[default]
+    Cubic,
+    NewReno,
+    /// Does not back off on random loss, which suits lossy links.
+    Bbr,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            congestion: Congestion::default(),
+            initial_rtt: Duration::from_millis(333),
+            stream_receive_window: DEFAULT_STREAM_WINDOW,
+            receive_window: None,
+            send_window: 10_000_000,
+            max_concurrent_streams: 100,
+            datagram_receive_buffer: Some(1_250_000),
+            datagram_send_buffer: 1024 * 1024,
+            initial_mtu: 1200,
+        }
+    }
+}
+
+/// The quinn transport config for `config`.
+pub fn transport_config(config: &TransportConfig) -> Result<quinn::TransportConfig> {
+    if config.stream_receive_window > MAX_STREAM_WINDOW {
+        return Err(anyhow!(
+            "stream_receive_window is larger than {}",
+            MAX_STREAM_WINDOW
+        ));
+    }
+    if config.initial_mtu < 1200 {
+        return Err(anyhow!("initial_mtu is below the QUIC minimum of 1200"));
+    }
+    let mut transport = quinn::TransportConfig::default();
+    match config.congestion {
+        Congestion::Cubic => {
+            transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+        }
+        Congestion::NewReno => {
+            transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+        }
+        Congestion::Bbr => transport.congestion_controller_factory(Arc::new(BbrConfig::default())),
+    };
+    transport
+        .initial_rtt(config.initial_rtt)
+        .stream_receive_window(config.stream_receive_window.into())
+        .receive_window(match config.receive_window {
+            Some(window) => VarInt::from_u64(window)?,
+            None => VarInt::MAX,
+        })
+        .send_window(config.send_window)
+        .max_concurrent_bidi_streams(config.max_concurrent_streams.into())
+        .datagram_receive_buffer_size(config.datagram_receive_buffer)
+        .datagram_send_buffer_size(config.datagram_send_buffer)
+        .initial_mtu(config.initial_mtu);
+    Ok(transport)
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use quinn::VarInt;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AccountingConfig, Period};
+
+/// Application error code a session is closed with once its user is over quota.
+pub(crate) const QUOTA_EXCEEDED: VarInt = VarInt::from_u32(0x51);
+
+/// Counters of one user for one accounting period, as persisted.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Record {
+    period: String,
+    /// Client to target bytes.
+    up: u64,
+    /// Target to client bytes.
+    down: u64,
+    connections: u64,
+    /// Summed duration of finished sessions.
+    session_secs: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoreFile {
+    #[serde(default)]
+    user: HashMap<String, Record>,
+}
+
+#[derive(Serialize)]
+struct HistoryRecord<'a> {
+    user: &'a str,
+    #[serde(flatten)]
+    record: &'a Record,
+}
+
+/// Live counters of one user, updated by the relay loops.
+#[derive(Default)]
+pub(crate) struct Usage {
+    period: Mutex<String>,
+    pub up: AtomicU64,
+    pub down: AtomicU64,
+    connections: AtomicU64,
+    session_secs: AtomicU64,
+}
+
+impl Usage {
+    fn from_record(record: &Record) -> Self {
+        Self {
+            period: Mutex::new(record.period.clone()),
+            up: AtomicU64::new(record.up),
+            down: AtomicU64::new(record.down),
+            connections: AtomicU64::new(record.connections),
+            session_secs: AtomicU64::new(record.session_secs),
+        }
+    }
+
+    fn snapshot(&self, period: String) -> Record {
+        Record {
+            period,
+            up: self.up.load(Ordering::Relaxed),
+            down: self.down.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            session_secs: self.session_secs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts a new period if `current` differs, returning the finished one.
+    fn roll(&self, current: &str) -> Option<Record> {
+        let mut period = self.period.lock().unwrap();
+        if *period == current {
+            return None;
+        }
+        let finished = Record {
+            period: std::mem::replace(&mut *period, current.to_string()),
+            up: self.up.swap(0, Ordering::Relaxed),
+            down: self.down.swap(0, Ordering::Relaxed),
+            connections: self.connections.swap(0, Ordering::Relaxed),
+            session_secs: self.session_secs.swap(0, Ordering::Relaxed),
+        };
+        (!finished.period.is_empty()).then_some(finished)
+    }
+
+    fn used(&self) -> u64 {
+        self.up.load(Ordering::Relaxed) + self.down.load(Ordering::Relaxed)
+    }
+}
+
+/// One authenticated connection. Its duration is booked when it is dropped.
+pub(crate) struct Session {
+    pub usage: Arc<Usage>,
+    started: Instant,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.usage
+            .session_secs
+            .fetch_add(self.started.elapsed().as_secs(), Ordering::Relaxed);
+    }
+}
+
+/// Per-user traffic accounting with daily or monthly quotas.
+pub(crate) struct Accounting {
+    config: AccountingConfig,
+    users: Mutex<HashMap<String, Arc<Usage>>>,
+    history: Option<Mutex<File>>,
+}
+
+impl Accounting {
+    pub(crate) fn open(config: &AccountingConfig) -> Result<Self> {
+        let users = match &config.store {
+            Some(path) => read_store(path)?
+                .user
+                .iter()
+                .map(|(name, record)| (name.clone(), Arc::new(Usage::from_record(record))))
+                .collect(),
+            None => HashMap::new(),
+        };
+        let history = match &config.history {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening accounting history {}", path.display()))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            config: config.clone(),
+            users: Mutex::new(users),
+            history,
+        })
+    }
+
+    fn usage(&self, user: &str) -> Arc<Usage> {
+        let usage = self
+            .users
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_default()
+            .clone();
+        self.roll(user, &usage);
+        usage
+    }
+
+    fn roll(&self, user: &str, usage: &Usage) {
+        let Some(finished) = usage.roll(&current_period(self.config.period)) else {
+            return;
+        };
+        let Some(history) = &self.history else {
+            return;
+        };
+        let mut line = serde_json::to_string(&HistoryRecord {
+            user,
+            record: &finished,
+        })
+        .expect("serializing accounting record");
+        line.push('\n');
+        if let Err(e) = history.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("writing accounting history: {}", e);
+        }
+    }
+
+    pub(crate) fn session(&self, user: &str) -> Session {
+        let usage = self.usage(user);
+        usage.connections.fetch_add(1, Ordering::Relaxed);
+        Session {
+            usage,
+            started: Instant::now(),
+        }
+    }
+
+    /// Whether `user` has used up this period's quota.
+    pub(crate) fn exceeded(&self, user: &str) -> bool {
+        let quota = self.config.users.get(user).copied().or(self.config.quota);
+        quota.is_some_and(|quota| self.usage(user).used() >= quota)
+    }
+
+    pub(crate) fn close_sessions(&self) -> bool {
+        self.config.close_sessions
+    }
+
+    /// Rolls periods over and rewrites the store.
+    pub(crate) fn flush(&self) -> Result<()> {
+        let users: Vec<_> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, usage)| (name.clone(), usage.clone()))
+            .collect();
+        let mut file = StoreFile::default();
+        for (name, usage) in users {
+            self.roll(&name, &usage);
+            let period = usage.period.lock().unwrap().clone();
+            file.user.insert(name, usage.snapshot(period));
+        }
+        let Some(path) = &self.config.store else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, toml::to_string(&file)?)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))
+    }
+
+    pub(crate) fn run(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.config.flush_interval).await;
+                if let Err(e) = self.flush() {
+                    log::error!("saving accounting: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+fn read_store(path: &Path) -> Result<StoreFile> {
+    match std::fs::read_to_string(path) {
+        Ok(raw) => toml::from_str(&raw).with_context(|| format!("parsing {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(StoreFile::default()),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// `YYYY-MM` or `YYYY-MM-DD` in UTC.
+fn current_period(period: Period) -> String {
+    let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    match period {
+        Period::Monthly => now[..7].to_string(),
+        Period::Daily => now[..10].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(store: &Path, quota: &str) -> Accounting {
+        let config: AccountingConfig = toml::from_str(&format!(
+            "store = {:?}\nperiod = \"daily\"\n{}",
+            store.display().to_string(),
+            quota
+        ))
+        .unwrap();
+        Accounting::open(&config).unwrap()
+    }
+
+    #[test]
+    fn test_quota_and_persistence() {
+        let store =
+            std::env::temp_dir().join(format!("vpn-accounting-{}.toml", std::process::id()));
+        let accounting = open(&store, "quota = 1000\nusers.bob = 10");
+
+        let session = accounting.session("alice");
+        session.usage.up.fetch_add(600, Ordering::Relaxed);
+        assert!(!accounting.exceeded("alice"));
+        session.usage.down.fetch_add(400, Ordering::Relaxed);
+        assert!(accounting.exceeded("alice"));
+        assert!(!accounting.exceeded("bob"));
+        accounting
+            .session("bob")
+            .usage
+            .up
+            .fetch_add(10, Ordering::Relaxed);
+        assert!(accounting.exceeded("bob"));
+        drop(session);
+        accounting.flush().unwrap();
+
+        let reopened = open(&store, "quota = 1000");
+        assert!(reopened.exceeded("alice"));
+        let usage = reopened.usage("alice");
+        assert_eq!(usage.up.load(Ordering::Relaxed), 600);
+        assert_eq!(usage.connections.load(Ordering::Relaxed), 1);
+        std::fs::remove_file(store).unwrap();
+    }
+
+    #[test]
+    fn test_period_rollover() {
+        let usage = Usage::from_record(&Record {
+            period: "2001-01-01".to_string(),
+            up: 5,
+            down: 7,
+            connections: 1,
+            session_secs: 60,
+        });
+        let finished = usage.roll("2001-01-02").unwrap();
+        assert_eq!(
+            (finished.period.as_str(), finished.up, finished.down),
+            ("2001-01-01", 5, 7)
+        );
+        assert_eq!(usage.used(), 0);
+        assert_eq!(usage.roll("2001-01-02"), None);
+    }
+}
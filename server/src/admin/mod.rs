@@ -0,0 +1,241 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use log::LevelFilter;
+use quinn::VarInt;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    sync::Notify,
+};
+
+use crate::config::Config;
+use crate::policy::{EgressFilter, Policy};
+use crate::{ServerState, server_config};
+
+/// Application error code a connection is closed with when an admin kicks it.
+pub(crate) const KICKED: VarInt = VarInt::from_u32(0x52);
+
+/// One command, sent as a single JSON line, e.g. `{"cmd":"kick","user":"alice"}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    List,
+    /// Closes one connection by id, or every connection of a user.
+    Kick {
+        id: Option<usize>,
+        user: Option<String>,
+    },
+    /// Refuses new connections and exits once the last client is gone.
+    Drain,
+    /// Re-reads the config file and the user database.
+    Reload,
+    LogLevel {
+        level: LevelFilter,
+    },
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clients: Option<Vec<Client>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kicked: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Client {
+    id: usize,
+    user: String,
+    remote: String,
+    streams: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+/// Local admin API: newline-delimited JSON over a Unix socket.
+pub(crate) struct Admin {
+    pub state: Arc<ServerState>,
+    pub endpoint: quinn::Endpoint,
+    pub config_path: PathBuf,
+    /// Notified when a drain has finished.
+    pub shutdown: Arc<Notify>,
+}
+
+impl Admin {
+    /// Binds `path`, replacing a socket left over from a previous run, and
+    /// makes it accessible to the owner only.
+    pub(crate) fn serve(self, path: &Path) -> Result<()> {
+        let listener = encryption::local::bind_private(path)?;
+        log::info!("admin API on {}", path.display());
+
+        let admin = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("admin: {}", e);
+                        continue;
+                    }
+                };
+                let admin = admin.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = admin.session(stream).await {
+                        log::warn!("admin: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    async fn session(&self, stream: UnixStream) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    log::info!("admin: {:?}", request);
+                    self.handle(request).unwrap_or_else(|e| Response {
+                        error: Some(format!("{:#}", e)),
+                        ..Response::default()
+                    })
+                }
+                Err(e) => Response {
+                    error: Some(format!("bad request: {}", e)),
+                    ..Response::default()
+                },
+            };
+            let mut out = serde_json::to_vec(&response).expect("serializing admin response");
+            out.push(b'\n');
+            writer.write_all(&out).await?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: Request) -> Result<Response> {
+        let mut response = Response {
+            ok: true,
+            ..Response::default()
+        };
+        match request {
+            Request::List => response.clients = Some(self.clients()),
+            Request::Kick { id, user } => {
+                if id.is_none() && user.is_none() {
+                    return Err(anyhow!("kick needs an id or a user"));
+                }
+                let clients = self.state.clients.lock().unwrap();
+                let targets: Vec<_> = clients
+                    .iter()
+                    .filter(|(client, peer)| {
+                        id.is_some_and(|id| id == **client)
+                            || user.as_ref().is_some_and(|u| *u == peer.identity.user)
+                    })
+                    .map(|(_, peer)| peer.clone())
+                    .collect();
+                drop(clients);
+                for peer in &targets {
+                    log::info!(
+                        "kicking {} ({})",
+                        peer.identity.user,
+                        peer.connection.remote_address()
+                    );
+                    peer.terminate(KICKED, b"kicked by admin");
+                }
+                response.kicked = Some(targets.len());
+            }
+            Request::Drain => self.drain(),
+            Request::Reload => self.reload()?,
+            Request::LogLevel { level } => log::set_max_level(level),
+        }
+        Ok(response)
+    }
+
+    fn clients(&self) -> Vec<Client> {
+        let mut clients: Vec<_> = self
+            .state
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, peer)| Client {
+                id: *id,
+                user: peer.identity.user.clone(),
+                remote: peer.connection.remote_address().to_string(),
+                streams: peer.streams.load(Ordering::Relaxed),
+                bytes_up: peer.bytes_up.load(Ordering::Relaxed),
+                bytes_down: peer.bytes_down.load(Ordering::Relaxed),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    fn drain(&self) {
+        if self.state.draining.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        log::info!("draining, new connections are refused");
+        let state = self.state.clone();
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            while !state.clients.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            shutdown.notify_one();
+        });
+    }
+
+    /// Applies the parts of the config that can change at runtime: TLS
+    /// material, transport settings of new connections, policy, egress, log
+    /// level and the user database. Anything else only takes effect on
+    /// restart.
+    fn reload(&self) -> Result<()> {
+        let config = Arc::new(Config::load(&self.config_path)?);
+        let server_config = server_config(&config)?;
+        self.state.auth.reload()?;
+        self.endpoint.set_server_config(Some(server_config));
+        *self.state.policy.write().unwrap() = Policy::from_config(&config.policy);
+        *self.state.egress.write().unwrap() = EgressFilter::from_config(&config);
+        if std::env::var_os("RUST_LOG").is_none() {
+            log::set_max_level(config.log_level);
+        }
+        *self.state.config.write().unwrap() = config;
+        log::info!("reloaded {}", self.config_path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        let parse = |line| serde_json::from_str::<Request>(line);
+        assert!(matches!(parse(r#"{"cmd":"list"}"#), Ok(Request::List)));
+        assert!(matches!(
+            parse(r#"{"cmd":"kick","user":"alice"}"#),
+            Ok(Request::Kick { id: None, user: Some(u) }) if u == "alice"
+        ));
+        assert!(matches!(
+            parse(r#"{"cmd":"log_level","level":"debug"}"#),
+            Ok(Request::LogLevel {
+                level: LevelFilter::Debug
+            })
+        ));
+        assert!(parse(r#"{"cmd":"shutdown"}"#).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use super::{Identity, Rejection};
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Method {
+    Password,
+    Certificate,
+    Enrollment,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Success,
+    Rejected(Rejection),
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    user: &'a str,
+    identity: Option<&'a str>,
+    remote: SocketAddr,
+    method: Method,
+    outcome: Outcome,
+}
+
+/// Login audit trail: every attempt is printed and, if configured, appended
+/// to a JSON lines file.
+pub(crate) struct AuditLog {
+    file: Option<Mutex<File>>,
+    metrics: Arc<Metrics>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(path: Option<&Path>, metrics: Arc<Metrics>) -> Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening audit log {}", path.display()))?,
+            )),
+            None => None,
+        };
+        Ok(Self { file, metrics })
+    }
+
+    pub(crate) fn record(
+        &self,
+        user: &[u8],
+        remote: SocketAddr,
+        method: Method,
+        result: &Result<Identity, Rejection>,
+    ) {
+        self.metrics.auth(method, result.is_ok());
+        let user = String::from_utf8_lossy(user);
+        match result {
+            Ok(identity) => log::info!(
+                "{:?} login as {} for user {:?} from {}",
+                method,
+                identity.user,
+                user,
+                remote
+            ),
+            Err(rejection) => log::info!(
+                "{:?} login {:?} for user {:?} from {}",
+                method,
+                rejection,
+                user,
+                remote
+            ),
+        }
+
+        let Some(file) = &self.file else {
+            return;
+        };
+        let record = AuditRecord {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            user: &user,
+            identity: result.as_ref().ok().map(|identity| identity.user.as_str()),
+            remote,
+            method,
+            outcome: match result {
+                Ok(_) => Outcome::Success,
+                Err(rejection) => Outcome::Rejected(*rejection),
+            },
+        };
+        let mut line = serde_json::to_string(&record).expect("serializing audit record");
+        line.push('\n');
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("writing audit log: {}", e);
+        }
+    }
+}
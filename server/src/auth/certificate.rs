@@ -0,0 +1,69 @@
+use anyhow::{Result, anyhow};
+use quinn::Connection;
+use rustls::pki_types::CertificateDer;
+use x509_parser::{extensions::GeneralName, prelude::parse_x509_certificate};
+
+use crate::config::CertUserField;
+
+/// User named by the certificate a client presented during the handshake.
+/// The chain was already verified against `client_auth.ca` by rustls.
+pub(crate) fn peer_user(connection: &Connection, field: CertUserField) -> Option<String> {
+    let chain = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    match user_from_certificate(chain.first()?, field) {
+        Ok(user) => Some(user),
+        Err(e) => {
+            log::warn!(
+                "client certificate from {}: {:?}",
+                connection.remote_address(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn user_from_certificate(cert: &CertificateDer<'_>, field: CertUserField) -> Result<String> {
+    let (_, cert) = parse_x509_certificate(cert)?;
+    let user = match field {
+        CertUserField::CommonName => cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string),
+        CertUserField::San => cert.subject_alternative_name()?.and_then(|san| {
+            san.value.general_names.iter().find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })
+        }),
+    };
+    user.ok_or_else(|| anyhow!("certificate has no {:?}", field))
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    use super::*;
+
+    #[test]
+    fn test_user_from_certificate() {
+        let mut params = CertificateParams::default();
+        let mut subject = DistinguishedName::new();
+        subject.push(DnType::CommonName, "build-agent-7");
+        params.distinguished_name = subject;
+        params.subject_alt_names = vec![SanType::Rfc822Name("ci@example.com".try_into().unwrap())];
+        let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+
+        let user = user_from_certificate(cert.der(), CertUserField::CommonName).unwrap();
+        assert_eq!(user, "build-agent-7");
+        let user = user_from_certificate(cert.der(), CertUserField::San).unwrap();
+        assert_eq!(user, "ci@example.com");
+    }
+}
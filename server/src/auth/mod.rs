@@ -0,0 +1,103 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::config::{AuthBackend, Config};
+use crate::users::UserStore;
+
+mod audit;
+mod certificate;
+mod token;
+mod webhook;
+
+pub(crate) use audit::{AuditLog, Method};
+pub(crate) use certificate::peer_user;
+pub(crate) use token::TokenAuth;
+pub(crate) use webhook::Webhook;
+
+/// Who is on the other end of a connection, as established by an [`Authenticator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Identity {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Rejection {
+    UnknownUser,
+    BadPassword,
+    Disabled,
+    Expired,
+    InvalidToken,
+    /// The request itself is malformed, e.g. an enrollment CSR that does
+    /// not parse.
+    BadRequest,
+    /// The backend could not be asked, e.g. the webhook is down.
+    Unavailable,
+}
+
+/// RFC 1929 username/password pair as received from the client.
+pub(crate) struct Credentials<'a> {
+    pub username: &'a [u8],
+    pub password: &'a [u8],
+    pub remote: SocketAddr,
+}
+
+pub(crate) trait Authenticator {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Identity, Rejection>;
+}
+
+/// Authenticator picked at startup from `[auth] backend`.
+pub(crate) enum Backend {
+    File(Arc<UserStore>),
+    Webhook(Webhook),
+    Token(TokenAuth),
+}
+
+impl Backend {
+    pub(crate) fn from_config(config: &Config) -> Result<Self> {
+        Ok(match &config.auth.backend {
+            AuthBackend::File => {
+                let users = Arc::new(UserStore::open(&config.users.db)?);
+                users.clone().watch(config.users.reload_interval);
+                Self::File(users)
+            }
+            AuthBackend::Webhook { url, timeout } => Self::Webhook(Webhook::new(url, *timeout)?),
+            AuthBackend::Token { secret } => Self::Token(TokenAuth::open(secret)?),
+        })
+    }
+
+    /// The identity of `user`, whom the client certificate names. The user
+    /// database has to know the user, with the same checks as for a
+    /// password; the other backends have no users to look up, so the name
+    /// stands on its own, without groups.
+    pub(crate) fn identify(&self, user: &str) -> Result<Identity, Rejection> {
+        match self {
+            Self::File(users) => users.lookup(user),
+            Self::Webhook(_) | Self::Token(_) => Ok(Identity {
+                user: user.to_string(),
+                groups: vec![],
+            }),
+        }
+    }
+
+    /// Re-reads the user database; the other backends are left as they are.
+    pub(crate) fn reload(&self) -> Result<()> {
+        match self {
+            Self::File(users) => users.reload(),
+            Self::Webhook(_) | Self::Token(_) => Ok(()),
+        }
+    }
+}
+
+impl Authenticator for Backend {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Identity, Rejection> {
+        match self {
+            Self::File(users) => users.authenticate(credentials).await,
+            Self::Webhook(webhook) => webhook.authenticate(credentials).await,
+            Self::Token(tokens) => tokens.authenticate(credentials).await,
+        }
+    }
+}
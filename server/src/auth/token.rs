@@ -0,0 +1,120 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{Authenticator, Credentials, Identity, Rejection};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Time-limited tokens of the form `<expiry unix secs>.<hex hmac>`, bound to
+/// the username they were issued for. The client sends the token as password.
+pub(crate) struct TokenAuth {
+    secret: Vec<u8>,
+}
+
+impl TokenAuth {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let secret = std::fs::read(path)
+            .with_context(|| format!("reading token secret {}", path.display()))?;
+        Self::new(secret)
+    }
+
+    fn new(secret: Vec<u8>) -> Result<Self> {
+        if secret.len() < 16 {
+            return Err(anyhow!("token secret is shorter than 16 bytes"));
+        }
+        Ok(Self { secret })
+    }
+
+    pub(crate) fn issue(&self, user: &str, ttl: Duration) -> String {
+        let expires = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_secs();
+        let signature = self.mac(user, expires).finalize().into_bytes();
+        format!("{}.{}", expires, hex::encode(signature))
+    }
+
+    fn mac(&self, user: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key size");
+        mac.update(&(user.len() as u64).to_be_bytes());
+        mac.update(user.as_bytes());
+        mac.update(&expires.to_be_bytes());
+        mac
+    }
+}
+
+impl Authenticator for TokenAuth {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Identity, Rejection> {
+        let user =
+            std::str::from_utf8(credentials.username).map_err(|_| Rejection::InvalidToken)?;
+        let token =
+            std::str::from_utf8(credentials.password).map_err(|_| Rejection::InvalidToken)?;
+
+        let (expires, signature) = token.split_once('.').ok_or(Rejection::InvalidToken)?;
+        let expires: u64 = expires.parse().map_err(|_| Rejection::InvalidToken)?;
+        let signature = hex::decode(signature).map_err(|_| Rejection::InvalidToken)?;
+
+        // verify_slice compares in constant time
+        self.mac(user, expires)
+            .verify_slice(&signature)
+            .map_err(|_| Rejection::InvalidToken)?;
+
+        if UNIX_EPOCH + Duration::from_secs(expires) <= SystemTime::now() {
+            return Err(Rejection::Expired);
+        }
+
+        Ok(Identity {
+            user: user.to_string(),
+            groups: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials<'a>(username: &'a str, password: &'a str) -> Credentials<'a> {
+        Credentials {
+            username: username.as_bytes(),
+            password: password.as_bytes(),
+            remote: "127.0.0.1:1".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let auth = TokenAuth::new(b"0123456789abcdef0123456789abcdef".to_vec()).unwrap();
+        let token = auth.issue("alice", Duration::from_secs(60));
+
+        let identity = auth
+            .authenticate(&credentials("alice", &token))
+            .await
+            .unwrap();
+        assert_eq!(identity.user, "alice");
+
+        assert_eq!(
+            auth.authenticate(&credentials("bob", &token)).await,
+            Err(Rejection::InvalidToken)
+        );
+
+        let (expires, signature) = token.split_once('.').unwrap();
+        let extended = format!("{}.{}", expires.parse::<u64>().unwrap() + 1, signature);
+        assert_eq!(
+            auth.authenticate(&credentials("alice", &extended)).await,
+            Err(Rejection::InvalidToken)
+        );
+
+        let stale = auth.issue("alice", Duration::ZERO);
+        assert_eq!(
+            auth.authenticate(&credentials("alice", &stale)).await,
+            Err(Rejection::Expired)
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{Authenticator, Credentials, Identity, Rejection};
+
+/// Largest webhook response read, a hook only returns a user and groups.
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+    remote: String,
+}
+
+#[derive(Deserialize)]
+struct WebhookResponse {
+    user: Option<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+/// Delegates the decision to an HTTP endpoint: `200` with an optional
+/// `{"user": .., "groups": [..]}` body accepts, `401`/`403` rejects.
+/// Only plain `http://` is supported, the hook is expected to be local.
+pub(crate) struct Webhook {
+    host: String,
+    path: String,
+    timeout: Duration,
+}
+
+impl Webhook {
+    pub(crate) fn new(url: &str, timeout: Duration) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("webhook url must start with http://"))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self {
+            host,
+            path: path.to_string(),
+            timeout,
+        })
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect(&self.host).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+
+        let mut response = vec![];
+        stream
+            .take(MAX_RESPONSE + 1)
+            .read_to_end(&mut response)
+            .await?;
+        if response.len() as u64 > MAX_RESPONSE {
+            return Err(anyhow!("webhook response over {} bytes", MAX_RESPONSE));
+        }
+
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("malformed webhook response"))?;
+        let status = std::str::from_utf8(&response[..split])?
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| anyhow!("malformed webhook status line"))?;
+        Ok((status, response[split + 4..].to_vec()))
+    }
+}
+
+impl Authenticator for Webhook {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Identity, Rejection> {
+        let username =
+            std::str::from_utf8(credentials.username).map_err(|_| Rejection::UnknownUser)?;
+        let password =
+            std::str::from_utf8(credentials.password).map_err(|_| Rejection::BadPassword)?;
+        let request = serde_json::to_vec(&WebhookRequest {
+            username,
+            password,
+            remote: credentials.remote.to_string(),
+        })
+        .expect("serializing webhook request");
+
+        let (status, body) = match tokio::time::timeout(self.timeout, self.post(&request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                log::warn!("auth webhook {}: {:?}", self.host, e);
+                return Err(Rejection::Unavailable);
+            }
+            Err(_) => {
+                log::warn!("auth webhook {}: timed out", self.host);
+                return Err(Rejection::Unavailable);
+            }
+        };
+
+        match status {
+            200 => {
+                let response: WebhookResponse = if body.iter().all(u8::is_ascii_whitespace) {
+                    WebhookResponse {
+                        user: None,
+                        groups: vec![],
+                    }
+                } else {
+                    serde_json::from_slice(&body).map_err(|e| {
+                        log::warn!("auth webhook {}: bad response body: {}", self.host, e);
+                        Rejection::Unavailable
+                    })?
+                };
+                Ok(Identity {
+                    user: response.user.unwrap_or_else(|| username.to_string()),
+                    groups: response.groups,
+                })
+            }
+            401 | 403 => Err(Rejection::BadPassword),
+            status => {
+                log::warn!("auth webhook {}: unexpected status {}", self.host, status);
+                Err(Rejection::Unavailable)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                let response = if request.starts_with("POST /big ") {
+                    format!("HTTP/1.1 200 OK\r\n\r\n{:1$}", "", MAX_RESPONSE as usize)
+                } else if request.contains(r#""password":"letmein""#) {
+                    "HTTP/1.1 200 OK\r\n\r\n{\"user\":\"alice@corp\",\"groups\":[\"staff\"]}"
+                        .to_string()
+                } else {
+                    "HTTP/1.1 401 Unauthorized\r\n\r\n".to_string()
+                };
+                // the client may have given up on a large response
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Reads the head and as much body as its Content-Length announces,
+    /// which may arrive in several segments.
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "request cut short");
+            request.extend_from_slice(&buf[..n]);
+            let Some(split) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8_lossy(&request[..split]).to_ascii_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map(|length| length.trim().parse().unwrap())
+                .unwrap_or(0);
+            if request.len() >= split + 4 + length {
+                return String::from_utf8_lossy(&request).to_string();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let url = stand_in().await;
+        let webhook = Webhook::new(&format!("{}/auth", url), Duration::from_secs(1)).unwrap();
+        let remote = "127.0.0.1:1".parse().unwrap();
+
+        let identity = webhook
+            .authenticate(&Credentials {
+                username: b"alice",
+                password: b"letmein",
+                remote,
+            })
+            .await
+            .unwrap();
+        assert_eq!(identity.user, "alice@corp");
+        assert_eq!(identity.groups, vec!["staff".to_string()]);
+
+        let rejected = webhook
+            .authenticate(&Credentials {
+                username: b"alice",
+                password: b"nope",
+                remote,
+            })
+            .await;
+        assert_eq!(rejected, Err(Rejection::BadPassword));
+
+        let oversized = Webhook::new(&format!("{}/big", url), Duration::from_secs(1)).unwrap();
+        let rejected = oversized
+            .authenticate(&Credentials {
+                username: b"alice",
+                password: b"letmein",
+                remote,
+            })
+            .await;
+        assert_eq!(rejected, Err(Rejection::Unavailable));
+    }
+}
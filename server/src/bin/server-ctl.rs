@@ -0,0 +1,79 @@
+//! Talks to the server's admin socket, see `[admin]` in the server config.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+
+const DEFAULT_SOCKET: &str = "/run/vpn_server.sock";
+
+const USAGE: &str = "usage: server-ctl [--socket <path>] <command>
+
+commands:
+  list                  connected clients
+  kick-client <id>      close one connection
+  kick-user <user>      close every connection of a user
+  drain                 refuse new connections, exit when the last client leaves
+  reload                re-read the config file and the user database
+  log-level <level>     off, error, warn, info, debug or trace";
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut socket = DEFAULT_SOCKET.to_string();
+    if args.peek().is_some_and(|arg| arg == "--socket") {
+        args.next();
+        socket = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    }
+    let command = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let mut arg = || args.next().ok_or_else(|| anyhow!(USAGE));
+    let request = match command.as_str() {
+        "list" => json!({ "cmd": "list" }),
+        "kick-client" => json!({ "cmd": "kick", "id": arg()?.parse::<usize>()? }),
+        "kick-user" => json!({ "cmd": "kick", "user": arg()? }),
+        "drain" => json!({ "cmd": "drain" }),
+        "reload" => json!({ "cmd": "reload" }),
+        "log-level" => json!({ "cmd": "log_level", "level": arg()? }),
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    let mut stream =
+        UnixStream::connect(&socket).with_context(|| format!("connecting to {}", socket))?;
+    let mut line = request.to_string();
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line).context("parsing server response")?;
+
+    if response["ok"] != true {
+        return Err(anyhow!(
+            "{}",
+            response["error"].as_str().unwrap_or("request failed")
+        ));
+    }
+    if let Some(clients) = response["clients"].as_array() {
+        println!(
+            "{:<20} {:<16} {:<24} {:>7} {:>12} {:>12}",
+            "ID", "USER", "REMOTE", "STREAMS", "UP", "DOWN"
+        );
+        for client in clients {
+            println!(
+                "{:<20} {:<16} {:<24} {:>7} {:>12} {:>12}",
+                client["id"],
+                client["user"].as_str().unwrap_or_default(),
+                client["remote"].as_str().unwrap_or_default(),
+                client["streams"],
+                client["bytes_up"],
+                client["bytes_down"]
+            );
+        }
+    } else if let Some(kicked) = response["kicked"].as_u64() {
+        println!("kicked {} connection(s)", kicked);
+    } else {
+        println!("ok");
+    }
+    Ok(())
+}
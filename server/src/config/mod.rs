@@ -0,0 +1,358 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use encryption::resume::DEFAULT_STREAM_WINDOW;
+use encryption::transport::TransportConfig;
+use ipnet::IpNet;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, de};
+
+use crate::policy::{Action, Rule};
+
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "/etc/vpn_server.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub listen: SocketAddr,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub aead_key: PathBuf,
+    pub users: UsersConfig,
+    pub auth: AuthConfig,
+    pub client_auth: ClientAuthConfig,
+    pub enrollment: EnrollmentConfig,
+    pub policy: PolicyConfig,
+    pub egress: EgressConfig,
+    pub shaping: ShapingConfig,
+    pub accounting: AccountingConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub shutdown: ShutdownConfig,
+    pub resumption: ResumptionConfig,
+    /// QUIC settings of client connections. Changes apply to new connections.
+    pub transport: TransportConfig,
+    /// Used when `RUST_LOG` is not set; can be changed at runtime over the admin API.
+    pub log_level: LevelFilter,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UsersConfig {
+    /// TOML file with `[[user]]` records, see `users.toml` in the repo root.
+    pub db: PathBuf,
+    /// How often the user database is checked for modifications.
+    #[serde(with = "humantime_serde")]
+    pub reload_interval: Duration,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct AuthConfig {
+    #[serde(flatten, deserialize_with = "backend_or_file")]
+    pub backend: AuthBackend,
+    /// Append-only JSON lines file with one record per login attempt.
+    pub audit_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub(crate) enum AuthBackend {
+    /// Users from `[users] db`.
+    #[default]
+    File,
+    /// POSTs credentials as JSON to a plain HTTP endpoint, e.g. a local sidecar.
+    Webhook {
+        url: String,
+        #[serde(default = "default_webhook_timeout", with = "humantime_serde")]
+        timeout: Duration,
+    },
+    /// HMAC-SHA256 signed tokens issued by `server issue-token`, sent as password.
+    Token { secret: PathBuf },
+}
+
+/// A flattened internally tagged enum insists on its tag, so an `[auth]`
+/// section with only `audit_log` would fail to parse without this.
+fn backend_or_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<AuthBackend, D::Error> {
+    let mut table = toml::Table::deserialize(deserializer)?;
+    table
+        .entry("backend")
+        .or_insert_with(|| toml::Value::String("file".into()));
+    table.try_into().map_err(de::Error::custom)
+}
+
+fn default_webhook_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
+/// Mutual TLS on the QUIC endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ClientAuthConfig {
+    /// PEM bundle of CAs that client certificates must chain to. Unset disables mTLS.
+    pub ca: Option<PathBuf>,
+    /// Refuse logins without a client certificate instead of falling back to
+    /// passwords. Handshakes without one are still taken for enrollment if
+    /// that is on.
+    pub required: bool,
+    pub user_from: CertUserField,
+    /// PEM CRLs checked on every handshake, reloaded when the file changes.
+    pub crl: Option<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    pub crl_reload_interval: Duration,
+}
+
+/// Client enrollment: one-time tokens redeemed for a certificate signed by our CA.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EnrollmentConfig {
+    /// CA that signs client certificates. Enrollment is off unless both are set.
+    pub ca_cert: Option<PathBuf>,
+    pub ca_key: Option<PathBuf>,
+    /// Pending tokens, managed by `server enroll-token`.
+    pub tokens: PathBuf,
+    /// Lifetime of issued client certificates.
+    #[serde(with = "humantime_serde")]
+    pub validity: Duration,
+}
+
+/// Destination ACLs checked on every CONNECT.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PolicyConfig {
+    /// Applies when no rule matches.
+    pub default: Action,
+    /// `[[policy.rule]]` entries, first match wins.
+    pub rule: Vec<Rule>,
+}
+
+/// Internal destinations are refused regardless of `[policy]`; these are the exceptions.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EgressConfig {
+    /// Networks clients may reach even though they are loopback, private, etc.
+    pub allow: Vec<IpNet>,
+}
+
+/// Bandwidth limits. Unset rates are unlimited.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ShapingConfig {
+    /// Shared by all connections of a user.
+    pub user: Limits,
+    /// Shared by all streams of one connection.
+    pub connection: Limits,
+    /// Replace `user` for the named users.
+    pub users: HashMap<String, Limits>,
+    /// Largest chunk in bytes a stream may send before the others get a turn.
+    pub quantum: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Limits {
+    /// Client to target, bytes per second.
+    pub upload: Option<u64>,
+    /// Target to client, bytes per second.
+    pub download: Option<u64>,
+    /// Bucket size in bytes, one second worth of traffic if unset.
+    pub burst: Option<u64>,
+}
+
+/// Per-user traffic counters and quotas.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AccountingConfig {
+    /// Counters of the current period, rewritten every `flush_interval`.
+    pub store: Option<PathBuf>,
+    /// Finished periods are appended here as JSON lines.
+    pub history: Option<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    pub flush_interval: Duration,
+    pub period: Period,
+    /// Bytes up plus down per period and user. Unset means unlimited.
+    pub quota: Option<u64>,
+    /// Replace `quota` for the named users.
+    pub users: HashMap<String, u64>,
+    /// Also close live sessions of users over quota, not just refuse new CONNECTs.
+    pub close_sessions: bool,
+}
+
+/// Quota periods, in UTC.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Period {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MetricsConfig {
+    /// Address for the Prometheus `/metrics` endpoint, off when unset.
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdminConfig {
+    /// Unix socket for the JSON admin API used by `server-ctl`, off when unset.
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ShutdownConfig {
+    /// How long open streams may keep running after SIGTERM or SIGINT.
+    #[serde(with = "humantime_serde")]
+    pub deadline: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ResumptionConfig {
+    /// How long the target connections of a disconnected client are kept
+    /// for it to resume its session.
+    #[serde(with = "humantime_serde")]
+    pub grace: Duration,
+    /// Largest client stream window honoured. Each flow keeps this much of
+    /// what it sent the client for replay, whatever the client announces.
+    pub max_stream_window: u32,
+    /// Sessions a user may have, attached or waiting to be resumed.
+    pub max_sessions: usize,
+    /// Bytes a user's flows may keep for replay, across its sessions. Flows
+    /// opened beyond that are not resumable.
+    pub max_replay: u64,
+}
+
+/// Which certificate field names the user.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CertUserField {
+    #[default]
+    CommonName,
+    /// First DNS, email or URI subject alternative name.
+    San,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "172.28.0.3:1080".parse().unwrap(),
+            cert: "/etc/cert.pem".into(),
+            key: "/etc/key.pem".into(),
+            aead_key: "/etc/xchacha20.key".into(),
+            users: UsersConfig::default(),
+            auth: AuthConfig::default(),
+            client_auth: ClientAuthConfig::default(),
+            enrollment: EnrollmentConfig::default(),
+            policy: PolicyConfig::default(),
+            egress: EgressConfig::default(),
+            shaping: ShapingConfig::default(),
+            accounting: AccountingConfig::default(),
+            metrics: MetricsConfig::default(),
+            admin: AdminConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            resumption: ResumptionConfig::default(),
+            transport: TransportConfig::default(),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for ResumptionConfig {
+    fn default() -> Self {
+        Self {
+            grace: Duration::from_secs(30),
+            max_stream_window: 4 * DEFAULT_STREAM_WINDOW,
+            max_sessions: 8,
+            max_replay: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for ClientAuthConfig {
+    fn default() -> Self {
+        Self {
+            ca: None,
+            required: false,
+            user_from: CertUserField::default(),
+            crl: None,
+            crl_reload_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for EnrollmentConfig {
+    fn default() -> Self {
+        Self {
+            ca_cert: None,
+            ca_key: None,
+            tokens: "/etc/vpn_enroll.toml".into(),
+            validity: Duration::from_secs(90 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl Default for ShapingConfig {
+    fn default() -> Self {
+        Self {
+            user: Limits::default(),
+            connection: Limits::default(),
+            users: HashMap::new(),
+            quantum: 16 * 1024,
+        }
+    }
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        Self {
+            store: None,
+            history: None,
+            flush_interval: Duration::from_secs(60),
+            period: Period::default(),
+            quota: None,
+            users: HashMap::new(),
+            close_sessions: false,
+        }
+    }
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        Self {
+            db: "/etc/users.toml".into(),
+            reload_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl EnrollmentConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.ca_cert.is_some() && self.ca_key.is_some()
+    }
+}
+
+impl Config {
+    /// Reads config from `path`, falling back to defaults if the file is missing.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(raw) => toml::from_str(&raw).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("no config at {}, using defaults", path.display());
+                Ok(Self::default())
+            }
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow};
+use encryption::pki::{Ca, ClientCsr, Issued};
+use quinn::{Connection, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{AuditLog, Identity, Method, Rejection};
+use crate::config::EnrollmentConfig;
+
+/// SOCKS5 method from the private range (0x80-0xFE) used to redeem a token.
+pub(crate) const ENROLLMENT_METHOD: u8 = 0x80;
+
+#[derive(Serialize, Deserialize)]
+struct PendingToken {
+    user: String,
+    sha256: String,
+    #[serde(with = "humantime_serde")]
+    expires: SystemTime,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct TokenFile {
+    #[serde(default)]
+    token: Vec<PendingToken>,
+}
+
+pub(crate) struct Enrollment {
+    ca: Ca,
+    tokens: PathBuf,
+    validity: Duration,
+}
+
+impl Enrollment {
+    pub(crate) fn from_config(config: &EnrollmentConfig) -> Result<Option<Self>> {
+        let (Some(cert), Some(key)) = (&config.ca_cert, &config.ca_key) else {
+            return Ok(None);
+        };
+        let cert = std::fs::read_to_string(cert)
+            .with_context(|| format!("reading enrollment CA {}", cert.display()))?;
+        let key = std::fs::read_to_string(key)
+            .with_context(|| format!("reading enrollment CA key {}", key.display()))?;
+        Ok(Some(Self {
+            ca: Ca::load(&cert, &key)?,
+            tokens: config.tokens.clone(),
+            validity: config.validity,
+        }))
+    }
+
+    /// Adds a one-time token for `user` to the token file and returns it.
+    /// Only its hash is stored.
+    pub(crate) fn issue_token(tokens: &Path, user: &str, ttl: Duration) -> Result<String> {
+        let mut secret = [0u8; 24];
+        rand::fill(&mut secret);
+        let token = hex::encode(secret);
+
+        let _lock = lock_tokens(tokens)?;
+        let mut file = read_tokens(tokens)?;
+        file.token.push(PendingToken {
+            user: user.to_string(),
+            sha256: hex::encode(Sha256::digest(token.as_bytes())),
+            expires: SystemTime::now() + ttl,
+        });
+        write_tokens(tokens, &file)?;
+        Ok(token)
+    }
+
+    /// Consumes `token` and signs the CSR for the user it was issued to.
+    fn redeem(&self, token: &[u8], csr: &[u8]) -> Result<(String, Issued), Rejection> {
+        // checked first, a CSR the client got wrong must not use up the token
+        let csr = ClientCsr::from_der(csr).map_err(|e| {
+            log::info!("enrollment: bad CSR: {:#}", e);
+            Rejection::BadRequest
+        })?;
+        let _lock = lock_tokens(&self.tokens).map_err(|e| {
+            log::warn!("enrollment: {:?}", e);
+            Rejection::Unavailable
+        })?;
+        let mut file = read_tokens(&self.tokens).map_err(|e| {
+            log::warn!("enrollment: {:?}", e);
+            Rejection::Unavailable
+        })?;
+
+        let hash = hex::encode(Sha256::digest(token));
+        let index = file
+            .token
+            .iter()
+            .position(|pending| pending.sha256 == hash)
+            .ok_or(Rejection::InvalidToken)?;
+        let pending = file.token.remove(index);
+        let persisted = write_tokens(&self.tokens, &file);
+        if pending.expires <= SystemTime::now() {
+            return Err(Rejection::Expired);
+        }
+        if let Err(e) = persisted {
+            // never hand out a certificate for a token that could be used again
+            log::warn!("enrollment: {:?}", e);
+            return Err(Rejection::Unavailable);
+        }
+
+        let issued = self
+            .ca
+            .sign_client_csr(&csr, &pending.user, self.validity)
+            .map_err(|e| {
+                log::warn!("enrollment for {}: {:?}", pending.user, e);
+                Rejection::Unavailable
+            })?;
+        Ok((pending.user, issued))
+    }
+
+    /// Runs the exchange after the client picked [`ENROLLMENT_METHOD`]:
+    ///
+    /// client: |version 0x01|token len|token|csr len (u16)|csr (DER)|
+    /// server: |version 0x01|status|cert len (u16)|cert chain (PEM)|
+    pub(crate) async fn serve(
+        &self,
+        audit: &AuditLog,
+        client: &Connection,
+        send: &mut SendStream,
+        recv: &mut RecvStream,
+    ) -> Result<()> {
+        let mut buf = [0u8; 2];
+        recv.read_exact(&mut buf[..1]).await?;
+        if buf[0] != 0x01 {
+            return Err(anyhow!("invalid enrollment version"));
+        }
+        recv.read_exact(&mut buf[..1]).await?;
+        let mut token = vec![0u8; buf[0] as usize];
+        recv.read_exact(&mut token).await?;
+        recv.read_exact(&mut buf).await?;
+        let mut csr = vec![0u8; u16::from_be_bytes(buf) as usize];
+        recv.read_exact(&mut csr).await?;
+
+        let result = self.redeem(&token, &csr);
+        let identity = match &result {
+            Ok((user, _)) => Ok(Identity {
+                user: user.clone(),
+                groups: vec![],
+            }),
+            Err(rejection) => Err(*rejection),
+        };
+        let user = identity.as_ref().map_or(&[][..], |i| i.user.as_bytes());
+        audit.record(user, client.remote_address(), Method::Enrollment, &identity);
+
+        let mut reply = vec![0x01];
+        match &result {
+            Ok((user, issued)) => {
+                log::info!(
+                    "enrolled {} with certificate serial {}",
+                    user,
+                    issued.serial
+                );
+                let cert = issued.cert_pem.as_bytes();
+                reply.push(0x00);
+                reply.extend_from_slice(&(cert.len() as u16).to_be_bytes());
+                reply.extend_from_slice(cert);
+            }
+            Err(_) => reply.extend_from_slice(&[0x01, 0, 0]),
+        }
+        send.write_all(&reply).await?;
+        send.finish()?;
+        // make sure the reply is delivered before the connection goes away
+        let _ = send.stopped().await;
+
+        result
+            .map(|_| ())
+            .map_err(|r| anyhow!("enrollment failed: {:?}", r))
+    }
+}
+
+/// Takes an exclusive lock on `<path>.lock` until the returned file is
+/// dropped, so the server redeeming and `server enroll-token` issuing do not
+/// undo each other's changes. The token file itself is replaced on every
+/// write, a lock on it would not outlive that.
+fn lock_tokens(path: &Path) -> Result<File> {
+    let lock = path.with_extension("lock");
+    let file = File::create(&lock).with_context(|| format!("creating {}", lock.display()))?;
+    file.lock()
+        .with_context(|| format!("locking {}", lock.display()))?;
+    Ok(file)
+}
+
+fn read_tokens(path: &Path) -> Result<TokenFile> {
+    match std::fs::read_to_string(path) {
+        Ok(raw) => toml::from_str(&raw).with_context(|| format!("parsing {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TokenFile::default()),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+fn write_tokens(path: &Path, file: &TokenFile) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, toml::to_string(file)?)
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_single_use() {
+        let tokens = std::env::temp_dir().join(format!("vpn-enroll-{}.toml", std::process::id()));
+        let ca = Ca::generate("test ca", Duration::from_secs(3600)).unwrap();
+        let enrollment = Enrollment {
+            ca,
+            tokens: tokens.clone(),
+            validity: Duration::from_secs(3600),
+        };
+        let token = Enrollment::issue_token(&tokens, "alice", Duration::from_secs(60)).unwrap();
+        let stale = Enrollment::issue_token(&tokens, "bob", Duration::ZERO).unwrap();
+        let (csr, _key) = encryption::pki::client_csr("laptop").unwrap();
+
+        // a broken CSR leaves the token for another try
+        assert_eq!(
+            enrollment.redeem(token.as_bytes(), b"not a CSR").err(),
+            Some(Rejection::BadRequest)
+        );
+        let (user, _) = enrollment.redeem(token.as_bytes(), &csr).unwrap();
+        assert_eq!(user, "alice");
+        assert_eq!(
+            enrollment.redeem(token.as_bytes(), &csr).err(),
+            Some(Rejection::InvalidToken)
+        );
+        assert_eq!(
+            enrollment.redeem(stale.as_bytes(), &csr).err(),
+            Some(Rejection::Expired)
+        );
+        std::fs::remove_file(&tokens).unwrap();
+        std::fs::remove_file(tokens.with_extension("lock")).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_issuing() {
+        let tokens = std::env::temp_dir().join(format!("vpn-issue-{}.toml", std::process::id()));
+        let issuers: Vec<_> = (0..8)
+            .map(|i| {
+                let tokens = tokens.clone();
+                std::thread::spawn(move || {
+                    Enrollment::issue_token(&tokens, &format!("user{}", i), Duration::from_secs(60))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for issuer in issuers {
+            issuer.join().unwrap();
+        }
+        assert_eq!(read_tokens(&tokens).unwrap().token.len(), 8);
+        std::fs::remove_file(&tokens).unwrap();
+        std::fs::remove_file(tokens.with_extension("lock")).unwrap();
+    }
+}
@@ -1,71 +1,363 @@
 use anyhow::{Result, anyhow};
-use quinn::{Connection, ServerConfig, TransportConfig};
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use std::io::Read;
-use std::sync::Arc;
-use std::time::Duration;
+use log::LevelFilter;
+use quinn::{Connection, RecvStream, SendStream, ServerConfig, VarInt};
+use rustls::crypto::{CryptoProvider, ring};
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{
+    collections::HashMap,
     fs::File,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::Path,
 };
 use tokio::net::TcpStream;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Notify;
 
-const USERNAME: &str = "testuser";
-const PASSWORD: &str = "testpass";
+use encryption::resume::{
+    CMD_CONNECT, CMD_RESUME, CMD_SESSION, MAX_STREAM_WINDOW, SESSION_JOIN, SessionId,
+};
+use encryption::transport::transport_config;
+
+use crate::accounting::{Accounting, QUOTA_EXCEEDED, Session};
+use crate::admin::Admin;
+use crate::auth::{AuditLog, Authenticator, Backend, Credentials, Identity, Method, TokenAuth};
+use crate::config::{AuthBackend, CertUserField, Config};
+use crate::enroll::{ENROLLMENT_METHOD, Enrollment};
+use crate::metrics::{ConnectGuard, Metrics};
+use crate::policy::{Action, Destination, EgressFilter, Policy, Protocol};
+use crate::resume::{Flow, ResumableSession, Sessions, TakenOver};
+use crate::shaping::{ConnectionShaper, Shaping};
+use crate::tls::Handshake;
+
+mod accounting;
+mod admin;
+mod auth;
+mod config;
+mod enroll;
+mod metrics;
+mod policy;
+mod resume;
+mod shaping;
+mod tls;
+mod users;
+
+/// Application error code for streams refused and connections closed on shutdown.
+const SHUTTING_DOWN: VarInt = VarInt::from_u32(0x53);
 
 struct TargetInfo {
     stream: TcpStream,
 }
 
-fn read_certs_from_file() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-    let certs = CertificateDer::from_pem_file("/etc/cert.pem").unwrap();
-    let key = PrivateKeyDer::from_pem_file("/etc/key.pem").unwrap();
-    Ok((vec![certs], key))
+struct ServerState {
+    /// Current config, replaced by an admin reload.
+    config: RwLock<Arc<Config>>,
+    auth: Backend,
+    audit: AuditLog,
+    cert_user: CertUserField,
+    enrollment: Option<Enrollment>,
+    policy: RwLock<Policy>,
+    egress: RwLock<EgressFilter>,
+    shaping: Shaping,
+    accounting: Arc<Accounting>,
+    metrics: Arc<Metrics>,
+    /// Authenticated clients by connection `stable_id`.
+    clients: Mutex<HashMap<usize, Arc<Peer>>>,
+    /// Set by an admin drain or on shutdown: new connections are refused.
+    draining: AtomicBool,
+    /// Set on shutdown: new streams are refused.
+    closing: AtomicBool,
+    sessions: Arc<Sessions>,
+}
+
+/// An authenticated client: its QUIC connection together with the identity
+/// the auth backend returned for it.
+struct Peer {
+    connection: Connection,
+    identity: Identity,
+    shaper: ConnectionShaper,
+    session: Session,
+    /// Open CONNECT streams.
+    streams: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    /// Set once the client opened a resumable session.
+    resumable: Mutex<Option<Arc<ResumableSession>>>,
+    /// Set when the connection was closed for good, e.g. by a kick, so that
+    /// its session goes too instead of waiting for the client to resume.
+    terminated: AtomicBool,
+}
+
+impl Peer {
+    /// Closes the connection and ends its session with all its flows.
+    fn terminate(&self, code: VarInt, reason: &[u8]) {
+        self.terminated.store(true, Ordering::Relaxed);
+        self.connection.close(code, reason);
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    println!("SOCKS5 VPN server with NAT listening on 172.28.0.3:1080");
-    let (certs, key) = read_certs_from_file().unwrap();
+    // env_logger lets everything through, the effective level is log's max
+    // level so that the admin API can change it at runtime
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let config_path = match args.next() {
+        Some(cmd) if cmd == "hash-password" => {
+            let password = args
+                .next()
+                .ok_or_else(|| anyhow!("usage: server hash-password <password>"))?;
+            println!("{}", users::hash_password(password.as_bytes())?);
+            return Ok(());
+        }
+        Some(cmd) if cmd == "issue-token" => {
+            let usage = "usage: server issue-token <user> <ttl> [config]";
+            let user = args.next().ok_or_else(|| anyhow!(usage))?;
+            let ttl = humantime::parse_duration(&args.next().ok_or_else(|| anyhow!(usage))?)?;
+            let config_path = args
+                .next()
+                .unwrap_or_else(|| config::DEFAULT_CONFIG_PATH.to_string());
+            let config = Config::load(Path::new(&config_path))?;
+            let AuthBackend::Token { secret } = &config.auth.backend else {
+                return Err(anyhow!("auth backend in {} is not token", config_path));
+            };
+            println!("{}", TokenAuth::open(secret)?.issue(&user, ttl));
+            return Ok(());
+        }
+        Some(cmd) if cmd == "enroll-token" => {
+            let usage = "usage: server enroll-token <user> <ttl> [config]";
+            let user = args.next().ok_or_else(|| anyhow!(usage))?;
+            let ttl = humantime::parse_duration(&args.next().ok_or_else(|| anyhow!(usage))?)?;
+            let config_path = args
+                .next()
+                .unwrap_or_else(|| config::DEFAULT_CONFIG_PATH.to_string());
+            let config = Config::load(Path::new(&config_path))?;
+            println!(
+                "{}",
+                Enrollment::issue_token(&config.enrollment.tokens, &user, ttl)?
+            );
+            return Ok(());
+        }
+        Some(path) => path,
+        None => config::DEFAULT_CONFIG_PATH.to_string(),
+    };
+    let config = Arc::new(Config::load(Path::new(&config_path))?);
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(config.log_level);
+    }
 
-    let mut server_config = ServerConfig::with_single_cert(certs, key).unwrap();
-    let mut transport_config = TransportConfig::default();
+    log::info!("SOCKS5 VPN server with NAT listening on {}", config.listen);
+    CryptoProvider::install_default(ring::default_provider())
+        .expect("failed to install default crypto provider");
 
-    transport_config.max_idle_timeout(None);
-    transport_config.keep_alive_interval(Some(Duration::from_secs(10)));
+    let metrics = Arc::new(Metrics::default());
+    let state = Arc::new(ServerState::new(config.clone(), metrics.clone())?);
+    if let Some(addr) = config.metrics.listen {
+        metrics.serve(encryption::metrics::bind(addr).await?)?;
+    }
+    state.accounting.clone().run();
 
-    server_config.transport_config(Arc::new(transport_config));
-    let server =
-        quinn::Endpoint::server(server_config, "172.28.0.3:1080".parse().unwrap()).unwrap();
+    let server = quinn::Endpoint::server(server_config(&config)?, config.listen).unwrap();
+    let transport = &config.transport;
+    log::info!(
+        "QUIC transport: {:?} congestion control, initial RTT {:?}, stream window {}, \
+         connection window {}, send window {}, {} streams, initial MTU {}",
+        transport.congestion,
+        transport.initial_rtt,
+        transport.stream_receive_window,
+        transport
+            .receive_window
+            .map_or("unlimited".to_string(), |window| window.to_string()),
+        transport.send_window,
+        transport.max_concurrent_streams,
+        transport.initial_mtu
+    );
+    watch_crl(server.clone(), state.clone());
+
+    let shutdown = Arc::new(Notify::new());
+    if let Some(socket) = &config.admin.socket {
+        Admin {
+            state: state.clone(),
+            endpoint: server.clone(),
+            config_path: config_path.into(),
+            shutdown: shutdown.clone(),
+        }
+        .serve(socket)?;
+    }
 
-    let path = Path::new("/etc/xchacha20.key");
-    let mut file = File::open(path)?;
+    let mut file = File::open(&config.aead_key)?;
     let mut aead_key = vec![];
     file.read_to_end(&mut aead_key)?;
     // let aead_key = aead_key.as_slice().into();
 
+    accept(server.clone(), state.clone());
+
+    let mut sigterm = signal(SignalKind::terminate())?;
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
-            println!("Received SIGINT/SIGTERM, shutting down...");
+            log::info!("received SIGINT, shutting down");
+        },
+        _ = sigterm.recv() => {
+            log::info!("received SIGTERM, shutting down");
+        },
+        _ = shutdown.notified() => {
+            log::info!("drained, shutting down");
         },
-        _ = async {
-            while let Some(conn) = server.accept().await {
-                tokio::spawn(async move {
-                    let connection = conn.await.unwrap();
-                    println!("new client: {}", connection.remote_address());
-                    if let Err(e) = handle_client(connection).await {
-                        eprintln!("client error: {:?}", e);
-                    }
-                });
-            }
-        } => {},
     };
+    graceful_shutdown(&state, &server, config.shutdown.deadline).await;
+
+    if let Err(e) = state.accounting.flush() {
+        log::error!("saving accounting: {:?}", e);
+    }
+    if let Some(socket) = &config.admin.socket {
+        let _ = std::fs::remove_file(socket);
+    }
     Ok(())
 }
 
-async fn handle_client(client: Connection) -> Result<()> {
+impl ServerState {
+    fn new(config: Arc<Config>, metrics: Arc<Metrics>) -> Result<Self> {
+        Ok(Self {
+            auth: Backend::from_config(&config)?,
+            audit: AuditLog::open(config.auth.audit_log.as_deref(), metrics.clone())?,
+            cert_user: config.client_auth.user_from,
+            enrollment: Enrollment::from_config(&config.enrollment)?,
+            policy: RwLock::new(Policy::from_config(&config.policy)),
+            egress: RwLock::new(EgressFilter::from_config(&config)),
+            shaping: Shaping::new(config.shaping.clone()),
+            accounting: Arc::new(Accounting::open(&config.accounting)?),
+            metrics,
+            clients: Mutex::new(HashMap::new()),
+            draining: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            sessions: Arc::new(Sessions::new(&config.resumption)),
+            config: RwLock::new(config),
+        })
+    }
+}
+
+/// Serves the clients connecting to `endpoint` until it is closed.
+fn accept(endpoint: quinn::Endpoint, state: Arc<ServerState>) {
+    tokio::spawn(async move {
+        while let Some(conn) = endpoint.accept().await {
+            if state.draining.load(Ordering::Relaxed) {
+                conn.refuse();
+                continue;
+            }
+            let state = state.clone();
+            tokio::spawn(async move {
+                let connecting = match conn.accept() {
+                    Ok(connecting) => connecting,
+                    Err(e) => {
+                        state.metrics.handshake_failed();
+                        log::warn!("handshake failed: {}", e);
+                        return;
+                    }
+                };
+                let Ok((connection, accepted)) = connecting.into_0rtt() else {
+                    unreachable!("incoming connections always convert to 0.5-RTT");
+                };
+                let handshake = Handshake::new(&connection, accepted, state.metrics.clone());
+                log::info!("new client: {}", connection.remote_address());
+                let _connection = state.metrics.connection(&connection);
+                if let Err(e) = handle_client(state, connection, handshake).await {
+                    log::warn!("client error: {:?}", e);
+                }
+            });
+        }
+    });
+}
+
+/// Stops taking connections and streams, gives open streams until `deadline`
+/// to finish and then closes every connection with `SHUTTING_DOWN`.
+async fn graceful_shutdown(state: &ServerState, endpoint: &quinn::Endpoint, deadline: Duration) {
+    state.draining.store(true, Ordering::Relaxed);
+    state.closing.store(true, Ordering::Relaxed);
+
+    let open_streams = || {
+        state
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|peer| peer.streams.load(Ordering::Relaxed))
+            .sum::<u64>()
+    };
+    let started = Instant::now();
+    let mut streams = open_streams();
+    if streams > 0 {
+        log::info!(
+            "waiting up to {} for {} open streams",
+            humantime::format_duration(deadline),
+            streams
+        );
+    }
+    while streams > 0 && started.elapsed() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        streams = open_streams();
+    }
+    if streams > 0 {
+        log::warn!("deadline passed, cutting {} open streams", streams);
+    }
+
+    endpoint.close(SHUTTING_DOWN, b"server shutting down");
+    // give the CONNECTION_CLOSE frames a chance to reach the clients
+    let _ = tokio::time::timeout(Duration::from_secs(1), endpoint.wait_idle()).await;
+}
+
+fn server_config(config: &Config) -> Result<ServerConfig> {
+    let mut server_config = ServerConfig::with_crypto(Arc::new(tls::server_crypto(config)?));
+    let mut transport_config = transport_config(&config.transport)?;
+
+    transport_config.max_idle_timeout(None);
+    transport_config.keep_alive_interval(Some(Duration::from_secs(10)));
+
+    server_config.transport_config(Arc::new(transport_config));
+    // roaming clients keep their connection, and with it the identity they
+    // authenticated as, when their address changes
+    server_config.migration(true);
+    Ok(server_config)
+}
+
+/// Swaps in a fresh TLS config whenever the CRL file changes, so revocations
+/// apply to new handshakes without a restart.
+fn watch_crl(endpoint: quinn::Endpoint, state: Arc<ServerState>) {
+    let modified = |config: &Config| {
+        let crl = config.client_auth.crl.as_ref()?;
+        std::fs::metadata(crl).and_then(|m| m.modified()).ok()
+    };
+    tokio::spawn(async move {
+        let mut last = modified(&state.config.read().unwrap());
+        loop {
+            let config = state.config.read().unwrap().clone();
+            tokio::time::sleep(config.client_auth.crl_reload_interval).await;
+            let current = modified(&config);
+            if current == last {
+                continue;
+            }
+            match server_config(&config) {
+                Ok(server_config) => {
+                    endpoint.set_server_config(Some(server_config));
+                    log::info!("reloaded client CRL");
+                    last = current;
+                }
+                Err(e) => log::warn!("keeping previous client CRL: {:?}", e),
+            }
+        }
+    });
+}
+
+async fn handle_client(
+    state: Arc<ServerState>,
+    client: Connection,
+    handshake: Handshake,
+) -> Result<()> {
     let (mut send, mut recv) = client.accept_bi().await?;
 
     // ==== METHOD NEGOTIATION ====
@@ -75,12 +367,488 @@ async fn handle_client(client: Connection) -> Result<()> {
     let mut methods = vec![0u8; nmethods];
     recv.read_exact(&mut methods).await?;
 
-    if !methods.contains(&0x02) {
-        send.write_all(&[0x05, 0xFF]).await?;
-        return Err(anyhow!("client does not support username/password"));
+    // early data may be a replay: nobody logs in, redeems a token or gets
+    // to sessions and targets before the handshake is done, which is also
+    // when the certificate is known
+    handshake.done().await?;
+
+    let identity = match auth::peer_user(&client, state.cert_user) {
+        // the handshake already authenticated the client, skip the password step
+        Some(user) if methods.contains(&0x00) => {
+            let result = state.auth.identify(&user);
+            state.audit.record(
+                user.as_bytes(),
+                client.remote_address(),
+                Method::Certificate,
+                &result,
+            );
+            match result {
+                Ok(identity) => {
+                    send.write_all(&[0x05, 0x00]).await?;
+                    identity
+                }
+                Err(rejection) => {
+                    refuse_methods(&mut send).await?;
+                    return Err(anyhow!("certificate login refused: {:?}", rejection));
+                }
+            }
+        }
+        _ if methods.contains(&ENROLLMENT_METHOD) && state.enrollment.is_some() => {
+            send.write_all(&[0x05, ENROLLMENT_METHOD]).await?;
+            let enrollment = state.enrollment.as_ref().unwrap();
+            return enrollment
+                .serve(&state.audit, &client, &mut send, &mut recv)
+                .await;
+        }
+        _ => {
+            if state.config.read().unwrap().client_auth.required {
+                refuse_methods(&mut send).await?;
+                return Err(anyhow!("client certificate required"));
+            }
+            if !methods.contains(&0x02) {
+                refuse_methods(&mut send).await?;
+                return Err(anyhow!("client does not support username/password"));
+            }
+            send.write_all(&[0x05, 0x02]).await?;
+            password_auth(&state, &client, &mut send, &mut recv).await?
+        }
+    };
+
+    state.metrics.authenticated(&client, &identity.user);
+    let peer = Arc::new(Peer {
+        connection: client,
+        shaper: state.shaping.connection(&identity),
+        session: state.accounting.session(&identity.user),
+        identity,
+        streams: AtomicU64::new(0),
+        bytes_up: AtomicU64::new(0),
+        bytes_down: AtomicU64::new(0),
+        resumable: Mutex::new(None),
+        terminated: AtomicBool::new(false),
+    });
+    if state.accounting.close_sessions() {
+        enforce_quota(state.clone(), peer.clone());
+    }
+    log_migrations(peer.clone());
+
+    let id = peer.connection.stable_id();
+    state.clients.lock().unwrap().insert(id, peer.clone());
+    let result = relay(&state, &peer).await;
+    state.clients.lock().unwrap().remove(&id);
+    let resumable = peer.resumable.lock().unwrap().take();
+    if let Some(session) = resumable {
+        if peer.terminated.load(Ordering::Relaxed) {
+            state.sessions.end(&session);
+        } else {
+            state.sessions.detach(session, &peer.connection);
+        }
+    }
+    result
+}
+
+/// Answers the method negotiation with "no acceptable methods" and waits for
+/// the answer to arrive, as the connection closes once the caller returns.
+async fn refuse_methods(send: &mut SendStream) -> Result<()> {
+    send.write_all(&[0x05, 0xFF]).await?;
+    send.finish()?;
+    let _ = send.stopped().await;
+    Ok(())
+}
+
+async fn relay(state: &Arc<ServerState>, peer: &Arc<Peer>) -> Result<()> {
+    // ==== CONNECT REQUEST ====
+    loop {
+        let (mut send, mut recv) = peer.connection.accept_bi().await?;
+        if state.closing.load(Ordering::Relaxed) {
+            let _ = send.reset(SHUTTING_DOWN);
+            let _ = recv.stop(SHUTTING_DOWN);
+            continue;
+        }
+        log::debug!(
+            "new stream inside client {:?} ({})",
+            peer.connection.remote_address(),
+            peer.identity.user
+        );
+        let state = state.clone();
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            peer.streams.fetch_add(1, Ordering::Relaxed);
+            let result = connect_stream(&state, &peer, send, recv).await;
+            peer.streams.fetch_sub(1, Ordering::Relaxed);
+            result
+        });
+    }
+}
+
+/// Handles one CONNECT request and relays the stream until either side closes.
+async fn connect_stream(
+    state: &ServerState,
+    peer: &Peer,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let _stream = state.metrics.stream();
+    let mut req = [0u8; 4];
+    recv.read_exact(&mut req).await?;
+    if req[0] != 0x05 {
+        return Err(anyhow!("invalid SOCKS version"));
     }
-    send.write_all(&[0x05, 0x02]).await?;
+    match req[1] {
+        CMD_SESSION => return open_session(state, peer, req[2], send, recv).await,
+        0x01 | CMD_CONNECT => {}
+        CMD_RESUME => return resume_flow(state, peer, send, recv).await,
+        _ => return Err(anyhow!("only CONNECT command supported")),
+    }
+    let mut attempt = state.metrics.connect();
+    let addr_type = req[3];
 
+    let (domain, ip) = match addr_type {
+        0x01 => {
+            let mut ip_buf = [0u8; 4];
+            recv.read_exact(&mut ip_buf).await?;
+            (None, Some(IpAddr::V4(Ipv4Addr::from(ip_buf))))
+        }
+        0x03 => {
+            let mut len = [0u8; 1];
+            recv.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            recv.read_exact(&mut name).await?;
+            (Some(String::from_utf8(name)?), None)
+        }
+        0x04 => {
+            let mut ip_buf = [0u8; 16];
+            recv.read_exact(&mut ip_buf).await?;
+            (None, Some(IpAddr::V6(Ipv6Addr::from(ip_buf))))
+        }
+        _ => return Err(anyhow!("address type not supported")),
+    };
+    let mut port_buf = [0u8; 2];
+    recv.read_exact(&mut port_buf).await?;
+    let port = u16::from_be_bytes(port_buf);
+    let flow = if req[1] == CMD_CONNECT {
+        let session = peer.resumable.lock().unwrap().clone();
+        let session = session.ok_or_else(|| anyhow!("CONNECT with a flow id outside a session"))?;
+        let mut id = [0u8; 4];
+        recv.read_exact(&mut id).await?;
+        Some((session, u32::from_be_bytes(id)))
+    } else {
+        None
+    };
+
+    if state.accounting.exceeded(&peer.identity.user) {
+        log::info!("denied CONNECT from {}: quota exceeded", peer.identity.user);
+        return reply(&mut attempt, &mut send, 0x02).await;
+    }
+
+    let candidates: Vec<SocketAddr> = match (&domain, ip) {
+        (_, Some(ip)) => vec![SocketAddr::new(ip, port)],
+        (Some(name), None) => match tokio::net::lookup_host((name.as_str(), port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                log::info!("resolving {} for {}: {}", name, peer.identity.user, e);
+                return reply(&mut attempt, &mut send, 0x04).await;
+            }
+        },
+        (None, None) => unreachable!(),
+    };
+
+    // every resolved address is checked, so a name cannot smuggle in a denied
+    // IP and DNS rebinding only ever lands on addresses we would dial anyway
+    let mut allowed = vec![];
+    for addr in candidates {
+        let destination = Destination {
+            addr,
+            domain: domain.as_deref(),
+            protocol: Protocol::Tcp,
+        };
+        if let Some(reason) = state.egress.read().unwrap().blocked(addr.ip()) {
+            log::info!(
+                "denied CONNECT from {} to {}: {} destination",
+                peer.identity.user,
+                destination,
+                reason
+            );
+            continue;
+        }
+        let verdict = state
+            .policy
+            .read()
+            .unwrap()
+            .check(&peer.identity, &destination);
+        if verdict.action == Action::Allow {
+            allowed.push(addr);
+        } else {
+            log::info!(
+                "denied CONNECT from {} to {}: {}",
+                peer.identity.user,
+                destination,
+                verdict
+            );
+        }
+    }
+    if allowed.is_empty() {
+        // connection not allowed by ruleset
+        return reply(&mut attempt, &mut send, 0x02).await;
+    }
+
+    let started = Instant::now();
+    let target_stream = match TcpStream::connect(allowed.as_slice()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::info!(
+                "CONNECT from {} to {:?}: {}",
+                peer.identity.user,
+                allowed,
+                e
+            );
+            return reply(&mut attempt, &mut send, connect_error_reply(&e)).await;
+        }
+    };
+    state.metrics.connect_latency(started.elapsed());
+
+    send.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    attempt.replied(0x00);
+    drop(attempt);
+
+    if let Some((session, id)) = flow {
+        match session.reserve() {
+            Some(reservation) => {
+                let flow = session.insert(id, target_stream, reservation);
+                return relay_flow(state, peer, &session, id, &flow, (send, recv), None).await;
+            }
+            // relayed as usual, a RESUME of it fails
+            None => log::debug!(
+                "flow {} of {} is not resumable: replay memory spent",
+                id,
+                peer.identity.user
+            ),
+        }
+    }
+
+    let mut target_info = TargetInfo {
+        stream: target_stream,
+    };
+
+    let (mut target_r, mut target_w) = target_info.stream.split();
+
+    let usage = &peer.session.usage;
+    let up = [&usage.up, &state.metrics.bytes_up, &peer.bytes_up];
+    let down = [&usage.down, &state.metrics.bytes_down, &peer.bytes_down];
+    let c2t = peer.shaper.upload.copy(&mut recv, &mut target_w, &up);
+    let t2c = peer.shaper.download.copy(&mut target_r, &mut send, &down);
+
+    tokio::try_join!(c2t, t2c)?;
+
+    Ok(())
+}
+
+/// SESSION: resumes the client's session or starts a new one, or joins it
+/// from another path.
+async fn open_session(
+    state: &ServerState,
+    peer: &Peer,
+    flags: u8,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let mut id = SessionId::default();
+    recv.read_exact(&mut id).await?;
+    let mut window = [0u8; 4];
+    recv.read_exact(&mut window).await?;
+    let client_window = u32::from_be_bytes(window);
+    let join = flags & SESSION_JOIN != 0;
+    let attached = {
+        let mut resumable = peer.resumable.lock().unwrap();
+        if resumable.is_some() {
+            // replacing it would keep the old session attached for good
+            Err(anyhow!("connection has a session already"))
+        } else if client_window > MAX_STREAM_WINDOW {
+            Err(anyhow!("stream window {} is too large", client_window))
+        } else {
+            // set before replying: the client opens flows as soon as it has the reply
+            state
+                .sessions
+                .attach(id, &peer.identity.user, join)
+                .inspect(|(session, _)| *resumable = Some(session.clone()))
+        }
+    };
+    let (session, resumed) = match attached {
+        Ok(attached) => attached,
+        Err(e) => {
+            send.write_all(&[0x05, 0x01, 0x00]).await?;
+            send.write_all(&SessionId::default()).await?;
+            send.write_all(&0u32.to_be_bytes()).await?;
+            send.finish()?;
+            return Err(e);
+        }
+    };
+    // sizes the replay buffers of its flows, so it is capped here
+    let max_window = state.config.read().unwrap().resumption.max_stream_window;
+    session.set_window(client_window.min(max_window));
+    let window = state.config.read().unwrap().transport.stream_receive_window;
+    send.write_all(&[0x05, 0x00, resumed as u8]).await?;
+    send.write_all(&session.id).await?;
+    send.write_all(&window.to_be_bytes()).await?;
+    send.finish()?;
+
+    let path = peer.connection.stats().path;
+    log::debug!(
+        "{}: client stream window {}, RTT {:?}, MTU {}, congestion window {}",
+        peer.identity.user,
+        client_window,
+        peer.connection.rtt(),
+        path.current_mtu,
+        path.cwnd
+    );
+    state.sessions.claim(&session, &peer.connection, join);
+    if join && resumed {
+        log::info!(
+            "{} joined its session from {}",
+            peer.identity.user,
+            peer.connection.remote_address()
+        );
+    } else if resumed {
+        log::info!(
+            "{} resumed its session from {}",
+            peer.identity.user,
+            peer.connection.remote_address()
+        );
+    }
+    Ok(())
+}
+
+/// RESUME: picks up a parked flow of the client's session on this stream.
+async fn resume_flow(
+    state: &ServerState,
+    peer: &Peer,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let mut req = [0u8; 12];
+    recv.read_exact(&mut req).await?;
+    let id = u32::from_be_bytes(req[..4].try_into().unwrap());
+    let received = u64::from_be_bytes(req[4..].try_into().unwrap());
+
+    let session = peer.resumable.lock().unwrap().clone();
+    let Some((session, flow)) = session.and_then(|s| s.flow(id).map(|flow| (s, flow))) else {
+        send.write_all(&[0x05, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0])
+            .await?;
+        send.finish()?;
+        return Err(anyhow!("RESUME of unknown flow {}", id));
+    };
+    relay_flow(
+        state,
+        peer,
+        &session,
+        id,
+        &flow,
+        (send, recv),
+        Some(received),
+    )
+    .await
+}
+
+/// Relays a flow of a resumable session. When the connection is lost the
+/// flow is kept for the client to resume, otherwise it is done.
+async fn relay_flow(
+    state: &ServerState,
+    peer: &Peer,
+    session: &ResumableSession,
+    id: u32,
+    flow: &Flow,
+    (mut send, mut recv): (SendStream, RecvStream),
+    received: Option<u64>,
+) -> Result<()> {
+    let usage = &peer.session.usage;
+    let up = [&usage.up, &state.metrics.bytes_up, &peer.bytes_up];
+    let down = [&usage.down, &state.metrics.bytes_down, &peer.bytes_down];
+    let result = flow
+        .relay(&mut send, &mut recv, received, &peer.shaper, &up, &down)
+        .await;
+    match &result {
+        Err(e) if e.is::<TakenOver>() => {
+            log::debug!(
+                "flow {} of {} moved to another path",
+                id,
+                peer.identity.user
+            );
+            return Ok(());
+        }
+        Err(_) if peer.connection.close_reason().is_some() => {
+            log::debug!("parked flow {} of {}", id, peer.identity.user);
+        }
+        _ => session.remove(id),
+    }
+    result
+}
+
+/// Sends a failed CONNECT reply and ends the stream.
+async fn reply(attempt: &mut ConnectGuard, send: &mut SendStream, code: u8) -> Result<()> {
+    attempt.replied(code);
+    send.write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    send.finish()?;
+    Ok(())
+}
+
+fn connect_error_reply(e: &std::io::Error) -> u8 {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => 0x05,
+        ErrorKind::NetworkUnreachable => 0x03,
+        ErrorKind::HostUnreachable => 0x04,
+        ErrorKind::TimedOut => 0x06,
+        _ => 0x01,
+    }
+}
+
+/// Closes the connection once its user runs over quota.
+fn enforce_quota(state: Arc<ServerState>, peer: Arc<Peer>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = peer.connection.closed() => return,
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            }
+            if state.accounting.exceeded(&peer.identity.user) {
+                log::info!("closing session of {}: quota exceeded", peer.identity.user);
+                peer.terminate(QUOTA_EXCEEDED, b"quota exceeded");
+                return;
+            }
+        }
+    });
+}
+
+/// Logs when a client shows up from another address, e.g. after switching
+/// networks or a NAT rebinding.
+fn log_migrations(peer: Arc<Peer>) {
+    tokio::spawn(async move {
+        let mut remote = peer.connection.remote_address();
+        loop {
+            tokio::select! {
+                _ = peer.connection.closed() => return,
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            }
+            let current = peer.connection.remote_address();
+            if current != remote {
+                log::info!(
+                    "{} migrated from {} to {}",
+                    peer.identity.user,
+                    remote,
+                    current
+                );
+                remote = current;
+            }
+        }
+    });
+}
+
+async fn password_auth(
+    state: &ServerState,
+    client: &Connection,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+) -> Result<Identity> {
     // ==== USERNAME/PASSWORD AUTH ====
     let mut buf = [0u8; 1];
     recv.read_exact(&mut buf).await?; // version
@@ -98,54 +866,425 @@ async fn handle_client(client: Connection) -> Result<()> {
     let mut passwd = vec![0u8; plen];
     recv.read_exact(&mut passwd).await?;
 
-    if uname != USERNAME.as_bytes() || passwd != PASSWORD.as_bytes() {
-        send.write_all(&[0x01, 0x01]).await?;
-        return Err(anyhow!("invalid username/password"));
+    let credentials = Credentials {
+        username: &uname,
+        password: &passwd,
+        remote: client.remote_address(),
+    };
+    let result = state.auth.authenticate(&credentials).await;
+    state
+        .audit
+        .record(&uname, client.remote_address(), Method::Password, &result);
+    match result {
+        Ok(identity) => {
+            send.write_all(&[0x01, 0x00]).await?;
+            Ok(identity)
+        }
+        Err(rejection) => {
+            send.write_all(&[0x01, 0x01]).await?;
+            Err(anyhow!("authentication failed: {:?}", rejection))
+        }
     }
-    send.write_all(&[0x01, 0x00]).await?;
+}
 
-    // ==== CONNECT REQUEST ====
-    loop {
-        let (mut send, mut recv) = client.accept_bi().await?;
-        println!("new stream inside client {:?}", client.remote_address());
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use encryption::pki::Ca;
+    use encryption::resume::DEFAULT_STREAM_WINDOW;
+    use rustls::RootCertStore;
+    use rustls::pki_types::CertificateDer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A server on a local port with token auth for any user, and what a
+    /// client needs to reach it.
+    struct TestServer {
+        addr: SocketAddr,
+        state: Arc<ServerState>,
+        client_config: quinn::ClientConfig,
+        tokens: TokenAuth,
+        dir: PathBuf,
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn server(name: &str) -> TestServer {
+        server_with(name, |_| String::new())
+    }
+
+    /// Like [`server`], with `extra` config added for the test directory.
+    fn server_with(name: &str, extra: impl FnOnce(&Path) -> String) -> TestServer {
+        let _ = CryptoProvider::install_default(ring::default_provider());
+        let dir = std::env::temp_dir().join(format!("vpn-server-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.signing_key.serialize_pem()).unwrap();
+        std::fs::write(dir.join("secret"), [7u8; 32]).unwrap();
+        let config: Config = toml::from_str(&format!(
+            "listen = \"127.0.0.1:0\"\ncert = {:?}\nkey = {:?}\n\
+             [auth]\nbackend = \"token\"\nsecret = {:?}\n\
+             [egress]\nallow = [\"127.0.0.0/8\"]\n{}",
+            dir.join("cert.pem"),
+            dir.join("key.pem"),
+            dir.join("secret"),
+            extra(&dir),
+        ))
+        .unwrap();
+        let config = Arc::new(config);
+
+        let state = Arc::new(ServerState::new(config.clone(), Arc::default()).unwrap());
+        let endpoint = quinn::Endpoint::server(server_config(&config).unwrap(), config.listen);
+        let endpoint = endpoint.unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        accept(endpoint, state.clone());
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.cert.der().to_vec()))
+            .unwrap();
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.enable_early_data = true;
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap();
+        TestServer {
+            addr,
+            state,
+            client_config: quinn::ClientConfig::new(Arc::new(crypto)),
+            tokens: TokenAuth::open(&dir.join("secret")).unwrap(),
+            dir,
+        }
+    }
+
+    impl TestServer {
+        /// A connection authenticated as `user`.
+        async fn connect(&self, user: &str) -> Connection {
+            let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(self.client_config.clone());
+            let connection = endpoint
+                .connect(self.addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            let token = self.tokens.issue(user, Duration::from_secs(60));
+            // |version, nmethods, methods| then |version, id_len, id, password_len, password|
+            let mut request = vec![0x05, 0x01, 0x02, 0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(token.len() as u8);
+            request.extend_from_slice(token.as_bytes());
+            send.write_all(&request).await.unwrap();
+            let mut reply = [0u8; 4];
+            recv.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x05, 0x02, 0x01, 0x00]);
+            connection
+        }
+    }
+
+    /// Sends SESSION for `id`, returning the session id and whether it was
+    /// resumed.
+    async fn open_session(connection: &Connection, id: SessionId) -> (SessionId, bool) {
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.write_all(&[0x05, CMD_SESSION, 0x00, 0x00])
+            .await
+            .unwrap();
+        send.write_all(&id).await.unwrap();
+        send.write_all(&DEFAULT_STREAM_WINDOW.to_be_bytes())
+            .await
+            .unwrap();
+        send.finish().unwrap();
+        let mut reply = [0u8; 23];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x00]);
+        (reply[3..19].try_into().unwrap(), reply[2] == 1)
+    }
+
+    /// Relays UDP between a client and `server`, holding back what the server
+    /// sends until the returned `Notify` fires. The client cannot finish its
+    /// handshake until then, so everything it sends is early data.
+    async fn holding_relay(server: SocketAddr) -> (SocketAddr, Arc<Notify>) {
+        let front = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let back = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        back.connect(server).await.unwrap();
+        let addr = front.local_addr().unwrap();
+        let release = Arc::new(Notify::new());
+        let released = release.clone();
         tokio::spawn(async move {
-            let mut req = [0u8; 4];
-            recv.read_exact(&mut req).await?;
-            if req[0] != 0x05 || req[1] != 0x01 {
-                return Err(anyhow!("only CONNECT command supported"));
-            }
-            let addr_type = req[3];
-
-            let target_addr: SocketAddr = match addr_type {
-                0x01 => {
-                    let mut ip_buf = [0u8; 4];
-                    recv.read_exact(&mut ip_buf).await?;
-                    let mut port_buf = [0u8; 2];
-                    recv.read_exact(&mut port_buf).await?;
-                    let ip = Ipv4Addr::from(ip_buf);
-                    let port = u16::from_be_bytes(port_buf);
-                    SocketAddr::new(IpAddr::V4(ip), port)
+            let mut client = None;
+            let mut held = Some(vec![]);
+            let (mut up, mut down) = ([0u8; 2048], [0u8; 2048]);
+            loop {
+                tokio::select! {
+                    received = front.recv_from(&mut up) => {
+                        let (n, from) = received.unwrap();
+                        client = Some(from);
+                        let _ = back.send(&up[..n]).await;
+                    }
+                    received = back.recv(&mut down) => {
+                        let n = received.unwrap();
+                        match (&mut held, client) {
+                            (Some(held), _) => held.push(down[..n].to_vec()),
+                            (None, Some(client)) => {
+                                let _ = front.send_to(&down[..n], client).await;
+                            }
+                            (None, None) => {}
+                        }
+                    }
+                    _ = released.notified(), if held.is_some() => {
+                        for datagram in held.take().unwrap() {
+                            let _ = front.send_to(&datagram, client.unwrap()).await;
+                        }
+                    }
                 }
-                _ => return Err(anyhow!("address type not supported")),
-            };
+            }
+        });
+        (addr, release)
+    }
 
-            let target_stream = TcpStream::connect(target_addr).await?;
+    #[tokio::test]
+    async fn test_early_connect_waits_for_handshake() {
+        let server = server("early");
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(server.client_config.clone());
 
-            let mut target_info = TargetInfo {
-                stream: target_stream,
-            };
+        // a first connection leaves a session ticket for 0-RTT
+        let connection = server.connect("alice").await;
+        connection.close(VarInt::from_u32(0), b"done");
+
+        let (relay, release) = holding_relay(server.addr).await;
+        let connecting = endpoint.connect(relay, "localhost").unwrap();
+        let Ok((connection, _)) = connecting.into_0rtt() else {
+            panic!("no 0-RTT with the session ticket");
+        };
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let token = server.tokens.issue("alice", Duration::from_secs(60));
+        let mut request = vec![0x05, 0x01, 0x02, 0x01, 5];
+        request.extend_from_slice(b"alice");
+        request.push(token.len() as u8);
+        request.extend_from_slice(token.as_bytes());
+        send.write_all(&request).await.unwrap();
+        let (mut flow_send, mut flow_recv) = connection.open_bi().await.unwrap();
+        // |version, command, reserved, type (ipv4), addr, port|
+        let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        flow_send.write_all(&request).await.unwrap();
+
+        // the server has the CONNECT but not the client's Finished
+        let early = tokio::time::timeout(Duration::from_millis(300), target.accept()).await;
+        assert!(early.is_err(), "CONNECT relayed before the handshake");
+
+        release.notify_one();
+        target.accept().await.unwrap();
+        let mut reply = [0u8; 4];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02, 0x01, 0x00]);
+        let mut reply = [0u8; 10];
+        flow_recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+    }
 
-            send.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-                .await?;
+    #[tokio::test]
+    async fn test_enrollment_with_required_certificates() {
+        let server = server_with("enroll", |dir| {
+            let ca = Ca::generate("test CA", Duration::from_secs(3600)).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.cert_pem()).unwrap();
+            std::fs::write(dir.join("ca.key"), ca.key_pem()).unwrap();
+            format!(
+                "[client_auth]\nca = {:?}\nrequired = true\n\
+                 [enrollment]\nca_cert = {:?}\nca_key = {:?}\ntokens = {:?}",
+                dir.join("ca.pem"),
+                dir.join("ca.pem"),
+                dir.join("ca.key"),
+                dir.join("tokens.toml"),
+            )
+        });
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(server.client_config.clone());
+
+        // passwords are refused without a certificate
+        let connection = endpoint
+            .connect(server.addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let mut reply = [0u8; 2];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xFF]);
+
+        // but a token can still be redeemed
+        let tokens = server.dir.join("tokens.toml");
+        let token = Enrollment::issue_token(&tokens, "alice", Duration::from_secs(60)).unwrap();
+        let (csr, _) = encryption::pki::client_csr("alice").unwrap();
+        let connection = endpoint
+            .connect(server.addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let mut request = vec![0x05, 0x01, ENROLLMENT_METHOD, 0x01, token.len() as u8];
+        request.extend_from_slice(token.as_bytes());
+        request.extend_from_slice(&(csr.len() as u16).to_be_bytes());
+        request.extend_from_slice(&csr);
+        send.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 4];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, ENROLLMENT_METHOD, 0x01, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_kick_ends_session() {
+        let server = server("kick");
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
 
-            let (mut target_r, mut target_w) = target_info.stream.split();
+        let connection = server.connect("alice").await;
+        let (id, _) = open_session(&connection, SessionId::default()).await;
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let mut request = vec![0x05, CMD_CONNECT, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        request.extend_from_slice(&7u32.to_be_bytes());
+        send.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+        let (mut flow, _) = target.accept().await.unwrap();
 
-            let c2t = tokio::io::copy(&mut recv, &mut target_w);
-            let t2c = tokio::io::copy(&mut target_r, &mut send);
+        let peers: Vec<_> = server
+            .state
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for peer in peers {
+            peer.terminate(admin::KICKED, b"kicked by admin");
+        }
+        // the target connection is closed instead of kept for a resume
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(5), flow.read_to_end(&mut rest))
+            .await
+            .expect("target connection kept after the kick")
+            .unwrap();
 
-            tokio::try_join!(c2t, t2c)?;
+        let connection = server.connect("alice").await;
+        let (resumed_id, resumed) = open_session(&connection, id).await;
+        assert!(!resumed);
+        assert_ne!(resumed_id, id);
+    }
 
-            Ok(())
+    #[tokio::test]
+    async fn test_user_series_end_with_last_connection() {
+        let server = server("series");
+        let metrics = server.state.metrics.clone();
+        let scraped = |line: &'static str| {
+            let metrics = metrics.clone();
+            async move {
+                while !metrics.render().contains(line) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        let first = server.connect("alice").await;
+        let second = server.connect("alice").await;
+
+        first.close(VarInt::from_u32(0), b"done");
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            scraped("vpn_connections_active 1\n"),
+        )
+        .await
+        .unwrap();
+        assert!(
+            metrics
+                .render()
+                .contains("vpn_path_lost_packets_total{user=\"alice\"}")
+        );
+
+        second.close(VarInt::from_u32(0), b"done");
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            scraped("vpn_connections_active 0\n"),
+        )
+        .await
+        .unwrap();
+        assert!(!metrics.render().contains("user=\"alice\""));
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_connection_loss() {
+        let server = server("resume");
+        let down: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+        let up: Vec<u8> = (0..256 * 1024).map(|i| (i % 241) as u8).collect();
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let sent = down.clone();
+        let target = tokio::spawn(async move {
+            let (stream, _) = target.accept().await.unwrap();
+            let (mut r, mut w) = stream.into_split();
+            let written = async {
+                w.write_all(&sent).await.unwrap();
+                w.shutdown().await.unwrap();
+            };
+            let mut received = vec![];
+            let (_, read) = tokio::join!(written, r.read_to_end(&mut received));
+            read.unwrap();
+            received
         });
+
+        let connection = server.connect("alice").await;
+        let (id, resumed) = open_session(&connection, SessionId::default()).await;
+        assert!(!resumed);
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        // |version, command, reserved, type (ipv4), addr, port, flow id|
+        let mut request = vec![0x05, CMD_CONNECT, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        request.extend_from_slice(&7u32.to_be_bytes());
+        send.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        // lost halfway through both directions
+        send.write_all(&up[..up.len() / 2]).await.unwrap();
+        let mut received = vec![0u8; 100_000];
+        recv.read_exact(&mut received).await.unwrap();
+        connection.close(VarInt::from_u32(0), b"lost");
+
+        let connection = server.connect("alice").await;
+        assert_eq!(open_session(&connection, id).await, (id, true));
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        // |version, command, reserved, reserved, flow id, received|
+        let mut request = vec![0x05, CMD_RESUME, 0x00, 0x00];
+        request.extend_from_slice(&7u32.to_be_bytes());
+        request.extend_from_slice(&(received.len() as u64).to_be_bytes());
+        send.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 11];
+        recv.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+        let delivered = u64::from_be_bytes(reply[3..].try_into().unwrap()) as usize;
+        send.write_all(&up[delivered..]).await.unwrap();
+        send.finish().unwrap();
+        let rest = recv.read_to_end(usize::MAX).await.unwrap();
+        received.extend_from_slice(&rest);
+        assert!(received == down, "target data lost or duplicated");
+        assert!(
+            target.await.unwrap() == up,
+            "client data lost or duplicated"
+        );
     }
 }
@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use encryption::metrics::label_value;
+use quinn::Connection;
+use tokio::net::TcpListener;
+
+use crate::auth::Method;
+
+/// SOCKS5 reply codes as metric labels.
+/// Counted for a CONNECT that ended before any reply, e.g. a malformed one.
+const NO_REPLY: u8 = 0xff;
+
+fn reply_label(code: u8) -> &'static str {
+    match code {
+        0x00 => "succeeded",
+        0x01 => "general_failure",
+        0x02 => "not_allowed",
+        0x03 => "network_unreachable",
+        0x04 => "host_unreachable",
+        0x05 => "connection_refused",
+        0x06 => "ttl_expired",
+        0x07 => "command_not_supported",
+        0x08 => "address_type_not_supported",
+        NO_REPLY => "none",
+        _ => "unknown",
+    }
+}
+
+/// Upper bounds of the CONNECT latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    /// Sum in microseconds, to keep it atomic.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Server counters, rendered in the Prometheus text format on `/metrics`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    connections: AtomicI64,
+    streams: AtomicI64,
+    handshake_failures: AtomicU64,
+    auth: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    connects: Mutex<BTreeMap<u8, u64>>,
+    connect_latency: Histogram,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    /// Live connections by `stable_id`, for path stats.
+    live: Mutex<HashMap<usize, Live>>,
+    /// Packets lost on the closed connections of each user that still has
+    /// live ones, so series don't outlive users.
+    lost: Mutex<BTreeMap<String, u64>>,
+}
+
+struct Live {
+    connection: Connection,
+    /// Set once the client authenticated, path stats are by user.
+    user: Option<String>,
+}
+
+/// Path stats of one user's live connections.
+#[derive(Default)]
+struct UserPaths {
+    connections: u32,
+    rtt: Duration,
+    cwnd: u64,
+    lost_packets: u64,
+}
+
+/// Tracks a live QUIC connection until dropped.
+pub(crate) struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    id: usize,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut live = self.metrics.live.lock().unwrap();
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+        let Some(Live {
+            connection,
+            user: Some(user),
+        }) = live.remove(&self.id)
+        else {
+            return;
+        };
+        let mut lost = self.metrics.lost.lock().unwrap();
+        if live.values().any(|live| live.user.as_ref() == Some(&user)) {
+            // kept so that the user's lost packets only ever go up
+            *lost.entry(user).or_default() += connection.stats().path.lost_packets;
+        } else {
+            // the series ends with the user's last connection
+            lost.remove(&user);
+        }
+    }
+}
+
+/// Counts an open stream until dropped.
+pub(crate) struct StreamGuard(Arc<Metrics>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a CONNECT by the reply sent for it when dropped.
+pub(crate) struct ConnectGuard {
+    metrics: Arc<Metrics>,
+    reply: Option<u8>,
+}
+
+impl ConnectGuard {
+    pub(crate) fn replied(&mut self, reply: u8) {
+        self.reply = Some(reply);
+    }
+}
+
+impl Drop for ConnectGuard {
+    fn drop(&mut self) {
+        let reply = self.reply.unwrap_or(NO_REPLY);
+        *self
+            .metrics
+            .connects
+            .lock()
+            .unwrap()
+            .entry(reply)
+            .or_default() += 1;
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection(self: &Arc<Self>, connection: &Connection) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        let id = connection.stable_id();
+        let live = Live {
+            connection: connection.clone(),
+            user: None,
+        };
+        self.live.lock().unwrap().insert(id, live);
+        ConnectionGuard {
+            metrics: self.clone(),
+            id,
+        }
+    }
+
+    /// Counts the path stats of `connection` toward `user` from now on.
+    pub(crate) fn authenticated(&self, connection: &Connection, user: &str) {
+        if let Some(live) = self.live.lock().unwrap().get_mut(&connection.stable_id()) {
+            live.user = Some(user.to_string());
+        }
+    }
+
+    pub(crate) fn stream(self: &Arc<Self>) -> StreamGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard(self.clone())
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn auth(&self, method: Method, success: bool) {
+        let method = match method {
+            Method::Password => "password",
+            Method::Certificate => "certificate",
+            Method::Enrollment => "enrollment",
+        };
+        *self
+            .auth
+            .lock()
+            .unwrap()
+            .entry((method, success))
+            .or_default() += 1;
+    }
+
+    /// Starts counting a CONNECT, by its reply or as `none` if it ends
+    /// without one.
+    pub(crate) fn connect(self: &Arc<Self>) -> ConnectGuard {
+        ConnectGuard {
+            metrics: self.clone(),
+            reply: None,
+        }
+    }
+
+    pub(crate) fn connect_latency(&self, latency: Duration) {
+        self.connect_latency.observe(latency);
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# TYPE vpn_connections_active gauge");
+        let _ = writeln!(
+            out,
+            "vpn_connections_active {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE vpn_streams_active gauge");
+        let _ = writeln!(
+            out,
+            "vpn_streams_active {}",
+            self.streams.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE vpn_handshake_failures_total counter");
+        let _ = writeln!(
+            out,
+            "vpn_handshake_failures_total {}",
+            self.handshake_failures.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(out, "# TYPE vpn_auth_attempts_total counter");
+        for ((method, success), count) in self.auth.lock().unwrap().iter() {
+            let outcome = if *success { "success" } else { "failure" };
+            let _ = writeln!(
+                out,
+                "vpn_auth_attempts_total{{method=\"{}\",outcome=\"{}\"}} {}",
+                method, outcome, count
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE vpn_connect_total counter");
+        for (reply, count) in self.connects.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "vpn_connect_total{{reply=\"{}\"}} {}",
+                reply_label(*reply),
+                count
+            );
+        }
+        let _ = writeln!(out, "# TYPE vpn_connect_duration_seconds histogram");
+        self.connect_latency
+            .render(&mut out, "vpn_connect_duration_seconds");
+
+        let _ = writeln!(out, "# TYPE vpn_relayed_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "vpn_relayed_bytes_total{{direction=\"up\"}} {}",
+            self.bytes_up.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "vpn_relayed_bytes_total{{direction=\"down\"}} {}",
+            self.bytes_down.load(Ordering::Relaxed)
+        );
+
+        // by user rather than by connection, which would add series for
+        // every client address and port that ever connected
+        let live: Vec<(String, Connection)> = self
+            .live
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|live| Some((live.user.clone()?, live.connection.clone())))
+            .collect();
+        let mut users: BTreeMap<String, UserPaths> = BTreeMap::new();
+        for (user, connection) in live {
+            let path = connection.stats().path;
+            let paths = users.entry(user).or_default();
+            paths.connections += 1;
+            paths.rtt += path.rtt;
+            paths.cwnd += path.cwnd;
+            paths.lost_packets += path.lost_packets;
+        }
+        let _ = writeln!(out, "# TYPE vpn_path_rtt_seconds gauge");
+        for (user, paths) in &users {
+            let rtt = paths.rtt.as_secs_f64() / paths.connections as f64;
+            let _ = writeln!(
+                out,
+                "vpn_path_rtt_seconds{{user=\"{}\"}} {}",
+                label_value(user),
+                rtt
+            );
+        }
+        let _ = writeln!(out, "# TYPE vpn_path_cwnd_bytes gauge");
+        for (user, paths) in &users {
+            let cwnd = paths.cwnd / paths.connections as u64;
+            let _ = writeln!(
+                out,
+                "vpn_path_cwnd_bytes{{user=\"{}\"}} {}",
+                label_value(user),
+                cwnd
+            );
+        }
+        let mut lost = self.lost.lock().unwrap().clone();
+        for (user, paths) in &users {
+            *lost.entry(user.clone()).or_default() += paths.lost_packets;
+        }
+        let _ = writeln!(out, "# TYPE vpn_path_lost_packets_total counter");
+        for (user, lost) in &lost {
+            let _ = writeln!(
+                out,
+                "vpn_path_lost_packets_total{{user=\"{}\"}} {}",
+                label_value(user),
+                lost
+            );
+        }
+        out
+    }
+
+    /// Serves `GET /metrics` on `listener`. Meant for a local or otherwise
+    /// firewalled address, there is no authentication.
+    pub(crate) fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        encryption::metrics::serve(listener, move || self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.connect().replied(0x00);
+        metrics.connect().replied(0x00);
+        metrics.connect().replied(0x02);
+        drop(metrics.connect());
+        metrics.auth(Method::Password, false);
+        metrics.connect_latency(Duration::from_millis(30));
+        metrics.bytes_up.fetch_add(1234, Ordering::Relaxed);
+        let stream = metrics.stream();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        metrics.clone().serve(listener).unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("vpn_streams_active 1\n"));
+        assert!(response.contains("vpn_connect_total{reply=\"succeeded\"} 2\n"));
+        assert!(response.contains("vpn_connect_total{reply=\"not_allowed\"} 1\n"));
+        assert!(response.contains("vpn_connect_total{reply=\"none\"} 1\n"));
+        assert!(
+            response
+                .contains("vpn_auth_attempts_total{method=\"password\",outcome=\"failure\"} 1\n")
+        );
+        assert!(response.contains("vpn_connect_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(response.contains("vpn_connect_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(response.contains("vpn_relayed_bytes_total{direction=\"up\"} 1234\n"));
+
+        drop(stream);
+        assert!(metrics.render().contains("vpn_streams_active 0\n"));
+    }
+
+    #[test]
+    fn test_user_label_escaped() {
+        let metrics = Metrics::default();
+        metrics
+            .lost
+            .lock()
+            .unwrap()
+            .insert("a\\b\"} 1\nvpn_fake 2".to_string(), 3);
+        let out = metrics.render();
+        assert!(
+            out.contains("vpn_path_lost_packets_total{user=\"a\\\\b\\\"} 1\\nvpn_fake 2\"} 3\n")
+        );
+        assert!(!out.contains("\nvpn_fake"));
+    }
+}
@@ -0,0 +1,173 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+
+use crate::config::Config;
+
+/// Default-deny filter for destinations on the server's side of the tunnel:
+/// loopback, link-local (cloud metadata lives there), private, reserved,
+/// multicast and the host's own addresses. Checked on resolved addresses, so
+/// DNS answers that point inside are refused just like literal IPs.
+pub(crate) struct EgressFilter {
+    allow: Vec<IpNet>,
+    own: Vec<IpAddr>,
+}
+
+impl EgressFilter {
+    /// The host's addresses are read now, a reload picks up new ones.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let mut own = local_addresses().unwrap_or_else(|e| {
+            log::warn!("listing interface addresses: {:#}", e);
+            vec![]
+        });
+        own.push(config.listen.ip().to_canonical());
+        Self {
+            allow: config.egress.allow.clone(),
+            own,
+        }
+    }
+
+    /// Why `ip` may not be dialed, or `None` if it may.
+    pub(crate) fn blocked(&self, ip: IpAddr) -> Option<&'static str> {
+        let ip = ip.to_canonical();
+        if self.allow.iter().any(|net| net.contains(&ip)) {
+            return None;
+        }
+        if self.own.contains(&ip) {
+            return Some("server address");
+        }
+        match ip {
+            IpAddr::V4(ip) => blocked_v4(ip),
+            IpAddr::V6(ip) => blocked_v6(ip),
+        }
+    }
+}
+
+/// Addresses of the host's interfaces, which the listen address alone does
+/// not cover when it is unspecified.
+fn local_addresses() -> std::io::Result<Vec<IpAddr>> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut found = vec![];
+    let mut next = addrs;
+    while let Some(ifaddr) = unsafe { next.as_ref() } {
+        next = ifaddr.ifa_next;
+        let Some(addr) = (unsafe { ifaddr.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        match addr.sa_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*ifaddr.ifa_addr.cast::<libc::sockaddr_in>() };
+                found.push(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into());
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*ifaddr.ifa_addr.cast::<libc::sockaddr_in6>() };
+                found.push(Ipv6Addr::from(addr.sin6_addr.s6_addr).into());
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    Ok(found)
+}
+
+fn blocked_v4(ip: Ipv4Addr) -> Option<&'static str> {
+    let [a, b, c, _] = ip.octets();
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if ip.is_private() || (a == 100 && b & 0xc0 == 64) {
+        // RFC 1918 and carrier-grade NAT space
+        Some("private")
+    } else if ip.is_multicast() || ip.is_broadcast() {
+        Some("multicast")
+    } else if a == 0 {
+        Some("unspecified")
+    } else if (a == 198 && b & 0xfe == 18) || a >= 240 || (a == 192 && b == 0 && c == 0) {
+        // benchmarking, future use and IETF protocol assignments
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+fn blocked_v6(ip: Ipv6Addr) -> Option<&'static str> {
+    let [a, b, ..] = ip.segments();
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_unicast_link_local() {
+        Some("link-local")
+    } else if ip.is_unique_local() {
+        Some("private")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_unspecified() {
+        Some("unspecified")
+    } else if (a == 0x64 && b == 0xff9b && ip.segments()[2..6] == [0; 4]) || a == 0x2002 {
+        // NAT64 and 6to4 carry an IPv4 address, which may be an inside one
+        Some("translated")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str]) -> EgressFilter {
+        EgressFilter {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            own: vec!["172.28.0.3".parse().unwrap()],
+        }
+    }
+
+    fn blocked(filter: &EgressFilter, ip: &str) -> Option<&'static str> {
+        filter.blocked(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_blocks_internal_destinations() {
+        let filter = filter(&[]);
+        assert_eq!(blocked(&filter, "127.0.0.1"), Some("loopback"));
+        assert_eq!(blocked(&filter, "169.254.169.254"), Some("link-local"));
+        assert_eq!(blocked(&filter, "172.28.0.2"), Some("private"));
+        assert_eq!(blocked(&filter, "172.28.0.3"), Some("server address"));
+        assert_eq!(blocked(&filter, "100.100.1.1"), Some("private"));
+        assert_eq!(blocked(&filter, "224.0.0.1"), Some("multicast"));
+        assert_eq!(blocked(&filter, "0.0.0.0"), Some("unspecified"));
+        assert_eq!(blocked(&filter, "::1"), Some("loopback"));
+        assert_eq!(blocked(&filter, "fe80::1"), Some("link-local"));
+        assert_eq!(blocked(&filter, "fd00::1"), Some("private"));
+        assert_eq!(blocked(&filter, "::ffff:10.0.0.1"), Some("private"));
+        assert_eq!(blocked(&filter, "198.19.0.1"), Some("reserved"));
+        assert_eq!(blocked(&filter, "240.0.0.1"), Some("reserved"));
+        assert_eq!(blocked(&filter, "192.0.0.170"), Some("reserved"));
+        assert_eq!(blocked(&filter, "64:ff9b::a00:1"), Some("translated"));
+        assert_eq!(blocked(&filter, "2002:a00:1::1"), Some("translated"));
+        assert_eq!(blocked(&filter, "1.1.1.1"), None);
+        assert_eq!(blocked(&filter, "2606:4700::1111"), None);
+    }
+
+    #[test]
+    fn test_allow_overrides() {
+        let filter = filter(&["10.1.0.0/16", "172.28.0.3/32"]);
+        assert_eq!(blocked(&filter, "10.1.2.3"), None);
+        assert_eq!(blocked(&filter, "172.28.0.3"), None);
+        assert_eq!(blocked(&filter, "10.2.0.1"), Some("private"));
+    }
+
+    #[test]
+    fn test_own_addresses() {
+        let config = Config {
+            listen: "0.0.0.0:4433".parse().unwrap(),
+            ..Config::default()
+        };
+        let filter = EgressFilter::from_config(&config);
+        // loopback is blocked anyway, but listed like any other interface
+        assert!(filter.own.contains(&"127.0.0.1".parse().unwrap()));
+    }
+}
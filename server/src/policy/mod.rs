@@ -0,0 +1,268 @@
+use std::{fmt, net::SocketAddr, ops::RangeInclusive};
+
+use anyhow::anyhow;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::auth::Identity;
+use crate::config::PolicyConfig;
+
+mod egress;
+
+pub(crate) use egress::EgressFilter;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// One `[[policy.rule]]`. Every list that is set must match; empty lists match anything.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rule {
+    action: Action,
+    /// Who the rule applies to. With neither set it applies to everyone.
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    cidrs: Vec<IpNet>,
+    #[serde(default)]
+    ports: Vec<PortRange>,
+    protocol: Option<Protocol>,
+    /// Matches the name and its subdomains. Only requests made by name can match.
+    #[serde(default)]
+    domains: Vec<String>,
+}
+
+/// A single port (`443`) or an inclusive range (`"8000-8100"`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawPortRange")]
+pub(crate) struct PortRange(RangeInclusive<u16>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPortRange {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<RawPortRange> for PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawPortRange) -> Result<Self, Self::Error> {
+        let (start, end) = match raw {
+            RawPortRange::Port(port) => (port, port),
+            RawPortRange::Range(range) => match range.split_once('-') {
+                Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+                None => {
+                    let port = range.trim().parse()?;
+                    (port, port)
+                }
+            },
+        };
+        if start > end {
+            return Err(anyhow!("empty port range {}-{}", start, end));
+        }
+        Ok(Self(start..=end))
+    }
+}
+
+/// Where a CONNECT wants to go, after name resolution.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Destination<'a> {
+    pub addr: SocketAddr,
+    pub domain: Option<&'a str>,
+    pub protocol: Protocol,
+}
+
+impl fmt::Display for Destination<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.domain {
+            Some(domain) => write!(f, "{} ({})", domain, self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Outcome of a policy check, with the rule that produced it for the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Verdict {
+    pub action: Action,
+    /// Index into `[[policy.rule]]`, `None` when the default applied.
+    pub rule: Option<usize>,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule {
+            Some(rule) => write!(f, "{:?} by rule {}", self.action, rule + 1),
+            None => write!(f, "{:?} by default", self.action),
+        }
+    }
+}
+
+/// Per-user and per-group destination rules, evaluated for every CONNECT before dialing.
+pub(crate) struct Policy {
+    default: Action,
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub(crate) fn from_config(config: &PolicyConfig) -> Self {
+        Self {
+            default: config.default,
+            rules: config.rule.clone(),
+        }
+    }
+
+    /// The first matching rule wins, otherwise the default action applies.
+    pub(crate) fn check(&self, identity: &Identity, destination: &Destination) -> Verdict {
+        self.rules
+            .iter()
+            .position(|rule| rule.applies_to(identity) && rule.matches(destination))
+            .map_or(
+                Verdict {
+                    action: self.default,
+                    rule: None,
+                },
+                |index| Verdict {
+                    action: self.rules[index].action,
+                    rule: Some(index),
+                },
+            )
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, identity: &Identity) -> bool {
+        if self.users.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+        self.users.contains(&identity.user)
+            || identity.groups.iter().any(|g| self.groups.contains(g))
+    }
+
+    fn matches(&self, destination: &Destination) -> bool {
+        let addr = destination.addr;
+        (self.cidrs.is_empty() || self.cidrs.iter().any(|net| net.contains(&addr.ip())))
+            && (self.ports.is_empty() || self.ports.iter().any(|p| p.0.contains(&addr.port())))
+            && self.protocol.is_none_or(|p| p == destination.protocol)
+            && (self.domains.is_empty()
+                || destination
+                    .domain
+                    .is_some_and(|name| self.domains.iter().any(|s| domain_matches(name, s))))
+    }
+}
+
+fn domain_matches(name: &str, suffix: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let suffix = suffix.trim_start_matches('.').trim_end_matches('.');
+    if name.len() < suffix.len() {
+        return false;
+    }
+    let (head, tail) = name.split_at(name.len() - suffix.len());
+    tail.eq_ignore_ascii_case(suffix) && (head.is_empty() || head.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> Policy {
+        Policy::from_config(&toml::from_str(toml).unwrap())
+    }
+
+    fn identity(user: &str, groups: &[&str]) -> Identity {
+        Identity {
+            user: user.to_string(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    fn tcp<'a>(addr: &str, domain: Option<&'a str>) -> Destination<'a> {
+        Destination {
+            addr: addr.parse().unwrap(),
+            domain,
+            protocol: Protocol::Tcp,
+        }
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy = policy(
+            r#"
+            default = "deny"
+
+            [[rule]]
+            action = "deny"
+            users = ["mallory"]
+
+            [[rule]]
+            action = "allow"
+            groups = ["ops"]
+            cidrs = ["10.0.0.0/8"]
+            ports = [22, "8000-8100"]
+
+            [[rule]]
+            action = "allow"
+            ports = [443]
+            protocol = "tcp"
+            "#,
+        );
+        let ops = identity("alice", &["ops"]);
+        let dev = identity("bob", &["dev"]);
+        let mallory = identity("mallory", &["ops"]);
+
+        let check = |who: &Identity, addr: &str| policy.check(who, &tcp(addr, None));
+        assert_eq!(check(&ops, "10.1.2.3:22").rule, Some(1));
+        assert_eq!(check(&ops, "10.1.2.3:8080").action, Action::Allow);
+        assert_eq!(check(&ops, "10.1.2.3:8101").action, Action::Deny);
+        assert_eq!(check(&dev, "10.1.2.3:22").action, Action::Deny);
+        assert_eq!(check(&dev, "1.1.1.1:443").rule, Some(2));
+        assert_eq!(check(&mallory, "1.1.1.1:443").rule, Some(0));
+        assert_eq!(check(&ops, "192.168.0.1:22").rule, None);
+    }
+
+    #[test]
+    fn test_domain_suffix() {
+        let policy = policy(
+            r#"
+            [[rule]]
+            action = "deny"
+            domains = [".corp.example"]
+            "#,
+        );
+        let who = identity("alice", &[]);
+        let check = |domain| policy.check(&who, &tcp("10.0.0.1:443", domain)).action;
+        assert_eq!(check(Some("corp.example")), Action::Deny);
+        assert_eq!(check(Some("git.CORP.example.")), Action::Deny);
+        assert_eq!(check(Some("notcorp.example")), Action::Allow);
+        assert_eq!(check(None), Action::Allow);
+    }
+
+    #[test]
+    fn test_rejects_inverted_port_range() {
+        assert!(
+            toml::from_str::<PolicyConfig>(
+                r#"
+                [[rule]]
+                action = "deny"
+                ports = ["100-10"]
+                "#
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use encryption::resume::{DEFAULT_STREAM_WINDOW, ReplayBuffer, SessionId};
+use quinn::{Connection, RecvStream, SendStream, VarInt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::Notify,
+};
+
+use crate::config::ResumptionConfig;
+use crate::shaping::ConnectionShaper;
+
+/// Application error code an old connection is closed with when its session
+/// is resumed on a new one.
+pub(crate) const SUPERSEDED: VarInt = VarInt::from_u32(0x54);
+
+/// Application error code the other paths of a session are closed with when
+/// it is ended, e.g. because one of them was kicked.
+pub(crate) const SESSION_ENDED: VarInt = VarInt::from_u32(0x55);
+
+/// Resumable sessions by id, including detached ones waiting for their client.
+pub(crate) struct Sessions {
+    grace: Duration,
+    max_sessions: usize,
+    max_replay: u64,
+    sessions: Mutex<HashMap<SessionId, Arc<ResumableSession>>>,
+    /// Replay memory of each user's flows, shared by its sessions.
+    replay: Mutex<HashMap<String, Arc<ReplayBudget>>>,
+}
+
+/// The flows of one client, which outlive the QUIC connection they were
+/// opened on for the grace period.
+pub(crate) struct ResumableSession {
+    pub id: SessionId,
+    user: String,
+    flows: Mutex<HashMap<u32, Arc<Flow>>>,
+    /// The client's stream receive window, which bounds what it may miss of
+    /// a flow.
+    window: AtomicU32,
+    /// The connections the client currently uses, more than one if it
+    /// joined them over several paths. Empty while detached.
+    connections: Mutex<Vec<Connection>>,
+    replay: Arc<ReplayBudget>,
+}
+
+/// Bytes of replay buffers a user's flows may hold, and hold.
+struct ReplayBudget {
+    max: u64,
+    used: AtomicU64,
+}
+
+/// A flow's share of its user's replay budget, given back when the flow is
+/// dropped.
+pub(crate) struct Reservation {
+    bytes: u64,
+    budget: Arc<ReplayBudget>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// A target connection with what is needed to pick it up on new streams.
+pub(crate) struct Flow {
+    io: tokio::sync::Mutex<FlowIo>,
+    /// Tells the relay on the old streams that the flow is being resumed,
+    /// which the client may do on another path before this one has noticed
+    /// that its connection is gone.
+    takeover: Notify,
+    _reservation: Reservation,
+}
+
+/// The error a relay ends with when its flow is resumed on other streams.
+#[derive(Debug)]
+pub(crate) struct TakenOver;
+
+impl fmt::Display for TakenOver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("flow resumed on another stream")
+    }
+}
+
+impl std::error::Error for TakenOver {}
+
+struct FlowIo {
+    target_r: OwnedReadHalf,
+    target_w: OwnedWriteHalf,
+    /// Client bytes written to the target.
+    up: u64,
+    /// Target bytes sent towards the client.
+    down: ReplayBuffer,
+}
+
+impl Sessions {
+    pub(crate) fn new(config: &ResumptionConfig) -> Self {
+        Self {
+            grace: config.grace,
+            max_sessions: config.max_sessions,
+            max_replay: config.max_replay,
+            sessions: Mutex::new(HashMap::new()),
+            replay: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Finds session `id` of `user`, or starts a new session if there is no
+    /// such session. The flag tells whether it was found. Nothing changes for
+    /// a session that is found until the connection `claim`s it.
+    ///
+    /// A joined session is started under `id`, otherwise under a random id.
+    pub(crate) fn attach(
+        &self,
+        id: SessionId,
+        user: &str,
+        join: bool,
+    ) -> Result<(Arc<ResumableSession>, bool)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(&id) {
+            Some(session) if session.user == user => return Ok((session.clone(), true)),
+            Some(_) if join => return Err(anyhow!("session id is taken")),
+            _ => {}
+        }
+        if join && id == SessionId::default() {
+            return Err(anyhow!("joining without a session id"));
+        }
+        let count = sessions.values().filter(|s| s.user == user).count();
+        if count >= self.max_sessions {
+            return Err(anyhow!("{} has {} sessions already", user, count));
+        }
+        let replay = self
+            .replay
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_insert_with(|| {
+                Arc::new(ReplayBudget {
+                    max: self.max_replay,
+                    used: AtomicU64::new(0),
+                })
+            })
+            .clone();
+
+        let session = Arc::new(ResumableSession {
+            id: if join { id } else { rand::random() },
+            user: user.to_string(),
+            flows: Mutex::new(HashMap::new()),
+            window: AtomicU32::new(DEFAULT_STREAM_WINDOW),
+            connections: Mutex::new(vec![]),
+            replay,
+        });
+        sessions.insert(session.id, session.clone());
+        Ok((session, false))
+    }
+
+    /// Makes `connection` the one `session` is used from. The connections it
+    /// replaces are closed. A joining connection is used alongside them.
+    pub(crate) fn claim(&self, session: &ResumableSession, connection: &Connection, join: bool) {
+        let mut connections = session.connections.lock().unwrap();
+        if join {
+            if !connections
+                .iter()
+                .any(|c| c.stable_id() == connection.stable_id())
+            {
+                connections.push(connection.clone());
+            }
+            return;
+        }
+        let old = std::mem::replace(&mut *connections, vec![connection.clone()]);
+        // e.g. the client changed networks before the server noticed
+        for old in old {
+            if old.stable_id() != connection.stable_id() {
+                old.close(SUPERSEDED, b"session resumed elsewhere");
+            }
+        }
+    }
+
+    /// Drops `session` and its target connections right away, and closes
+    /// the other connections it is used from.
+    pub(crate) fn end(&self, session: &Arc<ResumableSession>) {
+        {
+            let mut all = self.sessions.lock().unwrap();
+            if all
+                .get(&session.id)
+                .is_some_and(|s| Arc::ptr_eq(s, session))
+            {
+                all.remove(&session.id);
+            }
+        }
+        let flows = std::mem::take(&mut *session.flows.lock().unwrap());
+        log::info!(
+            "session of {} ended with {} flows",
+            session.user,
+            flows.len()
+        );
+        for connection in std::mem::take(&mut *session.connections.lock().unwrap()) {
+            connection.close(SESSION_ENDED, b"session ended");
+        }
+    }
+
+    /// Called when `connection` is gone. Unless the client resumes in time,
+    /// the session and its target connections are dropped.
+    pub(crate) fn detach(
+        self: &Arc<Self>,
+        session: Arc<ResumableSession>,
+        connection: &Connection,
+    ) {
+        {
+            let mut connections = session.connections.lock().unwrap();
+            connections.retain(|c| c.stable_id() != connection.stable_id());
+            // an unclaimed session expires as well
+            if !connections.is_empty() {
+                return;
+            }
+        }
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(sessions.grace).await;
+            if !session.connections.lock().unwrap().is_empty() {
+                return;
+            }
+            let mut all = sessions.sessions.lock().unwrap();
+            if all
+                .get(&session.id)
+                .is_some_and(|s| Arc::ptr_eq(s, &session))
+            {
+                all.remove(&session.id);
+                log::info!(
+                    "session of {} expired with {} flows",
+                    session.user,
+                    session.flows.lock().unwrap().len()
+                );
+            }
+        });
+    }
+}
+
+impl ResumableSession {
+    pub(crate) fn set_window(&self, window: u32) {
+        self.window.store(window, Ordering::Relaxed);
+    }
+
+    /// Takes the replay buffer of a new flow from the user's budget, `None`
+    /// if that is spent.
+    pub(crate) fn reserve(&self) -> Option<Reservation> {
+        let bytes = ReplayBuffer::new(self.window.load(Ordering::Relaxed)).capacity() as u64;
+        let budget = &self.replay;
+        budget
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used + bytes <= budget.max).then_some(used + bytes)
+            })
+            .ok()?;
+        Some(Reservation {
+            bytes,
+            budget: budget.clone(),
+        })
+    }
+
+    pub(crate) fn insert(&self, id: u32, target: TcpStream, reservation: Reservation) -> Arc<Flow> {
+        let (target_r, target_w) = target.into_split();
+        let flow = Arc::new(Flow {
+            io: tokio::sync::Mutex::new(FlowIo {
+                target_r,
+                target_w,
+                up: 0,
+                down: ReplayBuffer::new(self.window.load(Ordering::Relaxed)),
+            }),
+            takeover: Notify::new(),
+            _reservation: reservation,
+        });
+        self.flows.lock().unwrap().insert(id, flow.clone());
+        flow
+    }
+
+    pub(crate) fn flow(&self, id: u32) -> Option<Arc<Flow>> {
+        self.flows.lock().unwrap().get(&id).cloned()
+    }
+
+    pub(crate) fn remove(&self, id: u32) {
+        self.flows.lock().unwrap().remove(&id);
+    }
+}
+
+impl Flow {
+    /// Relays between the client streams and the target until both are done.
+    ///
+    /// For a resumed flow `received` is how much target data the client got
+    /// before its old streams broke. The reply tells it how much of its data
+    /// reached the target, then the target data it missed is replayed.
+    ///
+    /// Both directions only count what they have written, so the flow stays
+    /// consistent when the relay is torn down by a lost connection, or ends
+    /// with `TakenOver` because the flow is resumed elsewhere.
+    pub(crate) async fn relay(
+        &self,
+        send: &mut SendStream,
+        recv: &mut RecvStream,
+        received: Option<u64>,
+        shaper: &ConnectionShaper,
+        up_counters: &[&AtomicU64],
+        down_counters: &[&AtomicU64],
+    ) -> Result<()> {
+        // stops the relay on the old streams, if it has not noticed yet
+        self.takeover.notify_waiters();
+        let mut io = self.io.lock().await;
+        let taken_over = self.takeover.notified();
+        let FlowIo {
+            target_r,
+            target_w,
+            up,
+            down,
+        } = &mut *io;
+
+        if let Some(received) = received {
+            let Some(missed) = down.since(received) else {
+                send.write_all(&[0x05, 0x01, 0x00]).await?;
+                send.write_all(&0u64.to_be_bytes()).await?;
+                return Err(anyhow!("cannot replay from offset {}", received));
+            };
+            send.write_all(&[0x05, 0x00, 0x00]).await?;
+            send.write_all(&up.to_be_bytes()).await?;
+            send.write_all(&missed).await?;
+        }
+
+        let c2t = async {
+            let mut buf = vec![0u8; 16 * 1024];
+            loop {
+                let Some(n) = recv.read(&mut buf).await? else {
+                    target_w.shutdown().await?;
+                    return Ok::<_, anyhow::Error>(());
+                };
+                let mut written = 0;
+                while written < n {
+                    let end = written + shaper.upload.acquire(n - written).await;
+                    while written < end {
+                        let m = target_w.write(&buf[written..end]).await?;
+                        if m == 0 {
+                            return Err(anyhow!("target stopped accepting data"));
+                        }
+                        written += m;
+                        *up += m as u64;
+                        for counter in up_counters {
+                            counter.fetch_add(m as u64, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        };
+        let t2c = async {
+            let mut buf = vec![0u8; 16 * 1024];
+            loop {
+                let n = target_r.read(&mut buf).await?;
+                if n == 0 {
+                    send.finish()?;
+                    return Ok::<_, anyhow::Error>(());
+                }
+                // recorded before sending, so it can be replayed whatever happens to the stream
+                down.push(&buf[..n]);
+                let mut written = 0;
+                while written < n {
+                    let granted = shaper.download.acquire(n - written).await;
+                    send.write_all(&buf[written..written + granted]).await?;
+                    for counter in down_counters {
+                        counter.fetch_add(granted as u64, Ordering::Relaxed);
+                    }
+                    written += granted;
+                }
+            }
+        };
+        tokio::select! {
+            relayed = async { tokio::try_join!(c2t, t2c) } => relayed.map(|_| ()),
+            _ = taken_over => Err(TakenOver.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_picks_the_id() {
+        let sessions = Sessions::new(&ResumptionConfig::default());
+        let id = [7u8; 16];
+        let (session, found) = sessions.attach(id, "alice", true).unwrap();
+        assert!(!found);
+        assert_eq!(session.id, id);
+        let (again, found) = sessions.attach(id, "alice", true).unwrap();
+        assert!(found && Arc::ptr_eq(&session, &again));
+        assert!(sessions.attach(id, "bob", true).is_err());
+        assert!(sessions.attach(SessionId::default(), "bob", true).is_err());
+
+        // without joining, unknown ids get a new random one
+        let (other, found) = sessions.attach(id, "bob", false).unwrap();
+        assert!(!found);
+        assert_ne!(other.id, id);
+    }
+
+    #[test]
+    fn test_limits() {
+        let window = ReplayBuffer::new(DEFAULT_STREAM_WINDOW).capacity() as u64;
+        let sessions = Sessions::new(&ResumptionConfig {
+            max_sessions: 2,
+            max_replay: 3 * window,
+            ..Default::default()
+        });
+        let new = SessionId::default();
+        let (first, _) = sessions.attach(new, "alice", false).unwrap();
+        let (second, _) = sessions.attach(new, "alice", false).unwrap();
+        assert!(sessions.attach(new, "alice", false).is_err());
+        // found sessions do not count, nor do other users'
+        assert!(sessions.attach(first.id, "alice", false).is_ok());
+        assert!(sessions.attach(new, "bob", false).is_ok());
+
+        // the budget is shared by the user's sessions
+        let _held = [first.reserve().unwrap(), second.reserve().unwrap()];
+        let last = second.reserve().unwrap();
+        assert!(first.reserve().is_none());
+        drop(last);
+        assert!(first.reserve().is_some());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::auth::Identity;
+use crate::config::ShapingConfig;
+
+struct TokenBucket {
+    /// Bytes per second.
+    rate: f64,
+    burst: f64,
+    state: tokio::sync::Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: Option<u64>) -> Self {
+        let burst = burst.unwrap_or(rate).max(1) as f64;
+        Self {
+            rate: rate.max(1) as f64,
+            burst,
+            state: tokio::sync::Mutex::new(BucketState {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `n` tokens are available and takes them. The lock is held
+    /// while waiting and tokio's mutex is FIFO, so competing streams are
+    /// served in turn instead of the fastest reader starving the rest.
+    async fn take(&self, n: usize) {
+        let n = (n as f64).min(self.burst);
+        let mut state = self.state.lock().await;
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+            state.refilled = now;
+            if state.tokens >= n {
+                state.tokens -= n;
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs_f64((n - state.tokens) / self.rate)).await;
+        }
+    }
+}
+
+/// Buckets a stream has to pass in one direction: the user's and the connection's.
+pub(crate) struct Shaper {
+    buckets: Vec<Arc<TokenBucket>>,
+    /// Largest grant per turn; smaller values share more evenly between streams.
+    quantum: usize,
+}
+
+impl Shaper {
+    fn new(buckets: Vec<Arc<TokenBucket>>, quantum: usize) -> Self {
+        let quantum = buckets
+            .iter()
+            .map(|bucket| bucket.burst as usize)
+            .fold(quantum, usize::min)
+            .max(1);
+        Self { buckets, quantum }
+    }
+
+    /// Waits for up to `want` bytes worth of tokens and returns how many were granted.
+    pub(crate) async fn acquire(&self, want: usize) -> usize {
+        let n = want.min(self.quantum);
+        for bucket in &self.buckets {
+            bucket.take(n).await;
+        }
+        n
+    }
+
+    /// Like `tokio::io::copy`, but every chunk is paid for before it is
+    /// written and added to `counters` once it was.
+    pub(crate) async fn copy<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        counters: &[&AtomicU64],
+    ) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = vec![0u8; 16 * 1024];
+        let mut total = 0;
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(total);
+            }
+            let mut written = 0;
+            while written < n {
+                let granted = self.acquire(n - written).await;
+                writer.write_all(&buf[written..written + granted]).await?;
+                for counter in counters {
+                    counter.fetch_add(granted as u64, Ordering::Relaxed);
+                }
+                written += granted;
+            }
+            total += n as u64;
+        }
+    }
+}
+
+/// Rate limits for the streams of one QUIC connection.
+pub(crate) struct ConnectionShaper {
+    /// Client to target.
+    pub upload: Shaper,
+    /// Target to client.
+    pub download: Shaper,
+}
+
+#[derive(Default)]
+struct UserBuckets {
+    upload: Weak<TokenBucket>,
+    download: Weak<TokenBucket>,
+}
+
+/// Hands out shapers, sharing one pair of buckets among all connections of a user.
+pub(crate) struct Shaping {
+    config: ShapingConfig,
+    users: Mutex<HashMap<String, UserBuckets>>,
+}
+
+impl Shaping {
+    pub(crate) fn new(config: ShapingConfig) -> Self {
+        Self {
+            config,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn connection(&self, identity: &Identity) -> ConnectionShaper {
+        let user_limits = self
+            .config
+            .users
+            .get(&identity.user)
+            .unwrap_or(&self.config.user);
+        let connection_limits = &self.config.connection;
+
+        let mut users = self.users.lock().unwrap();
+        users.retain(|_, buckets| {
+            buckets.upload.strong_count() > 0 || buckets.download.strong_count() > 0
+        });
+        let shared = users.entry(identity.user.clone()).or_default();
+
+        let upload = [
+            shared_bucket(&mut shared.upload, user_limits.upload, user_limits.burst),
+            connection_limits
+                .upload
+                .map(|rate| Arc::new(TokenBucket::new(rate, connection_limits.burst))),
+        ];
+        let download = [
+            shared_bucket(
+                &mut shared.download,
+                user_limits.download,
+                user_limits.burst,
+            ),
+            connection_limits
+                .download
+                .map(|rate| Arc::new(TokenBucket::new(rate, connection_limits.burst))),
+        ];
+        ConnectionShaper {
+            upload: Shaper::new(upload.into_iter().flatten().collect(), self.config.quantum),
+            download: Shaper::new(
+                download.into_iter().flatten().collect(),
+                self.config.quantum,
+            ),
+        }
+    }
+}
+
+fn shared_bucket(
+    slot: &mut Weak<TokenBucket>,
+    rate: Option<u64>,
+    burst: Option<u64>,
+) -> Option<Arc<TokenBucket>> {
+    let rate = rate?;
+    if let Some(bucket) = slot.upgrade() {
+        return Some(bucket);
+    }
+    let bucket = Arc::new(TokenBucket::new(rate, burst));
+    *slot = Arc::downgrade(&bucket);
+    Some(bucket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(user: &str) -> Identity {
+        Identity {
+            user: user.to_string(),
+            groups: vec![],
+        }
+    }
+
+    #[test]
+    fn test_user_buckets_are_shared() {
+        let shaping = Shaping::new(
+            toml::from_str(
+                r#"
+                user = { upload = 1000, download = 2000 }
+                connection = { download = 1500 }
+                users.bob = { upload = 10 }
+                "#,
+            )
+            .unwrap(),
+        );
+        let first = shaping.connection(&identity("alice"));
+        let second = shaping.connection(&identity("alice"));
+        let other = shaping.connection(&identity("bob"));
+
+        assert!(Arc::ptr_eq(
+            &first.upload.buckets[0],
+            &second.upload.buckets[0]
+        ));
+        assert!(!Arc::ptr_eq(
+            &first.download.buckets[1],
+            &second.download.buckets[1]
+        ));
+        assert_eq!(first.download.buckets.len(), 2);
+        assert_eq!(other.upload.buckets[0].rate, 10.0);
+        assert_eq!(other.download.buckets.len(), 1);
+    }
+
+    /// Moves the paused clock and lets the woken copy run.
+    async fn advance(duration: Duration) {
+        tokio::time::advance(duration).await;
+        tokio::task::yield_now().await;
+    }
+
+    #[tokio::test]
+    async fn test_copy_is_rate_limited() {
+        tokio::time::pause();
+        // quarter seconds refill whole grants without rounding
+        let shaper = Shaper::new(vec![Arc::new(TokenBucket::new(1000, Some(500)))], 250);
+        let counter = Arc::new(AtomicU64::new(0));
+        let copied = counter.clone();
+        let copy = tokio::spawn(async move {
+            let data = vec![7u8; 1500];
+            let mut out = vec![];
+            let n = shaper
+                .copy(&mut data.as_slice(), &mut out, &[&copied])
+                .await
+                .unwrap();
+            assert_eq!(out, data);
+            n
+        });
+
+        // the burst goes out at once
+        tokio::task::yield_now().await;
+        assert_eq!(counter.load(Ordering::Relaxed), 500);
+        // then 250 bytes every 250 ms; timers fire on the millisecond
+        // after their deadline, so each one is checked a millisecond either side
+        advance(Duration::from_millis(249)).await;
+        for sent in [750, 1000, 1250, 1500] {
+            assert_eq!(counter.load(Ordering::Relaxed), sent - 250);
+            advance(Duration::from_millis(2)).await;
+            assert_eq!(counter.load(Ordering::Relaxed), sent);
+            advance(Duration::from_millis(248)).await;
+        }
+        assert_eq!(copy.await.unwrap(), 1500);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, VarInt, ZeroRttAccepted};
+use rustls::{
+    RootCertStore,
+    pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, pem::PemObject},
+    server::{ServerSessionMemoryCache, WebPkiClientVerifier},
+};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// TLS sessions kept for clients to resume. Each ticket is used once, which
+/// is what keeps 0-RTT data from being replayed.
+const SESSION_CACHE: usize = 4096;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn read_certs_from_file(
+    config: &Config,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .with_context(|| format!("reading {}", config.cert.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .with_context(|| format!("reading {}", config.key.display()))?;
+    Ok((certs, key))
+}
+
+pub(crate) fn server_crypto(config: &Config) -> Result<QuicServerConfig> {
+    let (certs, key) = read_certs_from_file(config)?;
+    let builder = rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13]);
+
+    let builder = match &config.client_auth.ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("reading client CA {}", ca.display()))?
+            {
+                roots.add(cert?)?;
+            }
+            let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            // enrolling clients have no certificate yet, `handle_client`
+            // refuses them everything else when one is required
+            if !config.client_auth.required || config.enrollment.enabled() {
+                verifier = verifier.allow_unauthenticated();
+            }
+            if let Some(crl) = &config.client_auth.crl {
+                let crls = CertificateRevocationListDer::pem_file_iter(crl)
+                    .with_context(|| format!("reading CRL {}", crl.display()))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                verifier = verifier.with_crls(crls);
+            }
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None if config.client_auth.required => {
+            return Err(anyhow!(
+                "client_auth.required is set without client_auth.ca"
+            ));
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut crypto = builder.with_single_cert(certs, key)?;
+    // a new cache per config, so a reload (e.g. of the CRL) ends all
+    // resumption of sessions set up under the old one
+    crypto.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE);
+    // QUIC takes all or nothing here
+    crypto.max_early_data_size = u32::MAX;
+    Ok(QuicServerConfig::try_from(crypto)?)
+}
+
+/// Completion of a connection's TLS handshake.
+///
+/// Connections are read from before their handshake is done, so a client
+/// that resumes a TLS session can send its requests as early data. Those may
+/// be replayed by someone else, and the client certificate is not known yet,
+/// so they are only answered once `done`.
+#[derive(Clone)]
+pub(crate) struct Handshake(watch::Receiver<Option<bool>>);
+
+impl Handshake {
+    pub(crate) fn new(
+        connection: &Connection,
+        accepted: ZeroRttAccepted,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (tx, rx) = watch::channel(None);
+        let connection = connection.clone();
+        tokio::spawn(async move {
+            // resolves when the handshake completes or the connection is
+            // closed; for servers its value only tells whether 0-RTT was used.
+            // Without an idle timeout a replay would otherwise hang around.
+            if tokio::time::timeout(HANDSHAKE_TIMEOUT, accepted)
+                .await
+                .is_err()
+            {
+                connection.close(VarInt::from_u32(0), b"handshake timed out");
+            }
+            let complete = connection.close_reason().is_none();
+            if !complete {
+                metrics.handshake_failed();
+                log::warn!(
+                    "handshake with {} failed: {}",
+                    connection.remote_address(),
+                    connection
+                        .close_reason()
+                        .map_or("connection dropped".to_string(), |e| e.to_string())
+                );
+            }
+            let _ = tx.send(Some(complete));
+        });
+        Self(rx)
+    }
+
+    pub(crate) async fn done(&self) -> Result<()> {
+        let mut rx = self.0.clone();
+        match *rx.wait_for(Option::is_some).await? {
+            Some(true) => Ok(()),
+            _ => Err(anyhow!("TLS handshake did not complete")),
+        }
+    }
+}
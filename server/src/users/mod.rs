@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow};
+use argon2::{
+    Algorithm, Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use serde::Deserialize;
+
+use crate::auth::{Authenticator, Credentials, Identity, Rejection};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserRecord {
+    name: String,
+    /// Argon2id PHC string, as printed by `server hash-password`.
+    password: String,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    #[serde(default, with = "humantime_serde")]
+    expires: Option<SystemTime>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserFile {
+    #[serde(default)]
+    user: Vec<UserRecord>,
+}
+
+type Users = HashMap<String, UserRecord>;
+
+/// File-backed user database, swapped atomically when the file changes on disk.
+pub(crate) struct UserStore {
+    path: PathBuf,
+    users: RwLock<Arc<Users>>,
+    modified: Mutex<Option<SystemTime>>,
+    dummy_hash: String,
+}
+
+impl UserStore {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let (users, modified) = read_users(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            users: RwLock::new(Arc::new(users)),
+            modified: Mutex::new(modified),
+            dummy_hash: hash_password(b"not a real password")?,
+        })
+    }
+
+    /// Re-reads the database if its mtime moved. A broken file keeps the old users.
+    pub(crate) fn reload_if_changed(&self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        if modified.is_some() && modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Re-reads the database unconditionally. A broken file keeps the old users.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let mut last = self.modified.lock().unwrap();
+        let (users, modified) = read_users(&self.path)?;
+        *self.users.write().unwrap() = Arc::new(users);
+        *last = modified;
+        Ok(())
+    }
+
+    pub(crate) fn watch(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match self.reload_if_changed() {
+                    Ok(true) => log::info!("reloaded user database {}", self.path.display()),
+                    Ok(false) => {}
+                    Err(e) => log::warn!("keeping previous user database: {:?}", e),
+                }
+            }
+        });
+    }
+}
+
+impl Authenticator for UserStore {
+    /// Unknown users are verified against a dummy hash so that response time
+    /// does not reveal which usernames exist; the Argon2 digest comparison
+    /// itself is constant-time.
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Identity, Rejection> {
+        let users = self.users.read().unwrap().clone();
+        let record = std::str::from_utf8(credentials.username)
+            .ok()
+            .and_then(|name| users.get(name));
+
+        let hash = record.map_or_else(|| self.dummy_hash.clone(), |r| r.password.clone());
+        let password = credentials.password.to_vec();
+        let password_ok = tokio::task::spawn_blocking(move || verify_password(&hash, &password))
+            .await
+            .unwrap_or(false);
+
+        match record {
+            None => Err(Rejection::UnknownUser),
+            Some(_) if !password_ok => Err(Rejection::BadPassword),
+            Some(r) => identity(r),
+        }
+    }
+}
+
+impl UserStore {
+    /// The identity of `name` for a client that proved it another way, e.g.
+    /// by certificate. The account has to exist and be usable all the same.
+    pub(crate) fn lookup(&self, name: &str) -> Result<Identity, Rejection> {
+        let users = self.users.read().unwrap().clone();
+        users
+            .get(name)
+            .map_or(Err(Rejection::UnknownUser), identity)
+    }
+}
+
+fn identity(record: &UserRecord) -> Result<Identity, Rejection> {
+    if !record.enabled {
+        return Err(Rejection::Disabled);
+    }
+    if record.expires.is_some_and(|at| at <= SystemTime::now()) {
+        return Err(Rejection::Expired);
+    }
+    Ok(Identity {
+        user: record.name.clone(),
+        groups: record.groups.clone(),
+    })
+}
+
+fn read_users(path: &Path) -> Result<(Users, Option<SystemTime>)> {
+    let modified = std::fs::metadata(path)
+        .with_context(|| format!("reading {}", path.display()))?
+        .modified()
+        .ok();
+    let raw = std::fs::read_to_string(path)?;
+    let file: UserFile =
+        toml::from_str(&raw).with_context(|| format!("parsing {}", path.display()))?;
+
+    let mut users = Users::new();
+    for record in file.user {
+        let hash = PasswordHash::new(&record.password)
+            .map_err(|e| anyhow!("user {}: invalid password hash: {}", record.name, e))?;
+        if hash.algorithm != Algorithm::Argon2id.ident() {
+            return Err(anyhow!(
+                "user {}: password is not an argon2id hash",
+                record.name
+            ));
+        }
+        if users.contains_key(&record.name) {
+            return Err(anyhow!("user {} is defined twice", record.name));
+        }
+        users.insert(record.name.clone(), record);
+    }
+    Ok((users, modified))
+}
+
+pub(crate) fn hash_password(password: &[u8]) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password, &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("hashing password: {}", e))
+}
+
+fn verify_password(hash: &str, password: &[u8]) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default().verify_password(password, &hash).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn verify(
+        store: &UserStore,
+        username: &str,
+        password: &str,
+    ) -> Result<String, Rejection> {
+        store
+            .authenticate(&Credentials {
+                username: username.as_bytes(),
+                password: password.as_bytes(),
+                remote: "127.0.0.1:1".parse().unwrap(),
+            })
+            .await
+            .map(|identity| identity.user)
+    }
+
+    fn write_db(name: &str, body: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vpn-users-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_login_outcomes() {
+        let hash = hash_password(b"secret").unwrap();
+        let path = write_db(
+            "outcomes",
+            &format!(
+                r#"
+                [[user]]
+                name = "alice"
+                password = "{hash}"
+
+                [[user]]
+                name = "bob"
+                password = "{hash}"
+                enabled = false
+
+                [[user]]
+                name = "carol"
+                password = "{hash}"
+                expires = "2001-01-01T00:00:00Z"
+                "#
+            ),
+        );
+        let store = UserStore::open(&path).unwrap();
+
+        assert_eq!(
+            verify(&store, "alice", "secret").await,
+            Ok("alice".to_string())
+        );
+        assert_eq!(
+            verify(&store, "alice", "wrong").await,
+            Err(Rejection::BadPassword)
+        );
+        assert_eq!(
+            verify(&store, "bob", "secret").await,
+            Err(Rejection::Disabled)
+        );
+        assert_eq!(
+            verify(&store, "bob", "wrong").await,
+            Err(Rejection::BadPassword)
+        );
+        assert_eq!(
+            verify(&store, "carol", "secret").await,
+            Err(Rejection::Expired)
+        );
+        assert_eq!(
+            verify(&store, "dave", "secret").await,
+            Err(Rejection::UnknownUser)
+        );
+
+        assert_eq!(store.lookup("alice").unwrap().user, "alice");
+        assert_eq!(store.lookup("bob"), Err(Rejection::Disabled));
+        assert_eq!(store.lookup("carol"), Err(Rejection::Expired));
+        assert_eq!(store.lookup("dave"), Err(Rejection::UnknownUser));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_non_argon2id_hashes() {
+        let path = write_db(
+            "plain",
+            r#"
+            [[user]]
+            name = "alice"
+            password = "testpass"
+            "#,
+        );
+        assert!(UserStore::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}